@@ -0,0 +1,40 @@
+//! Instruction throughput of the CPU core on a flat bus, in instructions per second.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nes_emu::testing::FlatMemoryBus;
+use nes_emu::Cpu;
+
+const INSTRUCTIONS: u64 = 100_000;
+
+/// LDA/ADC/STA on a zero page counter, looping 256 times through DEX/BNE.
+const PROGRAM: [u8; 14] = [
+    0xA2, 0x00, // 0400: LDX #$00
+    0xA5, 0x10, // 0402: LDA $10
+    0x69, 0x01, // 0404: ADC #$01
+    0x85, 0x10, // 0406: STA $10
+    0xCA, // 0408: DEX
+    0xD0, 0xF7, // 0409: BNE $0402
+    0x4C, 0x00, 0x04, // 040B: JMP $0400
+];
+
+fn tight_loop(c: &mut Criterion) {
+    let mut bus = FlatMemoryBus::new();
+    bus.load(0x0400, &PROGRAM);
+    let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+    cpu.set_prog_counter(0x0400);
+
+    let mut group = c.benchmark_group("cpu");
+    group.throughput(Throughput::Elements(INSTRUCTIONS));
+    group.bench_function("lda_adc_sta_bne", |b| {
+        b.iter(|| {
+            for _ in 0..INSTRUCTIONS {
+                cpu.run_instr(&mut bus)
+                    .expect("the loop only uses official opcodes");
+            }
+        });
+    });
+    group.finish();
+}
+
+criterion_group!(benches, tight_loop);
+criterion_main!(benches);
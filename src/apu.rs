@@ -0,0 +1,1225 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::state::{Persist, StateError, StateReader, StateWriter};
+use crate::Region;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+const NOISE_PERIOD_NTSC: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const NOISE_PERIOD_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+/// DMC output rates, in CPU cycles per bit.
+const DMC_RATE_NTSC: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+const DMC_RATE_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// CPU cycle of each frame sequencer step, from the start of the sequence.
+const FRAME_STEPS_NTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const FRAME_STEPS_PAL: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    const fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index >> 3) as usize];
+        }
+    }
+
+    const fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    const fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    const fn active(self) -> bool {
+        self.counter > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant_volume: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    const fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant_volume = data & 0b0001_0000 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    const fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    const fn output(self) -> u8 {
+        if self.constant_volume {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Pulse {
+    /// Pulse 1 negates with one's complement, pulse 2 with two's complement.
+    second_channel: bool,
+    duty: u8,
+    step: u8,
+    timer_period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    fn new(second_channel: bool) -> Self {
+        Self {
+            second_channel,
+            ..Self::default()
+        }
+    }
+
+    const fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0b0000_1000 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (data as u16 & 0x07) << 8;
+                self.length.load(data);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    const fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.step = (self.step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    const fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = if self.second_channel {
+                change
+            } else {
+                change + 1
+            };
+            self.timer_period.saturating_sub(change)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    const fn muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x07FF
+    }
+
+    const fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    const fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.muted()
+            || DUTY_TABLE[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Triangle {
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    length: LengthCounter,
+}
+
+impl Triangle {
+    const fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (data as u16 & 0x07) << 8;
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    /// The triangle timer runs at the CPU clock rate, twice as fast as the other channels.
+    const fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    const fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    const fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Noise {
+    envelope: Envelope,
+    length: LengthCounter,
+    short_mode: bool,
+    period_index: u8,
+    timer: u16,
+    shift_register: u16,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            shift_register: 1,
+            ..Self::default()
+        }
+    }
+
+    const fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period_index = data & 0x0F;
+            }
+            _ => {
+                self.length.load(data);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    const fn clock_timer(&mut self, region: Region) {
+        if self.timer == 0 {
+            self.timer = match region {
+                Region::Ntsc => NOISE_PERIOD_NTSC[self.period_index as usize],
+                Region::Pal => NOISE_PERIOD_PAL[self.period_index as usize],
+            };
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+            self.shift_register = (self.shift_register >> 1) | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    const fn output(&self) -> u8 {
+        if !self.length.active() || self.shift_register & 0x01 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy)]
+struct Dmc {
+    irq_enabled: bool,
+    loop_flag: bool,
+    irq: bool,
+    rate_index: u8,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    const fn new() -> Self {
+        Self {
+            irq_enabled: false,
+            loop_flag: false,
+            irq: false,
+            rate_index: 0,
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    const fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                self.loop_flag = data & 0b0100_0000 != 0;
+                self.rate_index = data & 0x0F;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.output_level = data & 0x7F,
+            2 => self.sample_address = 0xC000 | (data as u16) << 6,
+            _ => self.sample_length = (data as u16) << 4 | 1,
+        }
+    }
+
+    const fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    const fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    /// Address of the next sample byte, when the buffer needs refilling.
+    const fn dma_request(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    const fn dma_complete(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_address = match self.current_address.checked_add(1) {
+            Some(address) => address,
+            None => 0x8000,
+        };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    const fn clock_timer(&mut self, region: Region) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = match region {
+            Region::Ntsc => DMC_RATE_NTSC[self.rate_index as usize],
+            Region::Pal => DMC_RATE_PAL[self.rate_index as usize],
+        } - 1;
+        if !self.silence {
+            if self.shift_register & 0x01 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+            self.shift_register >>= 1;
+        }
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy, Default)]
+struct FrameCounter {
+    five_step: bool,
+    irq_inhibit: bool,
+    irq: bool,
+    cycle: u32,
+    /// CPU cycles left before a $4017 write restarts the sequence, 0 when none is due.
+    reset_delay: u8,
+    /// The mode the restarted sequence runs in.
+    next_five_step: bool,
+}
+
+/// Resampled APU output shared with a front-end, typically its audio thread. Cloning
+/// gives another handle to the same queue.
+#[derive(Debug, Clone)]
+pub struct AudioQueue {
+    samples: Arc<Mutex<VecDeque<f32>>>,
+    capacity: usize,
+}
+
+impl AudioQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Fill `out` with the oldest samples, returning how many were available. The rest
+    /// of `out` is left untouched.
+    pub fn pop_into(&self, out: &mut [f32]) -> usize {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = out.len().min(samples.len());
+        for (slot, sample) in out.iter_mut().zip(samples.drain(..count)) {
+            *slot = sample;
+        }
+        drop(samples);
+        count
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append samples, dropping the oldest ones when nobody keeps up with pulling them.
+    fn push(&self, new_samples: &[f32]) {
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        samples.extend(new_samples);
+        let excess = samples.len().saturating_sub(self.capacity);
+        samples.drain(..excess);
+    }
+}
+
+/// Linear resampling of the one sample per CPU cycle the mixer produces down to the
+/// host rate.
+#[derive(Debug, Clone)]
+struct Resampler {
+    sample_rate: f64,
+    /// Position of the next output sample, in input samples after `previous`.
+    phase: f64,
+    previous: f32,
+    /// Output waiting to be pushed to `queue`, so it is locked once per batch.
+    pending: Vec<f32>,
+    queue: AudioQueue,
+}
+
+impl Resampler {
+    const BATCH: usize = 64;
+
+    #[allow(clippy::while_float)]
+    fn push(&mut self, sample: f32, input_rate: f64) {
+        let step = input_rate / self.sample_rate;
+        while self.phase <= 1.0 {
+            #[allow(clippy::cast_possible_truncation)] // Back to the samples' precision.
+            let fraction = self.phase as f32;
+            self.pending
+                .push((sample - self.previous).mul_add(fraction, self.previous));
+            self.phase += step;
+        }
+        self.phase -= 1.0;
+        self.previous = sample;
+        if self.pending.len() >= Self::BATCH {
+            self.queue.push(&self.pending);
+            self.pending.clear();
+        }
+    }
+}
+
+/// The five sound channels, as named by [`Apu::set_channel_enabled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+#[derive(Clone)]
+pub struct Apu {
+    region: Region,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    /// The pulse and noise timers tick on every other CPU cycle.
+    odd_cycle: bool,
+    /// One bit per [`Channel`] that is silenced in the mixer.
+    muted: u8,
+    resampler: Option<Resampler>,
+}
+
+impl Apu {
+    #[must_use]
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            pulse1: Pulse::new(false),
+            pulse2: Pulse::new(true),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            frame_counter: FrameCounter::default(),
+            odd_cycle: false,
+            muted: 0,
+            resampler: None,
+        }
+    }
+
+    #[must_use]
+    pub const fn region(&self) -> Region {
+        self.region
+    }
+
+    pub const fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    /// Start resampling the output to `sample_rate` Hz, e.g. 44100. The returned queue
+    /// holds up to a quarter of a second of audio, older samples being dropped past that.
+    pub fn start_audio_output(&mut self, sample_rate: u32) -> AudioQueue {
+        let queue = AudioQueue::new(sample_rate as usize / 4);
+        self.resampler = Some(Resampler {
+            sample_rate: f64::from(sample_rate),
+            phase: 0.0,
+            previous: 0.0,
+            pending: Vec::with_capacity(Resampler::BATCH),
+            queue: queue.clone(),
+        });
+        queue
+    }
+
+    pub fn stop_audio_output(&mut self) {
+        self.resampler = None;
+    }
+
+    /// Mute or unmute `channel` in the mixer. This only masks the output: a muted
+    /// channel keeps running, so its length counter and timers behave as usual.
+    pub const fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        if enabled {
+            self.muted &= !(1 << channel as u8);
+        } else {
+            self.muted |= 1 << channel as u8;
+        }
+    }
+
+    #[must_use]
+    pub const fn channel_enabled(&self, channel: Channel) -> bool {
+        self.muted & (1 << channel as u8) == 0
+    }
+
+    /// The output of `channel` as seen by the mixer, zero when it is muted.
+    const fn channel_output(&self, channel: Channel) -> u8 {
+        if !self.channel_enabled(channel) {
+            return 0;
+        }
+        match channel {
+            Channel::Pulse1 => self.pulse1.output(),
+            Channel::Pulse2 => self.pulse2.output(),
+            Channel::Triangle => self.triangle.output(),
+            Channel::Noise => self.noise.output(),
+            Channel::Dmc => self.dmc.output_level,
+        }
+    }
+
+    /// State of the APU's /IRQ output, true when asserted.
+    #[must_use]
+    pub const fn irq_line(&self) -> bool {
+        self.frame_counter.irq || self.dmc.irq
+    }
+
+    /// Address the DMC wants to fetch its next sample byte from, if its buffer is empty.
+    /// The bus answers with [`Apu::dmc_dma_complete`].
+    #[must_use]
+    pub const fn dmc_dma_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+
+    pub const fn dmc_dma_complete(&mut self, data: u8) {
+        self.dmc.dma_complete(data);
+    }
+
+    /// Handle a CPU read of 0x4000-0x4017. Only $4015 is readable.
+    pub const fn cpu_read(&mut self, address: u16) -> Option<u8> {
+        let data = self.peek(address);
+        if address == 0x4015 {
+            self.frame_counter.irq = false;
+        }
+        data
+    }
+
+    /// Read a register without acknowledging the frame interrupt.
+    #[must_use]
+    pub const fn peek(&self, address: u16) -> Option<u8> {
+        if address != 0x4015 {
+            return None;
+        }
+        let mut status = 0;
+        if self.pulse1.length.active() {
+            status |= 0b0000_0001;
+        }
+        if self.pulse2.length.active() {
+            status |= 0b0000_0010;
+        }
+        if self.triangle.length.active() {
+            status |= 0b0000_0100;
+        }
+        if self.noise.length.active() {
+            status |= 0b0000_1000;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0b0001_0000;
+        }
+        if self.frame_counter.irq {
+            status |= 0b0100_0000;
+        }
+        if self.dmc.irq {
+            status |= 0b1000_0000;
+        }
+        Some(status)
+    }
+
+    /// Handle a CPU write to 0x4000-0x4017.
+    pub const fn cpu_write(&mut self, address: u16, data: u8) -> bool {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(address - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(address - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(address - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write(address - 0x4010, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0b0000_0001 != 0);
+                self.pulse2.length.set_enabled(data & 0b0000_0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0000_0100 != 0);
+                self.noise.length.set_enabled(data & 0b0000_1000 != 0);
+                self.dmc.set_enabled(data & 0b0001_0000 != 0);
+            }
+            // The IRQ inhibit flag applies right away, but the sequence only restarts 3
+            // cycles after a write on an even cycle and 4 after one on an odd cycle,
+            // in between two APU cycles.
+            0x4017 => {
+                self.frame_counter.next_five_step = data & 0b1000_0000 != 0;
+                self.frame_counter.irq_inhibit = data & 0b0100_0000 != 0;
+                if self.frame_counter.irq_inhibit {
+                    self.frame_counter.irq = false;
+                }
+                self.frame_counter.reset_delay = if self.odd_cycle { 4 } else { 3 };
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Advance the APU by one CPU cycle.
+    pub fn tick(&mut self) {
+        self.triangle.clock_timer();
+        self.dmc.clock_timer(self.region);
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+            self.noise.clock_timer(self.region);
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.clock_frame_counter();
+        if self.resampler.is_some() {
+            let sample = self.output_sample();
+            let input_rate = self.region.cpu_clock_rate();
+            if let Some(resampler) = &mut self.resampler {
+                resampler.push(sample, input_rate);
+            }
+        }
+    }
+
+    /// Step the frame sequencer. In 4-step mode the last step raises the frame
+    /// interrupt; 5-step mode has an extra, silent step and never does. Restarting the
+    /// sequence in 5-step mode clocks every unit at once.
+    const fn clock_frame_counter(&mut self) {
+        if self.frame_counter.reset_delay > 0 {
+            self.frame_counter.reset_delay -= 1;
+            if self.frame_counter.reset_delay == 0 {
+                self.frame_counter.five_step = self.frame_counter.next_five_step;
+                self.frame_counter.cycle = 0;
+                if self.frame_counter.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+                return;
+            }
+        }
+        let steps = match self.region {
+            Region::Ntsc => FRAME_STEPS_NTSC,
+            Region::Pal => FRAME_STEPS_PAL,
+        };
+        self.frame_counter.cycle += 1;
+        let cycle = self.frame_counter.cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if cycle == steps[1] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        } else if cycle == steps[3] && !self.frame_counter.five_step {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            if !self.frame_counter.irq_inhibit {
+                self.frame_counter.irq = true;
+            }
+            self.frame_counter.cycle = 0;
+        } else if cycle == steps[4] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            self.frame_counter.cycle = 0;
+        }
+    }
+
+    const fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    const fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.length.clock();
+        self.pulse2.clock_sweep();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+
+    /// Current mixer output in the 0.0-1.0 range, using the usual approximation of the
+    /// non-linear DAC.
+    #[must_use]
+    pub fn output_sample(&self) -> f32 {
+        let pulse =
+            f32::from(self.channel_output(Channel::Pulse1) + self.channel_output(Channel::Pulse2));
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = f32::from(self.channel_output(Channel::Triangle)) / 8227.0
+            + f32::from(self.channel_output(Channel::Noise)) / 12241.0
+            + f32::from(self.channel_output(Channel::Dmc)) / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+}
+
+impl Persist for LengthCounter {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.halt);
+        w.u8(self.counter);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.halt = r.bool()?;
+        self.counter = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Persist for Envelope {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.start);
+        w.bool(self.looping);
+        w.bool(self.constant_volume);
+        w.u8(self.volume);
+        w.u8(self.divider);
+        w.u8(self.decay);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.start = r.bool()?;
+        self.looping = r.bool()?;
+        self.constant_volume = r.bool()?;
+        self.volume = r.u8()?;
+        self.divider = r.u8()?;
+        self.decay = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Persist for Pulse {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.duty);
+        w.u8(self.step);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        self.envelope.write_state(w);
+        self.length.write_state(w);
+        w.bool(self.sweep_enabled);
+        w.u8(self.sweep_period);
+        w.bool(self.sweep_negate);
+        w.u8(self.sweep_shift);
+        w.bool(self.sweep_reload);
+        w.u8(self.sweep_divider);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.duty = r.u8()?;
+        self.step = r.u8()?;
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.envelope.read_state(r)?;
+        self.length.read_state(r)?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_period = r.u8()?;
+        self.sweep_negate = r.bool()?;
+        self.sweep_shift = r.u8()?;
+        self.sweep_reload = r.bool()?;
+        self.sweep_divider = r.u8()?;
+        Ok(())
+    }
+}
+
+impl Persist for Triangle {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.control);
+        w.u8(self.linear_reload_value);
+        w.u8(self.linear_counter);
+        w.bool(self.linear_reload);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+        w.u8(self.step);
+        self.length.write_state(w);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.control = r.bool()?;
+        self.linear_reload_value = r.u8()?;
+        self.linear_counter = r.u8()?;
+        self.linear_reload = r.bool()?;
+        self.timer_period = r.u16()?;
+        self.timer = r.u16()?;
+        self.step = r.u8()?;
+        self.length.read_state(r)
+    }
+}
+
+impl Persist for Noise {
+    fn write_state(&self, w: &mut StateWriter) {
+        self.envelope.write_state(w);
+        self.length.write_state(w);
+        w.bool(self.short_mode);
+        w.u8(self.period_index);
+        w.u16(self.timer);
+        w.u16(self.shift_register);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.envelope.read_state(r)?;
+        self.length.read_state(r)?;
+        self.short_mode = r.bool()?;
+        self.period_index = r.u8()?;
+        self.timer = r.u16()?;
+        self.shift_register = r.u16()?;
+        Ok(())
+    }
+}
+
+impl Persist for Dmc {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.irq_enabled);
+        w.bool(self.loop_flag);
+        w.bool(self.irq);
+        w.u8(self.rate_index);
+        w.u16(self.timer);
+        w.u8(self.output_level);
+        w.u16(self.sample_address);
+        w.u16(self.sample_length);
+        w.u16(self.current_address);
+        w.u16(self.bytes_remaining);
+        w.bool(self.sample_buffer.is_some());
+        w.u8(self.sample_buffer.unwrap_or_default());
+        w.u8(self.shift_register);
+        w.u8(self.bits_remaining);
+        w.bool(self.silence);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.irq_enabled = r.bool()?;
+        self.loop_flag = r.bool()?;
+        self.irq = r.bool()?;
+        self.rate_index = r.u8()?;
+        self.timer = r.u16()?;
+        self.output_level = r.u8()?;
+        self.sample_address = r.u16()?;
+        self.sample_length = r.u16()?;
+        self.current_address = r.u16()?;
+        self.bytes_remaining = r.u16()?;
+        let buffered = r.bool()?;
+        let sample = r.u8()?;
+        self.sample_buffer = buffered.then_some(sample);
+        self.shift_register = r.u8()?;
+        self.bits_remaining = r.u8()?;
+        self.silence = r.bool()?;
+        Ok(())
+    }
+}
+
+/// The region, muted channels and audio output are settings of the machine loading the
+/// state, and are kept.
+impl Persist for Apu {
+    fn write_state(&self, w: &mut StateWriter) {
+        self.pulse1.write_state(w);
+        self.pulse2.write_state(w);
+        self.triangle.write_state(w);
+        self.noise.write_state(w);
+        self.dmc.write_state(w);
+        w.bool(self.frame_counter.five_step);
+        w.bool(self.frame_counter.irq_inhibit);
+        w.bool(self.frame_counter.irq);
+        w.u32(self.frame_counter.cycle);
+        w.u8(self.frame_counter.reset_delay);
+        w.bool(self.frame_counter.next_five_step);
+        w.bool(self.odd_cycle);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.pulse1.read_state(r)?;
+        self.pulse2.read_state(r)?;
+        self.triangle.read_state(r)?;
+        self.noise.read_state(r)?;
+        self.dmc.read_state(r)?;
+        self.frame_counter.five_step = r.bool()?;
+        self.frame_counter.irq_inhibit = r.bool()?;
+        self.frame_counter.irq = r.bool()?;
+        self.frame_counter.cycle = r.u32()?;
+        self.frame_counter.reset_delay = r.u8()?;
+        self.frame_counter.next_five_step = r.bool()?;
+        self.odd_cycle = r.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPU cycles from a $4017 write of `data`, made after `ticks_before` cycles, until
+    /// the frame interrupt.
+    fn cycles_to_frame_irq(ticks_before: usize, data: u8) -> u32 {
+        let mut apu = Apu::new(Region::Ntsc);
+        for _ in 0..ticks_before {
+            apu.tick();
+        }
+        apu.cpu_write(0x4017, data);
+        let mut cycles = 0;
+        while !apu.irq_line() {
+            apu.tick();
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn frame_counter_restarts_after_three_or_four_cycles() {
+        assert_eq!(cycles_to_frame_irq(10, 0x00), 3 + FRAME_STEPS_NTSC[3]);
+        assert_eq!(cycles_to_frame_irq(11, 0x00), 4 + FRAME_STEPS_NTSC[3]);
+    }
+
+    /// The length counter of pulse 1, loaded with 254, and whether its envelope still
+    /// waits for its first quarter frame clock, once a $4017 write of `data` has taken
+    /// effect.
+    fn pulse_after_restart(data: u8) -> (u8, bool) {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.cpu_write(0x4015, 0x01);
+        apu.cpu_write(0x4003, 0x08);
+        assert_eq!(apu.pulse1.length.counter, 254);
+        apu.cpu_write(0x4017, data);
+        for _ in 0..3 {
+            apu.tick();
+        }
+        (apu.pulse1.length.counter, apu.pulse1.envelope.start)
+    }
+
+    #[test]
+    fn five_step_restart_clocks_the_units_at_once() {
+        assert_eq!(pulse_after_restart(0x80), (253, false));
+        assert_eq!(pulse_after_restart(0x00), (254, true));
+    }
+
+    #[test]
+    fn dmc_sample_wraps_to_0x8000_and_raises_its_irq_at_the_end() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.cpu_write(0x4017, 0x40);
+        // IRQ on, fastest rate, 65 bytes from 0xFFC0.
+        apu.cpu_write(0x4010, 0x8F);
+        apu.cpu_write(0x4012, 0xFF);
+        apu.cpu_write(0x4013, 0x04);
+        apu.cpu_write(0x4015, 0x10);
+        let mut addresses = Vec::new();
+        while let Some(address) = apu.dmc_dma_request() {
+            assert!(!apu.irq_line());
+            addresses.push(address);
+            apu.dmc_dma_complete(0x00);
+            while apu.dmc_dma_request().is_none() && apu.dmc.bytes_remaining > 0 {
+                apu.tick();
+            }
+        }
+        assert_eq!(addresses.len(), 65);
+        assert_eq!(addresses[63], 0xFFFF);
+        assert_eq!(addresses[64], 0x8000);
+        assert!(apu.irq_line());
+        assert_eq!(apu.peek(0x4015), Some(0x80));
+    }
+
+    /// The cycles on which pulse 1's length counter is clocked and on which the frame
+    /// interrupt is first raised, counting from a $4017 write of `data` on an even cycle,
+    /// over `cycles` cycles.
+    fn frame_events(region: Region, data: u8, cycles: u32) -> (Vec<u32>, Option<u32>) {
+        let mut apu = Apu::new(region);
+        apu.cpu_write(0x4015, 0x01);
+        apu.cpu_write(0x4003, 0x08);
+        apu.cpu_write(0x4017, data);
+        let mut half_frames = Vec::new();
+        let mut irq = None;
+        for cycle in 1..=cycles {
+            let counter = apu.pulse1.length.counter;
+            apu.tick();
+            if apu.pulse1.length.counter != counter {
+                half_frames.push(cycle);
+            }
+            if apu.irq_line() && irq.is_none() {
+                irq = Some(cycle);
+            }
+        }
+        (half_frames, irq)
+    }
+
+    #[test]
+    fn frame_steps_follow_the_region() {
+        // The second, fourth and fifth steps, in CPU cycles.
+        for (region, steps) in [
+            (Region::Ntsc, [14913, 29829, 37281]),
+            (Region::Pal, [16627, 33253, 41565]),
+        ] {
+            assert_eq!(
+                frame_events(region, 0x00, 3 + steps[1]),
+                (vec![3 + steps[0], 3 + steps[1]], Some(3 + steps[1])),
+                "{region:?}"
+            );
+            assert_eq!(
+                frame_events(region, 0x80, 3 + steps[2]),
+                (vec![3, 3 + steps[0], 3 + steps[2]], None),
+                "{region:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn muted_channel_is_silent_while_its_length_counter_runs() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.cpu_write(0x4015, 0x01);
+        // 50% duty, constant volume 15, a period the sweep leaves alone, length 10.
+        apu.cpu_write(0x4000, 0x9F);
+        apu.cpu_write(0x4002, 0x40);
+        apu.cpu_write(0x4003, 0x00);
+        apu.set_channel_enabled(Channel::Pulse1, false);
+        assert!(!apu.channel_enabled(Channel::Pulse1));
+        let mut cycles = 0;
+        while apu.peek(0x4015).is_some_and(|status| status & 0x01 != 0) {
+            assert_eq!(apu.channel_output(Channel::Pulse1), 0);
+            apu.tick();
+            cycles += 1;
+            assert!(cycles < 200_000, "length counter stuck");
+        }
+        // Ten length clocks, two per frame counter sequence of 29830 cycles.
+        assert!((5 * 29_830 - 10..=5 * 29_830).contains(&cycles), "{cycles}");
+
+        // Unmuted, it sounds again once its length is reloaded.
+        apu.set_channel_enabled(Channel::Pulse1, true);
+        apu.cpu_write(0x4003, 0x00);
+        let loud = (0..100).any(|_| {
+            apu.tick();
+            apu.channel_output(Channel::Pulse1) == 15
+        });
+        assert!(loud);
+    }
+
+    #[test]
+    fn audio_output_is_resampled_to_the_host_rate() {
+        let mut apu = Apu::new(Region::Ntsc);
+        let queue = apu.start_audio_output(44_100);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let tenth_of_a_second = (Region::Ntsc.cpu_clock_rate() / 10.0) as u32;
+        for _ in 0..tenth_of_a_second {
+            apu.tick();
+        }
+        // Samples go to the queue in batches.
+        assert!(
+            (4410 - Resampler::BATCH..=4411).contains(&queue.len()),
+            "{}",
+            queue.len()
+        );
+        // Past a quarter of a second, the oldest ones are dropped.
+        for _ in 0..3 * tenth_of_a_second {
+            apu.tick();
+        }
+        assert_eq!(queue.len(), 44_100 / 4);
+        apu.stop_audio_output();
+        apu.tick();
+        assert_eq!(queue.len(), 44_100 / 4);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)] // The samples are moved around, never computed.
+    fn audio_queue_keeps_the_newest_samples() {
+        let queue = AudioQueue::new(4);
+        queue.push(&[1.0, 2.0, 3.0]);
+        queue.push(&[4.0, 5.0, 6.0]);
+        assert_eq!(queue.len(), 4);
+        let mut out = [0.0; 3];
+        assert_eq!(queue.pop_into(&mut out), 3);
+        assert_eq!(out, [3.0, 4.0, 5.0]);
+        let mut out = [0.0; 3];
+        assert_eq!(queue.pop_into(&mut out), 1);
+        assert_eq!(out, [6.0, 0.0, 0.0]);
+        assert!(queue.is_empty());
+    }
+}
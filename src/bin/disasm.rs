@@ -0,0 +1,48 @@
+//! Disassembler for ROM hacking: prints the code reachable from the reset vector, or
+//! from `--start ADDR` (in hex), of an iNES ROM.
+
+use std::io;
+use std::process::ExitCode;
+
+use nes_emu::disasm::listing;
+use nes_emu::trace::StderrTracer;
+use nes_emu::{Bus, Nes};
+
+fn run() -> Result<String, String> {
+    let mut start = None;
+    let mut rom_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--start" => {
+                let text = args.next().ok_or("--start expects an address")?;
+                let text = text.trim_start_matches('$').trim_start_matches("0x");
+                start = Some(
+                    u16::from_str_radix(text, 16)
+                        .map_err(|_| "--start expects a hexadecimal address")?,
+                );
+            }
+            _ => rom_path = rom_path.or(Some(arg)),
+        }
+    }
+    let rom_path = rom_path.ok_or("usage: disasm [--start ADDR] ROM")?;
+    let rom = std::fs::read(&rom_path).map_err(|err| format!("{rom_path}: {err}"))?;
+    let mut nes = Nes::from_rom_bytes(&rom).map_err(|err| format!("{rom_path}: {err}"))?;
+    nes.cpu_mut()
+        .set_tracer(StderrTracer::with_writer(io::sink()));
+    let start = start.unwrap_or_else(|| nes.bus_mut().peek_u16(0xFFFC));
+    Ok(listing(nes.bus_mut(), start))
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(listing) => {
+            print!("{listing}");
+            ExitCode::SUCCESS
+        }
+        Err(message) => {
+            eprintln!("disasm: {message}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Windowed front-end: runs a ROM at 60 frames per second, with the keyboard as the
+//! first controller.
+
+use minifb::{Key, Scale, Window, WindowOptions};
+use nes_emu::controller::Buttons;
+use nes_emu::ppu::Ppu;
+use nes_emu::trace::StderrTracer;
+use nes_emu::Nes;
+
+const KEY_MAP: [(Key, Buttons); 8] = [
+    (Key::X, Buttons::A),
+    (Key::Z, Buttons::B),
+    (Key::RightShift, Buttons::SELECT),
+    (Key::Enter, Buttons::START),
+    (Key::Up, Buttons::UP),
+    (Key::Down, Buttons::DOWN),
+    (Key::Left, Buttons::LEFT),
+    (Key::Right, Buttons::RIGHT),
+];
+
+fn main() {
+    let rom_path = std::env::args().nth(1).expect("Not enough arguments");
+    let rom = std::fs::read(rom_path).expect("Unable to read file");
+    let mut nes = Nes::from_rom_bytes(&rom).unwrap_or_else(|err| panic!("{err}"));
+    nes.cpu_mut()
+        .set_tracer(StderrTracer::with_writer(std::io::sink()));
+
+    let mut window = Window::new(
+        "nes-emu",
+        Ppu::WIDTH,
+        Ppu::HEIGHT,
+        WindowOptions {
+            scale: Scale::X2,
+            ..WindowOptions::default()
+        },
+    )
+    .unwrap_or_else(|err| panic!("{err}"));
+    window.set_target_fps(nes.region().frame_rate());
+
+    let mut buffer = vec![0; Ppu::WIDTH * Ppu::HEIGHT];
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let buttons = KEY_MAP
+            .iter()
+            .filter(|(key, _)| window.is_key_down(*key))
+            .fold(Buttons::empty(), |buttons, &(_, button)| buttons | button);
+        if let Some(controller) = nes.bus_mut().controller_mut(0) {
+            controller.set_buttons(buttons);
+        }
+
+        nes.run_frame().unwrap_or_else(|err| panic!("{err}"));
+
+        let rgb = nes.bus().ppu().expect("Nes always has a PPU").frame_rgb();
+        for (pixel, rgb) in buffer.iter_mut().zip(rgb.chunks_exact(3)) {
+            *pixel = u32::from(rgb[0]) << 16 | u32::from(rgb[1]) << 8 | u32::from(rgb[2]);
+        }
+        window
+            .update_with_buffer(&buffer, Ppu::WIDTH, Ppu::HEIGHT)
+            .unwrap_or_else(|err| panic!("{err}"));
+    }
+}
@@ -0,0 +1,86 @@
+use bitflags::bitflags;
+
+use crate::state::{Persist, StateError, StateReader, StateWriter};
+
+bitflags! {
+    /// Buttons of a standard controller, in the order they are shifted out.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct Buttons: u8 {
+        const A = 0b0000_0001;
+        const B = 0b0000_0010;
+        const SELECT = 0b0000_0100;
+        const START = 0b0000_1000;
+        const UP = 0b0001_0000;
+        const DOWN = 0b0010_0000;
+        const LEFT = 0b0100_0000;
+        const RIGHT = 0b1000_0000;
+    }
+}
+
+/// A standard controller: a shift register latching the buttons while the strobe bit
+/// written to $4016 is set, then shifting them out one per read.
+#[derive(Debug, Clone, Default)]
+pub struct Controller {
+    buttons: Buttons,
+    strobe: bool,
+    shift_register: u8,
+}
+
+impl Controller {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub const fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    /// Set the buttons currently held down.
+    pub const fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift_register = buttons.bits();
+        }
+    }
+
+    /// Handle a write to $4016, of which only bit 0 (the strobe) matters.
+    pub const fn write_strobe(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.shift_register = self.buttons.bits();
+        }
+    }
+
+    /// The next bit, in bit 0. Once all 8 buttons are out the official controllers
+    /// return 1s.
+    pub const fn read(&mut self) -> u8 {
+        let data = self.peek();
+        if !self.strobe {
+            self.shift_register = self.shift_register >> 1 | 0b1000_0000;
+        }
+        data
+    }
+
+    /// The bit the next read returns, without shifting.
+    #[must_use]
+    pub const fn peek(&self) -> u8 {
+        self.shift_register & 1
+    }
+}
+
+impl Persist for Controller {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.buttons.bits());
+        w.bool(self.strobe);
+        w.u8(self.shift_register);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.buttons = Buttons::from_bits_retain(r.u8()?);
+        self.strobe = r.bool()?;
+        self.shift_register = r.u8()?;
+        Ok(())
+    }
+}
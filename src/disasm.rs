@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::opcode::{relative_target, AddressingMode, OPCODES};
+use crate::Bus;
+
+/// Disassemble the instruction at `address`, returning its text and its length in bytes.
+///
+/// Memory is read with `peek`, so this has no effect on the machine.
+pub fn disassemble<B: Bus>(bus: &mut B, address: u16) -> (String, u8) {
+    let bytes = [0, 1, 2].map(|offset| bus.peek(address.wrapping_add(offset)));
+    disassemble_bytes(address, bytes)
+}
+
+/// Like [`disassemble`], for an instruction at `address` whose opcode and following two
+/// bytes are already known.
+#[must_use]
+pub fn disassemble_bytes(address: u16, bytes: [u8; 3]) -> (String, u8) {
+    let [opcode, low, high] = bytes;
+    let opcode = OPCODES[usize::from(opcode)];
+    let word = u16::from(high) << 8 | u16::from(low);
+    let operand = match opcode.mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => " A".to_string(),
+        AddressingMode::Immediate => format!(" #${low:02X}"),
+        AddressingMode::ZeroPage => format!(" ${low:02X}"),
+        AddressingMode::ZeroPageX => format!(" ${low:02X},X"),
+        AddressingMode::ZeroPageY => format!(" ${low:02X},Y"),
+        AddressingMode::Relative => {
+            let (target, _) = relative_target(address.wrapping_add(2), low);
+            format!(" ${target:04X}")
+        }
+        AddressingMode::Absolute => format!(" ${word:04X}"),
+        AddressingMode::AbsoluteX => format!(" ${word:04X},X"),
+        AddressingMode::AbsoluteY => format!(" ${word:04X},Y"),
+        AddressingMode::Indirect => format!(" (${word:04X})"),
+        AddressingMode::IndirectX => format!(" (${low:02X},X)"),
+        AddressingMode::IndirectY => format!(" (${low:02X}),Y"),
+    };
+    (
+        format!("{}{operand}", opcode.mnemonic),
+        opcode.instruction_len(),
+    )
+}
+
+/// Like [`disassemble`], followed by what `nestest.log` shows on the right of memory
+/// operands, e.g. `LDA $10,X @ 13 = 42`.
+///
+/// That is the effective address computed with the index registers `x` and `y`, any
+/// pointer on the way, and the value there.
+///
+/// Memory is read with `peek`, so this has no effect on the machine.
+pub fn disassemble_annotated<B: Bus>(bus: &mut B, address: u16, x: u8, y: u8) -> (String, u8) {
+    use AddressingMode as M;
+
+    let (text, len) = disassemble(bus, address);
+    let opcode = OPCODES[usize::from(bus.peek(address))];
+    let low = bus.peek(address.wrapping_add(1));
+    let word = bus.peek_u16(address.wrapping_add(1));
+    // Zero page pointers wrap around within the zero page.
+    let mut zp_pointer = |pointer: u8| {
+        u16::from_le_bytes([
+            bus.peek(u16::from(pointer)),
+            bus.peek(u16::from(pointer.wrapping_add(1))),
+        ])
+    };
+    let annotation = match opcode.mode {
+        M::ZeroPage => Some((String::new(), u16::from(low))),
+        M::ZeroPageX => {
+            let target = low.wrapping_add(x);
+            Some((format!(" @ {target:02X}"), u16::from(target)))
+        }
+        M::ZeroPageY => {
+            let target = low.wrapping_add(y);
+            Some((format!(" @ {target:02X}"), u16::from(target)))
+        }
+        M::Absolute if !matches!(opcode.mnemonic, "JMP" | "JSR") => Some((String::new(), word)),
+        M::AbsoluteX => {
+            let target = word.wrapping_add(u16::from(x));
+            Some((format!(" @ {target:04X}"), target))
+        }
+        M::AbsoluteY => {
+            let target = word.wrapping_add(u16::from(y));
+            Some((format!(" @ {target:04X}"), target))
+        }
+        M::IndirectX => {
+            let pointer = low.wrapping_add(x);
+            let target = zp_pointer(pointer);
+            Some((format!(" @ {pointer:02X} = {target:04X}"), target))
+        }
+        M::IndirectY => {
+            let base = zp_pointer(low);
+            let target = base.wrapping_add(u16::from(y));
+            Some((format!(" = {base:04X} @ {target:04X}"), target))
+        }
+        M::Indirect => {
+            // The high byte comes from the start of the page when the pointer ends one.
+            let high = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = u16::from_le_bytes([bus.peek(word), bus.peek(high)]);
+            return (format!("{text} = {target:04X}"), len);
+        }
+        _ => None,
+    };
+    match annotation {
+        Some((path, target)) => (format!("{text}{path} = {:02X}", bus.peek(target)), len),
+        None => (text, len),
+    }
+}
+
+/// Disassemble `count` consecutive instructions starting at `start`, as
+/// `(address, text, length)` triples.
+pub fn disassemble_range<B: Bus>(bus: &mut B, start: u16, count: usize) -> Vec<(u16, String, u8)> {
+    let mut address = start;
+    (0..count)
+        .map(|_| {
+            let (text, len) = disassemble(bus, address);
+            let line = (address, text, len);
+            address = address.wrapping_add(u16::from(len));
+            line
+        })
+        .collect()
+}
+
+/// Disassemble all the code reachable from `start`, in address order.
+///
+/// Branches, `JMP` and `JSR` are followed to targets known without running the code. A
+/// path ends at `RTS`, `RTI`, `BRK`, an indirect `JMP`, a jammed opcode, or an address
+/// nothing answers at.
+///
+/// Memory is read with `peek`, so this has no effect on the machine.
+pub fn disassemble_reachable<B: Bus>(bus: &mut B, start: u16) -> Vec<(u16, String, u8)> {
+    let mut lines = BTreeMap::new();
+    let mut pending = vec![start];
+    while let Some(mut address) = pending.pop() {
+        while !lines.contains_key(&address) && bus.is_mapped(address) {
+            let opcode = OPCODES[usize::from(bus.peek(address))];
+            let (text, len) = disassemble(bus, address);
+            lines.insert(address, (text, len));
+            let next = address.wrapping_add(u16::from(len));
+            let target = bus.peek_u16(address.wrapping_add(1));
+            match (opcode.mnemonic, opcode.mode) {
+                ("RTS" | "RTI" | "BRK" | "JAM", _) | ("JMP", AddressingMode::Indirect) => break,
+                ("JMP", _) => {
+                    pending.push(target);
+                    break;
+                }
+                ("JSR", _) => pending.push(target),
+                (_, AddressingMode::Relative) => {
+                    let offset = bus.peek(address.wrapping_add(1));
+                    pending.push(relative_target(next, offset).0);
+                }
+                _ => {}
+            }
+            address = next;
+        }
+    }
+    lines
+        .into_iter()
+        .map(|(address, (text, len))| (address, text, len))
+        .collect()
+}
+
+/// The code reachable from `start`, as [`disassemble_reachable`] finds it.
+///
+/// Each instruction goes on its own line with its address and bytes, as in
+/// `8000  A9 01     LDA #$01`, and a blank line separates runs of code that are not
+/// contiguous.
+///
+/// Memory is read with `peek`, so this has no effect on the machine.
+pub fn listing<B: Bus>(bus: &mut B, start: u16) -> String {
+    let mut out = String::new();
+    let mut expected = start;
+    for (address, text, len) in disassemble_reachable(bus, start) {
+        if address != expected {
+            out.push('\n');
+        }
+        let bytes = (0..u16::from(len))
+            .map(|offset| format!("{:02X}", bus.peek(address.wrapping_add(offset))))
+            .collect::<Vec<_>>()
+            .join(" ");
+        // Writing to a String cannot fail.
+        let _ = writeln!(out, "{address:04X}  {bytes:<8}  {text}");
+        expected = address.wrapping_add(u16::from(len));
+    }
+    out
+}
+
+/// Format `len` bytes starting at `start` as hex and ASCII, 16 bytes per line:
+/// `0200: A9 01 85 10 ...  |....|`.
+///
+/// Memory is read with `peek`, so this has no effect on the machine.
+pub fn hexdump<B: Bus>(bus: &mut B, start: u16, len: u16) -> String {
+    let bytes = (0..len)
+        .map(|offset| bus.peek(start.wrapping_add(offset)))
+        .collect::<Vec<_>>();
+    let mut out = String::new();
+    for (line, offset) in bytes.chunks(16).zip((0u16..).step_by(16)) {
+        let hex = line
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    char::from(byte)
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        let address = start.wrapping_add(offset);
+        // Writing to a String cannot fail.
+        let _ = writeln!(out, "{address:04X}: {hex:<47}  |{ascii}|");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FlatMemoryBus;
+
+    #[test]
+    fn disassemble_range_lists_consecutive_instructions() {
+        let mut bus = FlatMemoryBus::new();
+        // LDA #$01, STA $0200,X, BNE -5, ASL A, JMP ($1234)
+        bus.load(
+            0x0300,
+            &[
+                0xA9, 0x01, 0x9D, 0x00, 0x02, 0xD0, 0xF9, 0x0A, 0x6C, 0x34, 0x12,
+            ],
+        );
+        assert_eq!(
+            disassemble_range(&mut bus, 0x0300, 5),
+            [
+                (0x0300, "LDA #$01".to_string(), 2),
+                (0x0302, "STA $0200,X".to_string(), 3),
+                (0x0305, "BNE $0300".to_string(), 2),
+                (0x0307, "ASL A".to_string(), 1),
+                (0x0308, "JMP ($1234)".to_string(), 3),
+            ]
+        );
+        assert_eq!(disassemble_range(&mut bus, 0x0300, 0), []);
+        assert_eq!(bus.cycles(), 0);
+    }
+
+    /// `disassemble_annotated` for the instruction `bytes` at 0x0300, with X = 0x20 and
+    /// Y = 0x21.
+    fn annotated(bytes: &[u8]) -> String {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x0000, &[0x04]);
+        bus.load(0x0010, &[0x00, 0x04, 0x43]);
+        bus.load(0x00FF, &[0x80]);
+        bus.load(0x0400, &[0x44]);
+        bus.load(0x041F, &[0x45, 0x47]);
+        bus.load(0x04A1, &[0x46]);
+        bus.load(0x0500, &[0x12]);
+        bus.load(0x05FF, &[0x34, 0x99]);
+        bus.load(0x0300, bytes);
+        let (text, len) = disassemble_annotated(&mut bus, 0x0300, 0x20, 0x21);
+        assert_eq!(usize::from(len), bytes.len());
+        assert_eq!(bus.cycles(), 0);
+        text
+    }
+
+    #[test]
+    fn annotations_follow_each_addressing_mode() {
+        assert_eq!(annotated(&[0xE8]), "INX");
+        assert_eq!(annotated(&[0x0A]), "ASL A");
+        assert_eq!(annotated(&[0xA9, 0x10]), "LDA #$10");
+        assert_eq!(annotated(&[0xD0, 0x02]), "BNE $0304");
+        assert_eq!(annotated(&[0xA5, 0x12]), "LDA $12 = 43");
+        assert_eq!(annotated(&[0xB5, 0xF2]), "LDA $F2,X @ 12 = 43");
+        assert_eq!(annotated(&[0xB6, 0xF1]), "LDX $F1,Y @ 12 = 43");
+        assert_eq!(annotated(&[0xAD, 0x00, 0x04]), "LDA $0400 = 44");
+        assert_eq!(annotated(&[0x4C, 0x00, 0x04]), "JMP $0400");
+        assert_eq!(annotated(&[0x20, 0x00, 0x04]), "JSR $0400");
+        assert_eq!(annotated(&[0xBD, 0xFF, 0x03]), "LDA $03FF,X @ 041F = 45");
+        assert_eq!(annotated(&[0xB9, 0xFF, 0x03]), "LDA $03FF,Y @ 0420 = 47");
+        assert_eq!(annotated(&[0xA1, 0xF0]), "LDA ($F0,X) @ 10 = 0400 = 44");
+        // The pointer at 0xFF takes its high byte from 0x00.
+        assert_eq!(annotated(&[0xB1, 0xFF]), "LDA ($FF),Y = 0480 @ 04A1 = 46");
+        // So does JMP (indirect) at the end of a page, from the start of that page.
+        assert_eq!(annotated(&[0x6C, 0xFF, 0x05]), "JMP ($05FF) = 1234");
+    }
+
+    #[test]
+    fn listing_shows_bytes_and_separates_runs() {
+        let mut bus = FlatMemoryBus::new();
+        // LDX #$01, JSR $0310, RTS, then at 0x0310 INX, RTS.
+        bus.load(0x0300, &[0xA2, 0x01, 0x20, 0x10, 0x03, 0x60]);
+        bus.load(0x0310, &[0xE8, 0x60]);
+        assert_eq!(
+            listing(&mut bus, 0x0300),
+            "0300  A2 01     LDX #$01\n\
+             0302  20 10 03  JSR $0310\n\
+             0305  60        RTS\n\
+             \n\
+             0310  E8        INX\n\
+             0311  60        RTS\n"
+        );
+        assert_eq!(bus.cycles(), 0);
+    }
+
+    #[test]
+    fn hexdump_shows_16_bytes_a_line_with_ascii() {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x0200, b"Hello, NES!\x00\x01\xFF~ ABCDE");
+        assert_eq!(
+            hexdump(&mut bus, 0x0200, 20),
+            "0200: 48 65 6C 6C 6F 2C 20 4E 45 53 21 00 01 FF 7E 20  |Hello, NES!...~ |\n\
+             0210: 41 42 43 44                                      |ABCD|\n"
+        );
+        assert_eq!(hexdump(&mut bus, 0x0200, 0), "");
+        // The address wraps at the top of memory.
+        bus.load(0xFFFF, b"Z");
+        assert_eq!(
+            hexdump(&mut bus, 0xFFFF, 2),
+            format!("FFFF: 5A 00{}  |Z.|\n", " ".repeat(42))
+        );
+        assert_eq!(bus.cycles(), 0);
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+/// The 16 letters of Game Genie codes, in the order of the values they stand for.
+const LETTERS: &[u8; 16] = b"APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GgError {
+    /// Codes are 6 or 8 letters long.
+    InvalidLength(usize),
+    InvalidLetter(char),
+}
+
+impl fmt::Display for GgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength(len) => {
+                write!(f, "Game Genie codes have 6 or 8 letters, not {len}")
+            }
+            Self::InvalidLetter(letter) => {
+                write!(f, "'{letter}' is not a Game Genie letter")
+            }
+        }
+    }
+}
+
+impl std::error::Error for GgError {}
+
+/// Decode a 6 or 8 letter Game Genie code into the address, value and compare byte to
+/// give to `CpuMemoryBus::add_cheat`. 6 letter codes have no compare byte.
+///
+/// # Errors
+///
+/// Fails if the code has the wrong length or a letter that is not in the Game Genie
+/// alphabet. Lowercase letters are accepted.
+pub fn decode_game_genie(code: &str) -> Result<(u16, u8, Option<u8>), GgError> {
+    let n = code
+        .chars()
+        .map(|letter| {
+            LETTERS
+                .iter()
+                .zip(0..)
+                .find(|(&l, _)| char::from(l) == letter.to_ascii_uppercase())
+                .map(|(_, value)| value)
+                .ok_or(GgError::InvalidLetter(letter))
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+    if n.len() != 6 && n.len() != 8 {
+        return Err(GgError::InvalidLength(n.len()));
+    }
+
+    let address = 0x8000
+        | u16::from(n[3] & 7) << 12
+        | u16::from(n[4] & 8) << 8
+        | u16::from(n[5] & 7) << 8
+        | u16::from(n[1] & 8) << 4
+        | u16::from(n[2] & 7) << 4
+        | u16::from(n[3] & 8)
+        | u16::from(n[4] & 7);
+    // The bit that 6 letter codes take from the last letter moves to the 8th one.
+    let value_low = if n.len() == 8 { n[7] } else { n[5] };
+    let value = (n[0] & 8) << 4 | (n[1] & 7) << 4 | (value_low & 8) | (n[0] & 7);
+    let compare =
+        (n.len() == 8).then(|| (n[7] & 7) << 4 | (n[6] & 8) << 4 | (n[6] & 7) | (n[5] & 8));
+    Ok((address, value, compare))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn six_letter_code() {
+        // Super Mario Bros., infinite lives.
+        assert_eq!(decode_game_genie("SXIOPO"), Ok((0x91D9, 0xAD, None)));
+    }
+
+    #[test]
+    fn eight_letter_code() {
+        assert_eq!(
+            decode_game_genie("ZEXPYGLA"),
+            Ok((0x94A7, 0x02, Some(0x03)))
+        );
+        assert_eq!(
+            decode_game_genie("zexpygla"),
+            Ok((0x94A7, 0x02, Some(0x03)))
+        );
+    }
+
+    #[test]
+    fn invalid_codes() {
+        assert_eq!(decode_game_genie("SXIOP"), Err(GgError::InvalidLength(5)));
+        assert_eq!(
+            decode_game_genie("SXIOPB"),
+            Err(GgError::InvalidLetter('B'))
+        );
+    }
+}
@@ -0,0 +1,4939 @@
+use std::fmt;
+
+use bitflags::bitflags;
+
+pub mod apu;
+pub mod controller;
+pub mod disasm;
+pub mod game_genie;
+pub mod nes;
+pub mod opcode;
+pub mod ppu;
+pub mod rom;
+mod state;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod trace;
+
+pub use nes::{diff_state, Nes, SaveState, StateDiff};
+pub use state::StateError;
+
+use apu::Apu;
+use controller::Controller;
+use opcode::{relative_target, AddressingMode, Opcode, OPCODES};
+use ppu::Ppu;
+use rom::{RomHeader, RomLoadError};
+use state::{Persist, StateReader, StateWriter};
+use trace::{StderrTracer, TraceContext, Tracer};
+
+/// Send a formatted line to the CPU's tracer.
+macro_rules! trace {
+    ($cpu:expr, $($arg:tt)*) => {
+        $cpu.tracer.trace(format_args!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    /// All four nametables show the first 1KB of VRAM.
+    SingleA,
+    /// All four nametables show the second 1KB of VRAM.
+    SingleB,
+    /// The cartridge provides another 2KB so each nametable has its own memory.
+    FourScreen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    /// Average CPU cycles per frame, counting the dot skipped on odd NTSC frames.
+    #[must_use]
+    pub const fn cpu_cycles_per_frame(self) -> f64 {
+        match self {
+            Self::Ntsc => 29_780.5,
+            Self::Pal => 33_247.5,
+        }
+    }
+
+    /// CPU clock frequency in Hz, which is also the rate the APU produces samples at.
+    #[must_use]
+    pub const fn cpu_clock_rate(self) -> f64 {
+        match self {
+            Self::Ntsc => 1_789_773.0,
+            Self::Pal => 1_662_607.0,
+        }
+    }
+
+    /// Scanlines per frame, from the first visible one to the pre-render line.
+    #[must_use]
+    pub const fn scanlines_per_frame(self) -> u16 {
+        match self {
+            Self::Ntsc => 262,
+            Self::Pal => 312,
+        }
+    }
+
+    /// PPU dots run in the first `cpu_cycles` CPU cycles: three per cycle on NTSC, and
+    /// sixteen every five cycles on PAL.
+    #[must_use]
+    pub const fn ppu_dots(self, cpu_cycles: u64) -> u64 {
+        match self {
+            Self::Ntsc => cpu_cycles * 3,
+            Self::Pal => cpu_cycles * 16 / 5,
+        }
+    }
+
+    /// Frames per second, rounded to what a frontend can pace to.
+    #[must_use]
+    pub const fn frame_rate(self) -> usize {
+        match self {
+            Self::Ntsc => 60,
+            Self::Pal => 50,
+        }
+    }
+}
+
+/// One window of an address space and the bank it currently shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankWindow {
+    pub start: u16,
+    pub size: usize,
+    /// Index of the bank in the ROM (or RAM), counted in banks of `size` bytes.
+    pub bank: usize,
+}
+
+impl BankWindow {
+    /// A window of `size` bytes at `start` showing `bank`, wrapped to the banks that
+    /// `data_len` bytes of ROM or RAM hold like the hardware address lines do.
+    const fn wrapped(start: u16, size: usize, bank: usize, data_len: usize) -> Self {
+        let banks = data_len / size;
+        Self {
+            start,
+            size,
+            bank: if banks == 0 { 0 } else { bank % banks },
+        }
+    }
+}
+
+/// What a mapper currently has switched in, for debuggers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BankState {
+    /// Windows of the CPU address space at 0x8000-0xFFFF.
+    pub prg: Vec<BankWindow>,
+    /// Windows of the PPU address space at 0x0000-0x1FFF.
+    pub chr: Vec<BankWindow>,
+    pub mirroring: Mirroring,
+}
+
+pub trait Mapper {
+    fn read(&mut self, address: u16) -> Option<u8>;
+    fn write(&mut self, address: u16, data: u8) -> bool;
+    fn mirroring(&self) -> Mirroring;
+    /// Human readable description of the board and its current banking.
+    fn summary(&self) -> String;
+    fn current_banks(&self) -> BankState;
+
+    /// Read from the PPU address space (pattern tables), as opposed to `read` which
+    /// serves the CPU address space. Boards whose CHR reads have side effects override
+    /// this, the others only implement `ppu_peek`.
+    fn ppu_read(&mut self, address: u16) -> Option<u8> {
+        self.ppu_peek(address)
+    }
+
+    /// Like `ppu_read`, without side effects, for debug views.
+    fn ppu_peek(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn ppu_write(&mut self, _address: u16, _data: u8) -> bool {
+        false
+    }
+
+    /// State of the board's /IRQ output, true when asserted. None of the boards
+    /// emulated so far have one.
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Whether the cartridge's work RAM at 0x6000-0x7FFF currently answers. When it
+    /// does not, reads there are open bus and writes are dropped.
+    fn wram_enabled(&self) -> bool {
+        true
+    }
+}
+
+/// The CPU's view of memory. Every `read` and `write` takes one CPU cycle.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, data: u8);
+    /// Read without side effects: the open bus latch is left alone and nothing is logged.
+    fn peek(&mut self, address: u16) -> u8;
+    /// Number of CPU cycles elapsed, one per bus access.
+    fn cycles(&self) -> u64;
+
+    /// Whether anything answers reads at `address`, as opposed to it being open bus.
+    fn is_mapped(&mut self, _address: u16) -> bool {
+        true
+    }
+
+    /// The scanline and dot of the PPU on this bus, if there is one, for trace logs.
+    fn ppu_position(&self) -> Option<(u16, u16)> {
+        None
+    }
+
+    /// Whether /NMI has been asserted since the interrupt lines were last polled, for
+    /// the CPU to sample in the middle of an interrupt sequence. Buses without an NMI
+    /// source leave the line to `Cpu::set_nmi_line`.
+    fn nmi_asserted(&mut self) -> bool {
+        false
+    }
+
+    /// Read a little-endian word at `address`.
+    fn read_u16(&mut self, address: u16) -> u16 {
+        u16::from(self.read(address)) | u16::from(self.read(address.wrapping_add(1))) << 8
+    }
+
+    /// Like `read_u16`, without side effects.
+    fn peek_u16(&mut self, address: u16) -> u16 {
+        u16::from(self.peek(address)) | u16::from(self.peek(address.wrapping_add(1))) << 8
+    }
+
+    /// Read a little-endian word from the zero page, the high byte wrapping around to
+    /// 0x00 rather than crossing into page one.
+    fn read_u16_zp(&mut self, pointer: u8) -> u16 {
+        u16::from(self.read(u16::from(pointer)))
+            | u16::from(self.read(u16::from(pointer.wrapping_add(1)))) << 8
+    }
+}
+
+pub trait CpuBusMember {
+    fn read(&mut self, address: u16) -> Option<u8>;
+    fn write(&mut self, address: u16, data: u8) -> bool;
+}
+
+#[derive(Clone)]
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    /// An empty `chr_rom` means the board has 8KB of CHR-RAM instead.
+    #[must_use]
+    pub fn new(prg_rom: &[u8], chr_rom: &[u8], mirroring: Mirroring) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Self {
+            prg_rom: prg_rom.to_vec(),
+            chr: if chr_is_ram {
+                vec![0; 8 * 1024]
+            } else {
+                chr_rom.to_vec()
+            },
+            chr_is_ram,
+            mirroring,
+        }
+    }
+
+    /// Resize the CHR-RAM of boards without CHR ROM. Ignored for 0, or when there is
+    /// CHR ROM.
+    #[must_use]
+    pub fn with_chr_ram_size(mut self, size: usize) -> Self {
+        if self.chr_is_ram && size > 0 {
+            self.chr = vec![0; size];
+        }
+        self
+    }
+}
+
+impl Mapper for Nrom {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        match address {
+            // 16KB boards mirror their only bank at 0xC000.
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => {
+                Some(self.prg_rom[(address as usize - 0x8000) % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, _address: u16, _data: u8) -> bool {
+        false
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "NROM: {}KB PRG ROM, {}KB CHR {}, {:?} mirroring",
+            self.prg_rom.len() / 1024,
+            self.chr.len() / 1024,
+            if self.chr_is_ram { "RAM" } else { "ROM" },
+            self.mirroring
+        )
+    }
+
+    fn current_banks(&self) -> BankState {
+        let prg_len = self.prg_rom.len();
+        BankState {
+            prg: vec![
+                BankWindow::wrapped(0x8000, 16 * 1024, 0, prg_len),
+                BankWindow::wrapped(0xC000, 16 * 1024, 1, prg_len),
+            ],
+            chr: vec![BankWindow::wrapped(0x0000, 8 * 1024, 0, self.chr.len())],
+            mirroring: self.mirroring,
+        }
+    }
+
+    fn ppu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000..=0x1FFF => self.chr.get(address as usize).copied(),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, data: u8) -> bool {
+        match address {
+            0x0000..=0x1FFF if self.chr_is_ram => {
+                if let Some(byte) = self.chr.get_mut(address as usize) {
+                    *byte = data;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Persist for Nrom {
+    fn write_state(&self, w: &mut StateWriter) {
+        if self.chr_is_ram {
+            w.bytes(&self.chr);
+        }
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        if self.chr_is_ram {
+            r.bytes_into(&mut self.chr)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Mmc1 {
+    pages: Vec<[u8; Self::ROM_PAGE_SIZE]>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    /// Serial port: bits come in LSB first, and the marker bit reaching bit 0 means the
+    /// fifth write is next.
+    shift_register: u8,
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub const ROM_PAGE_SIZE: usize = 16 * 1024;
+    pub const CHR_PAGE_SIZE: usize = 8 * 1024;
+    const CHR_BANK_SIZE: usize = 4 * 1024;
+    const SHIFT_REGISTER_EMPTY: u8 = 0b1_0000;
+    /// PRG banks reachable without the SUROM/SXROM outer bank bit.
+    const PRG_BANKS_PER_HALF: usize = 16;
+
+    /// An empty `chr_rom` means the board has 8KB of CHR-RAM instead.
+    #[must_use]
+    pub fn new(prg_rom: &[u8], chr_rom: &[u8], mirroring: Mirroring) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Self {
+            chr: if chr_is_ram {
+                vec![0; Self::CHR_PAGE_SIZE]
+            } else {
+                chr_rom.to_vec()
+            },
+            chr_is_ram,
+            pages: prg_rom
+                .chunks_exact(Self::ROM_PAGE_SIZE)
+                .map(|d| {
+                    let mut page = [0; Self::ROM_PAGE_SIZE];
+                    page.copy_from_slice(d);
+                    page
+                })
+                .collect(),
+            shift_register: Self::SHIFT_REGISTER_EMPTY,
+            // Power on in the mode with the last bank fixed at $C000, so the reset vector
+            // is reachable, and with the mirroring the header asks for until the game
+            // sets its own.
+            control: match mirroring {
+                Mirroring::Vertical => 0x0E,
+                _ => 0x0F,
+            },
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    /// Resize the CHR-RAM of boards without CHR ROM. Ignored for 0, or when there is
+    /// CHR ROM.
+    #[must_use]
+    pub fn with_chr_ram_size(mut self, size: usize) -> Self {
+        if self.chr_is_ram && size > 0 {
+            self.chr = vec![0; size];
+        }
+        self
+    }
+
+    /// Index in `pages` of the 16KB bank mapped at `address`.
+    ///
+    /// Boards with more than 256KB of PRG (SUROM, SXROM) use bit 4 of the CHR bank 0
+    /// register to pick which 256KB half every PRG bank, fixed ones included, comes from.
+    fn prg_page(&self, address: u16) -> usize {
+        let outer = if self.pages.len() > Self::PRG_BANKS_PER_HALF {
+            usize::from(self.chr_bank0 & 0x10)
+        } else {
+            0
+        };
+        let bank = usize::from(self.prg_bank & 0x0F);
+        let upper_half = address >= 0xC000;
+        let inner = match (self.control >> 2) & 0x03 {
+            0 | 1 => bank & !1 | usize::from(upper_half),
+            2 if upper_half => bank,
+            2 => 0,
+            _ if upper_half => Self::PRG_BANKS_PER_HALF - 1,
+            _ => bank,
+        };
+        (outer | inner) % self.pages.len()
+    }
+
+    fn chr_address(&self, address: u16) -> usize {
+        let offset = usize::from(address) % Self::CHR_BANK_SIZE;
+        let bank = if self.control & 0x10 == 0 {
+            usize::from(self.chr_bank0 & !1) | usize::from(address >= 0x1000)
+        } else if address < 0x1000 {
+            usize::from(self.chr_bank0)
+        } else {
+            usize::from(self.chr_bank1)
+        };
+        (bank * Self::CHR_BANK_SIZE + offset) % self.chr.len()
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xFFFF if !self.pages.is_empty() => {
+                Some(self.pages[self.prg_page(address)][usize::from(address) % Self::ROM_PAGE_SIZE])
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> bool {
+        if address < 0x8000 {
+            return false;
+        }
+        if data & 0x80 != 0 {
+            self.shift_register = Self::SHIFT_REGISTER_EMPTY;
+            self.control |= 0x0C;
+            return true;
+        }
+        let complete = self.shift_register & 0x01 != 0;
+        self.shift_register = self.shift_register >> 1 | (data & 0x01) << 4;
+        if complete {
+            let value = self.shift_register;
+            match address {
+                0x8000..=0x9FFF => self.control = value,
+                0xA000..=0xBFFF => self.chr_bank0 = value,
+                0xC000..=0xDFFF => self.chr_bank1 = value,
+                _ => self.prg_bank = value,
+            }
+            self.shift_register = Self::SHIFT_REGISTER_EMPTY;
+        }
+        true
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleA,
+            1 => Mirroring::SingleB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "MMC1: {} x 16KB PRG ROM, {}KB CHR {}, {:?} mirroring",
+            self.pages.len(),
+            self.chr.len() / 1024,
+            if self.chr_is_ram { "RAM" } else { "ROM" },
+            self.mirroring()
+        )
+    }
+
+    fn current_banks(&self) -> BankState {
+        let prg_window = |start| BankWindow {
+            start,
+            size: Self::ROM_PAGE_SIZE,
+            bank: if self.pages.is_empty() {
+                0
+            } else {
+                self.prg_page(start)
+            },
+        };
+        let chr_window = |start| BankWindow {
+            start,
+            size: Self::CHR_BANK_SIZE,
+            bank: self.chr_address(start) / Self::CHR_BANK_SIZE,
+        };
+        BankState {
+            prg: vec![prg_window(0x8000), prg_window(0xC000)],
+            chr: vec![chr_window(0x0000), chr_window(0x1000)],
+            mirroring: self.mirroring(),
+        }
+    }
+
+    fn ppu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000..=0x1FFF => Some(self.chr[self.chr_address(address)]),
+            _ => None,
+        }
+    }
+
+    /// Bit 4 of the PRG bank register disables the work RAM (on MMC1B and later).
+    fn wram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    fn ppu_write(&mut self, address: u16, data: u8) -> bool {
+        match address {
+            0x0000..=0x1FFF if self.chr_is_ram => {
+                let index = self.chr_address(address);
+                self.chr[index] = data;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Persist for Mmc1 {
+    fn write_state(&self, w: &mut StateWriter) {
+        if self.chr_is_ram {
+            w.bytes(&self.chr);
+        }
+        w.u8(self.shift_register);
+        w.u8(self.control);
+        w.u8(self.chr_bank0);
+        w.u8(self.chr_bank1);
+        w.u8(self.prg_bank);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        if self.chr_is_ram {
+            r.bytes_into(&mut self.chr)?;
+        }
+        self.shift_register = r.u8()?;
+        self.control = r.u8()?;
+        self.chr_bank0 = r.u8()?;
+        self.chr_bank1 = r.u8()?;
+        self.prg_bank = r.u8()?;
+        Ok(())
+    }
+}
+
+/// Mapper 7: 32KB PRG banks, with the same register choosing which 1KB of VRAM all the
+/// nametables show.
+#[derive(Clone)]
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    bank_select: u8,
+}
+
+impl Axrom {
+    pub const PRG_BANK_SIZE: usize = 32 * 1024;
+
+    /// An empty `chr_rom` means the board has 8KB of CHR-RAM instead, as on all
+    /// official boards.
+    #[must_use]
+    pub fn new(prg_rom: &[u8], chr_rom: &[u8]) -> Self {
+        let chr_is_ram = chr_rom.is_empty();
+        Self {
+            prg_rom: prg_rom.to_vec(),
+            chr: if chr_is_ram {
+                vec![0; 8 * 1024]
+            } else {
+                chr_rom.to_vec()
+            },
+            chr_is_ram,
+            // Games can't rely on the power-on value, but the last bank is the likeliest
+            // to hold the reset vector.
+            bank_select: 0x07,
+        }
+    }
+
+    /// Resize the CHR-RAM of boards without CHR ROM. Ignored for 0, or when there is
+    /// CHR ROM.
+    #[must_use]
+    pub fn with_chr_ram_size(mut self, size: usize) -> Self {
+        if self.chr_is_ram && size > 0 {
+            self.chr = vec![0; size];
+        }
+        self
+    }
+}
+
+impl Mapper for Axrom {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => {
+                let bank = usize::from(self.bank_select & 0x07);
+                let offset = bank * Self::PRG_BANK_SIZE + usize::from(address - 0x8000);
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> bool {
+        if address < 0x8000 {
+            return false;
+        }
+        self.bank_select = data;
+        true
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.bank_select & 0x10 == 0 {
+            Mirroring::SingleA
+        } else {
+            Mirroring::SingleB
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "AxROM: {} x 32KB PRG ROM, {}KB CHR {}, bank {}, {:?} mirroring",
+            self.prg_rom.len() / Self::PRG_BANK_SIZE,
+            self.chr.len() / 1024,
+            if self.chr_is_ram { "RAM" } else { "ROM" },
+            self.bank_select & 0x07,
+            self.mirroring()
+        )
+    }
+
+    fn current_banks(&self) -> BankState {
+        let bank = usize::from(self.bank_select & 0x07);
+        BankState {
+            prg: vec![BankWindow::wrapped(
+                0x8000,
+                Self::PRG_BANK_SIZE,
+                bank,
+                self.prg_rom.len(),
+            )],
+            chr: vec![BankWindow::wrapped(0x0000, 8 * 1024, 0, self.chr.len())],
+            mirroring: self.mirroring(),
+        }
+    }
+
+    fn ppu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000..=0x1FFF => self.chr.get(usize::from(address)).copied(),
+            _ => None,
+        }
+    }
+
+    fn ppu_write(&mut self, address: u16, data: u8) -> bool {
+        match address {
+            0x0000..=0x1FFF if self.chr_is_ram => {
+                if let Some(byte) = self.chr.get_mut(usize::from(address)) {
+                    *byte = data;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Persist for Axrom {
+    fn write_state(&self, w: &mut StateWriter) {
+        if self.chr_is_ram {
+            w.bytes(&self.chr);
+        }
+        w.u8(self.bank_select);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        if self.chr_is_ram {
+            r.bytes_into(&mut self.chr)?;
+        }
+        self.bank_select = r.u8()?;
+        Ok(())
+    }
+}
+
+/// Mapper 66: one register picks both the 32KB PRG bank (bits 4-5) and the 8KB CHR bank
+/// (bits 0-1).
+#[derive(Clone)]
+pub struct Gxrom {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    mirroring: Mirroring,
+    bank_select: u8,
+}
+
+impl Gxrom {
+    pub const PRG_BANK_SIZE: usize = 32 * 1024;
+    pub const CHR_BANK_SIZE: usize = 8 * 1024;
+
+    #[must_use]
+    pub fn new(prg_rom: &[u8], chr_rom: &[u8], mirroring: Mirroring) -> Self {
+        Self {
+            prg_rom: prg_rom.to_vec(),
+            chr_rom: chr_rom.to_vec(),
+            mirroring,
+            bank_select: 0,
+        }
+    }
+}
+
+impl Mapper for Gxrom {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        match address {
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => {
+                let bank = usize::from(self.bank_select >> 4 & 0x03);
+                let offset = bank * Self::PRG_BANK_SIZE + usize::from(address - 0x8000);
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> bool {
+        if address < 0x8000 {
+            return false;
+        }
+        self.bank_select = data;
+        true
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "GxROM: {} x 32KB PRG ROM, {} x 8KB CHR ROM, banks {}/{}, {:?} mirroring",
+            self.prg_rom.len() / Self::PRG_BANK_SIZE,
+            self.chr_rom.len() / Self::CHR_BANK_SIZE,
+            self.bank_select >> 4 & 0x03,
+            self.bank_select & 0x03,
+            self.mirroring
+        )
+    }
+
+    fn current_banks(&self) -> BankState {
+        BankState {
+            prg: vec![BankWindow::wrapped(
+                0x8000,
+                Self::PRG_BANK_SIZE,
+                usize::from(self.bank_select >> 4 & 0x03),
+                self.prg_rom.len(),
+            )],
+            chr: vec![BankWindow::wrapped(
+                0x0000,
+                Self::CHR_BANK_SIZE,
+                usize::from(self.bank_select & 0x03),
+                self.chr_rom.len(),
+            )],
+            mirroring: self.mirroring,
+        }
+    }
+
+    fn ppu_peek(&self, address: u16) -> Option<u8> {
+        match address {
+            0x0000..=0x1FFF if !self.chr_rom.is_empty() => {
+                let bank = usize::from(self.bank_select & 0x03);
+                let offset = bank * Self::CHR_BANK_SIZE + usize::from(address);
+                Some(self.chr_rom[offset % self.chr_rom.len()])
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Persist for Gxrom {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.bank_select);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.bank_select = r.u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub enum MapperEnum {
+    Nrom(Nrom),
+    Mmc1(Mmc1),
+    Axrom(Axrom),
+    Gxrom(Gxrom),
+}
+
+impl MapperEnum {
+    pub fn read(&mut self, address: u16) -> Option<u8> {
+        match self {
+            Self::Nrom(nrom) => nrom.read(address),
+            Self::Mmc1(mmc1) => mmc1.read(address),
+            Self::Axrom(axrom) => axrom.read(address),
+            Self::Gxrom(gxrom) => gxrom.read(address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) -> bool {
+        match self {
+            Self::Nrom(nrom) => nrom.write(address, data),
+            Self::Mmc1(mmc1) => mmc1.write(address, data),
+            Self::Axrom(axrom) => axrom.write(address, data),
+            Self::Gxrom(gxrom) => gxrom.write(address, data),
+        }
+    }
+
+    #[must_use]
+    pub fn mirroring(&self) -> Mirroring {
+        match self {
+            Self::Nrom(nrom) => nrom.mirroring(),
+            Self::Mmc1(mmc1) => mmc1.mirroring(),
+            Self::Axrom(axrom) => axrom.mirroring(),
+            Self::Gxrom(gxrom) => gxrom.mirroring(),
+        }
+    }
+
+    #[must_use]
+    pub fn summary(&self) -> String {
+        match self {
+            Self::Nrom(nrom) => nrom.summary(),
+            Self::Mmc1(mmc1) => mmc1.summary(),
+            Self::Axrom(axrom) => axrom.summary(),
+            Self::Gxrom(gxrom) => gxrom.summary(),
+        }
+    }
+
+    #[must_use]
+    pub fn current_banks(&self) -> BankState {
+        match self {
+            Self::Nrom(nrom) => nrom.current_banks(),
+            Self::Mmc1(mmc1) => mmc1.current_banks(),
+            Self::Axrom(axrom) => axrom.current_banks(),
+            Self::Gxrom(gxrom) => gxrom.current_banks(),
+        }
+    }
+
+    pub fn ppu_read(&mut self, address: u16) -> Option<u8> {
+        match self {
+            Self::Nrom(nrom) => nrom.ppu_read(address),
+            Self::Mmc1(mmc1) => mmc1.ppu_read(address),
+            Self::Axrom(axrom) => axrom.ppu_read(address),
+            Self::Gxrom(gxrom) => gxrom.ppu_read(address),
+        }
+    }
+
+    #[must_use]
+    pub fn ppu_peek(&self, address: u16) -> Option<u8> {
+        match self {
+            Self::Nrom(nrom) => nrom.ppu_peek(address),
+            Self::Mmc1(mmc1) => mmc1.ppu_peek(address),
+            Self::Axrom(axrom) => axrom.ppu_peek(address),
+            Self::Gxrom(gxrom) => gxrom.ppu_peek(address),
+        }
+    }
+
+    pub fn ppu_write(&mut self, address: u16, data: u8) -> bool {
+        match self {
+            Self::Nrom(nrom) => nrom.ppu_write(address, data),
+            Self::Mmc1(mmc1) => mmc1.ppu_write(address, data),
+            Self::Axrom(axrom) => axrom.ppu_write(address, data),
+            Self::Gxrom(gxrom) => gxrom.ppu_write(address, data),
+        }
+    }
+
+    #[must_use]
+    pub fn irq_pending(&self) -> bool {
+        match self {
+            Self::Nrom(nrom) => nrom.irq_pending(),
+            Self::Mmc1(mmc1) => mmc1.irq_pending(),
+            Self::Axrom(axrom) => axrom.irq_pending(),
+            Self::Gxrom(gxrom) => gxrom.irq_pending(),
+        }
+    }
+
+    #[must_use]
+    pub fn wram_enabled(&self) -> bool {
+        match self {
+            Self::Nrom(nrom) => nrom.wram_enabled(),
+            Self::Mmc1(mmc1) => mmc1.wram_enabled(),
+            Self::Axrom(axrom) => axrom.wram_enabled(),
+            Self::Gxrom(gxrom) => gxrom.wram_enabled(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Cart {
+    mapper: MapperEnum,
+    /// Work RAM at 0x6000-0x7FFF, mirrored when smaller than that window and absent
+    /// when empty.
+    wram: Box<[u8]>,
+    /// Holds /IRQ asserted, standing in for a mapper IRQ until a board has one.
+    #[cfg(test)]
+    forced_irq: bool,
+}
+
+impl Cart {
+    pub const WRAM_SIZE: usize = 0x2000;
+    const TRAINER_ADDRESS: usize = 0x7000 - 0x6000;
+
+    #[must_use]
+    pub fn new(mapper: MapperEnum) -> Self {
+        Self {
+            mapper,
+            wram: vec![0; Self::WRAM_SIZE].into_boxed_slice(),
+            #[cfg(test)]
+            forced_irq: false,
+        }
+    }
+
+    /// Replace the default 8KB of work RAM with `size` bytes.
+    #[must_use]
+    pub fn with_wram_size(mut self, size: usize) -> Self {
+        self.wram = vec![0; size].into_boxed_slice();
+        self
+    }
+
+    /// Work RAM as seen at 0x6000, before mirroring.
+    #[must_use]
+    pub fn wram(&self) -> &[u8] {
+        &self.wram
+    }
+
+    /// Copy an iNES trainer to 0x7000-0x71FF, where the game expects to find it.
+    pub fn load_trainer(&mut self, trainer: &[u8]) {
+        let len = trainer.len().min(RomHeader::TRAINER_SIZE);
+        if let Some(destination) = self
+            .wram
+            .get_mut(Self::TRAINER_ADDRESS..Self::TRAINER_ADDRESS + len)
+        {
+            destination.copy_from_slice(&trainer[..len]);
+        }
+    }
+
+    /// Pick the mapper named by `header` and load the PRG and CHR data into it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RomLoadError::UnsupportedMapper`] for mappers that are not emulated.
+    pub fn from_header_and_data(
+        header: &RomHeader,
+        prg: &[u8],
+        chr: &[u8],
+    ) -> Result<Self, RomLoadError> {
+        let chr_ram_size = header.chr_ram_size + header.chr_nvram_size;
+        let mapper = match header.mapper_number {
+            0 => MapperEnum::Nrom(
+                Nrom::new(prg, chr, header.mirroring).with_chr_ram_size(chr_ram_size),
+            ),
+            1 => MapperEnum::Mmc1(
+                Mmc1::new(prg, chr, header.mirroring).with_chr_ram_size(chr_ram_size),
+            ),
+            7 => MapperEnum::Axrom(Axrom::new(prg, chr).with_chr_ram_size(chr_ram_size)),
+            66 => MapperEnum::Gxrom(Gxrom::new(prg, chr, header.mirroring)),
+            mapper_number => return Err(RomLoadError::UnsupportedMapper(mapper_number)),
+        };
+        Ok(Self::new(mapper).with_wram_size(header.prg_ram_size + header.prg_nvram_size))
+    }
+
+    #[must_use]
+    pub fn summary(&self) -> String {
+        self.mapper.summary()
+    }
+
+    #[must_use]
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring()
+    }
+
+    /// The PRG and CHR banks switched in and the mirroring, for a mapper debugger.
+    #[must_use]
+    pub fn current_banks(&self) -> BankState {
+        self.mapper.current_banks()
+    }
+
+    /// Pattern table fetches from the PPU go through here so that CHR banking stays
+    /// inside the mapper.
+    pub fn ppu_read(&mut self, address: u16) -> Option<u8> {
+        self.mapper.ppu_read(address)
+    }
+
+    /// Like `ppu_read`, without side effects.
+    #[must_use]
+    pub fn ppu_peek(&self, address: u16) -> Option<u8> {
+        self.mapper.ppu_peek(address)
+    }
+
+    pub fn ppu_write(&mut self, address: u16, data: u8) -> bool {
+        self.mapper.ppu_write(address, data)
+    }
+
+    /// Whether the mapper asserts /IRQ.
+    #[must_use]
+    pub fn irq_pending(&self) -> bool {
+        #[cfg(test)]
+        if self.forced_irq {
+            return true;
+        }
+        self.mapper.irq_pending()
+    }
+}
+
+impl CpuBusMember for Cart {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        self.mapper.read(address).or_else(|| match address {
+            0x6000..=0x7FFF if !self.wram.is_empty() && self.mapper.wram_enabled() => {
+                Some(self.wram[usize::from(address - 0x6000) % self.wram.len()])
+            }
+            _ => None,
+        })
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> bool {
+        if self.mapper.write(address, data) {
+            return true;
+        }
+        match address {
+            0x6000..=0x7FFF if !self.wram.is_empty() && self.mapper.wram_enabled() => {
+                let len = self.wram.len();
+                self.wram[usize::from(address - 0x6000) % len] = data;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The mapper is saved after a tag telling which one it is, which must match.
+impl Persist for MapperEnum {
+    fn write_state(&self, w: &mut StateWriter) {
+        match self {
+            Self::Nrom(nrom) => {
+                w.u8(0);
+                nrom.write_state(w);
+            }
+            Self::Mmc1(mmc1) => {
+                w.u8(1);
+                mmc1.write_state(w);
+            }
+            Self::Axrom(axrom) => {
+                w.u8(2);
+                axrom.write_state(w);
+            }
+            Self::Gxrom(gxrom) => {
+                w.u8(3);
+                gxrom.write_state(w);
+            }
+        }
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        match (r.u8()?, self) {
+            (0, Self::Nrom(nrom)) => nrom.read_state(r),
+            (1, Self::Mmc1(mmc1)) => mmc1.read_state(r),
+            (2, Self::Axrom(axrom)) => axrom.read_state(r),
+            (3, Self::Gxrom(gxrom)) => gxrom.read_state(r),
+            _ => Err(StateError::Mismatch),
+        }
+    }
+}
+
+impl Persist for Cart {
+    fn write_state(&self, w: &mut StateWriter) {
+        self.mapper.write_state(w);
+        w.bytes(&self.wram);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.mapper.read_state(r)?;
+        r.bytes_into(&mut self.wram)
+    }
+}
+
+#[derive(Clone)]
+pub struct Ram {
+    storage: Box<[u8; Self::RAM_SIZE]>,
+}
+
+impl Ram {
+    const RAM_SIZE: usize = 2 * 1024;
+
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            storage: Box::new([0; Self::RAM_SIZE]),
+        }
+    }
+
+    /// The 2KB that appear at 0x0000-0x07FF and are mirrored up to 0x1FFF.
+    #[must_use]
+    pub fn contents(&self) -> &[u8] {
+        &*self.storage
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuBusMember for Ram {
+    fn read(&mut self, address: u16) -> Option<u8> {
+        if address > 0x1FFF {
+            return None;
+        }
+        Some(self.storage[address as usize % Self::RAM_SIZE])
+    }
+
+    fn write(&mut self, address: u16, data: u8) -> bool {
+        if address > 0x1FFF {
+            return false;
+        }
+        self.storage[address as usize % Self::RAM_SIZE] = data;
+        true
+    }
+}
+
+impl Persist for Ram {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bytes(&*self.storage);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        r.bytes_into(&mut *self.storage)
+    }
+}
+
+/// What a read from an address no bus member answers returns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpenBusMode {
+    /// The last value that went over the data bus, which is what the hardware does.
+    #[default]
+    LastValue,
+    /// The high byte of the address, as left on the bus by the operand fetch of most
+    /// absolute addressing modes.
+    AddressHigh,
+    Zero,
+}
+
+/// What to do about a CPU write to 0x8000-0xFFFF that the mapper does not take, which
+/// only lands in ROM and so is usually a bug in the game or in the mapper.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RomWriteMode {
+    /// Drop it silently, like the hardware does.
+    Ignore,
+    /// Drop it with a warning on stderr, and count it in
+    /// [`CpuMemoryBus::logged_rom_writes`].
+    #[default]
+    Log,
+    /// Keep it for [`CpuMemoryBus::take_rom_write`], so that `Nes::step` stops with
+    /// `CpuError::RomWrite` after the instruction.
+    Trap,
+}
+
+/// How the PPU is kept in step with the CPU.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PpuTiming {
+    /// The dots of each CPU cycle right after it, three on NTSC.
+    #[default]
+    PerCycle,
+    /// The dots of a whole instruction at once, when the CPU polls the interrupt lines
+    /// at its end. Cheaper, but register accesses see the PPU as it was when the
+    /// instruction started.
+    CatchUp,
+}
+
+#[derive(Clone)]
+pub struct CpuMemoryBus {
+    /// The value last driven on the data bus. Every access counts, including the dummy
+    /// reads instructions issue for timing, as those do go out on the real bus too.
+    last_exchanged_value: u8,
+    open_bus_mode: OpenBusMode,
+    rom_write_mode: RomWriteMode,
+    /// The last ignored write to ROM in [`RomWriteMode::Trap`], as `(address, data)`.
+    rom_write: Option<(u16, u8)>,
+    /// Writes to ROM warned about in [`RomWriteMode::Log`].
+    logged_rom_writes: u64,
+    ppu_timing: PpuTiming,
+    region: Region,
+    /// Dots owed to the PPU in [`PpuTiming::CatchUp`].
+    pending_ppu_dots: u32,
+    cart: Option<Cart>,
+    ram: Option<Ram>,
+    ppu: Option<Ppu>,
+    apu: Option<Apu>,
+    controllers: [Option<Controller>; 2],
+    cheats: Vec<Cheat>,
+    cycles: u64,
+    /// Whether /NMI was asserted at the end of any cycle since the CPU last polled, but
+    /// for the latest cycle: the CPU only sees it from the second-to-last cycle of an
+    /// instruction on, and an edge in the last one waits for the next instruction.
+    nmi_seen: bool,
+    /// /IRQ at the end of the cycle before the latest one.
+    irq_before_last_cycle: bool,
+    register_hook: RegisterHook,
+}
+
+/// Called with the address, the value and whether it was a write. A closure cannot be
+/// cloned, so clones of the bus start without one.
+#[derive(Default)]
+struct RegisterHook(Option<Box<RegisterHookFn>>);
+
+type RegisterHookFn = dyn FnMut(u16, u8, bool) + Send;
+
+impl Clone for RegisterHook {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+/// A Game Genie style patch: reads of `address` return `value`, if the byte actually
+/// there is `compare` when one is given.
+#[derive(Debug, Clone, Copy)]
+struct Cheat {
+    address: u16,
+    value: u8,
+    compare: Option<u8>,
+}
+
+/// Assembles a [`CpuMemoryBus`] from only some of the devices, so that one of them can
+/// be exercised through the CPU interface on its own. Addresses of missing devices read
+/// as open bus.
+#[derive(Default)]
+pub struct CpuMemoryBusBuilder {
+    cart: Option<Cart>,
+    ram: Option<Ram>,
+    ppu: Option<Ppu>,
+    apu: Option<Apu>,
+    controllers: [Option<Controller>; 2],
+}
+
+impl CpuMemoryBusBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with_cart(mut self, cart: Cart) -> Self {
+        self.cart = Some(cart);
+        self
+    }
+
+    #[must_use]
+    pub fn with_ram(mut self, ram: Ram) -> Self {
+        self.ram = Some(ram);
+        self
+    }
+
+    /// The PPU is plugged in as it is, so a fresh [`Ppu::new`] still goes through its
+    /// power-on warm-up: writes to PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR are dropped
+    /// for about 29658 CPU cycles. Call [`Ppu::skip_warm_up`] on it first to drive those
+    /// registers right away.
+    #[must_use]
+    pub fn with_ppu(mut self, ppu: Ppu) -> Self {
+        self.ppu = Some(ppu);
+        self
+    }
+
+    #[must_use]
+    pub fn with_apu(mut self, apu: Apu) -> Self {
+        self.apu = Some(apu);
+        self
+    }
+
+    /// Plug `controller` into `port`: 0 is read through $4016, 1 through $4017.
+    ///
+    /// # Panics
+    ///
+    /// If `port` is not 0 or 1.
+    #[must_use]
+    pub const fn with_controller(mut self, port: usize, controller: Controller) -> Self {
+        self.controllers[port] = Some(controller);
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> CpuMemoryBus {
+        CpuMemoryBus {
+            last_exchanged_value: 0,
+            open_bus_mode: OpenBusMode::default(),
+            rom_write_mode: RomWriteMode::default(),
+            rom_write: None,
+            logged_rom_writes: 0,
+            ppu_timing: PpuTiming::default(),
+            region: Region::Ntsc,
+            pending_ppu_dots: 0,
+            cart: self.cart,
+            ram: self.ram,
+            ppu: self.ppu,
+            apu: self.apu,
+            controllers: self.controllers,
+            cheats: Vec::new(),
+            cycles: 0,
+            nmi_seen: false,
+            irq_before_last_cycle: false,
+            register_hook: RegisterHook::default(),
+        }
+    }
+}
+
+impl CpuMemoryBus {
+    /// A console with `cart` inserted and every other device attached.
+    #[must_use]
+    pub fn new(cart: Cart) -> Self {
+        Self::builder()
+            .with_cart(cart)
+            .with_ram(Ram::new())
+            .with_ppu(Ppu::new())
+            .with_apu(Apu::new(Region::Ntsc))
+            .with_controller(0, Controller::new())
+            .with_controller(1, Controller::new())
+            .build()
+    }
+
+    #[must_use]
+    pub fn builder() -> CpuMemoryBusBuilder {
+        CpuMemoryBusBuilder::new()
+    }
+
+    #[must_use]
+    pub const fn cart(&self) -> Option<&Cart> {
+        self.cart.as_ref()
+    }
+
+    #[must_use]
+    pub const fn ram(&self) -> Option<&Ram> {
+        self.ram.as_ref()
+    }
+
+    #[must_use]
+    pub const fn ppu(&self) -> Option<&Ppu> {
+        self.ppu.as_ref()
+    }
+
+    #[must_use]
+    pub const fn apu(&self) -> Option<&Apu> {
+        self.apu.as_ref()
+    }
+
+    pub const fn apu_mut(&mut self) -> Option<&mut Apu> {
+        self.apu.as_mut()
+    }
+
+    /// The controller plugged into `port`, 0 or 1, if any.
+    pub fn controller_mut(&mut self, port: usize) -> Option<&mut Controller> {
+        self.controllers.get_mut(port)?.as_mut()
+    }
+
+    pub const fn set_region(&mut self, region: Region) {
+        self.region = region;
+        if let Some(ppu) = &mut self.ppu {
+            ppu.set_region(region);
+        }
+        if let Some(apu) = &mut self.apu {
+            apu.set_region(region);
+        }
+    }
+
+    /// Run the rest of the machine for the duration of one CPU cycle.
+    fn tick(&mut self) {
+        let (nmi, irq) = self.interrupt_lines();
+        self.nmi_seen |= nmi;
+        self.irq_before_last_cycle = irq;
+        self.cycles += 1;
+        if let Some(apu) = &mut self.apu {
+            apu.tick();
+        }
+        if let Some(ppu) = &mut self.ppu {
+            #[allow(clippy::cast_possible_truncation)] // Three or four dots.
+            let dots =
+                (self.region.ppu_dots(self.cycles) - self.region.ppu_dots(self.cycles - 1)) as u32;
+            match self.ppu_timing {
+                PpuTiming::PerCycle => {
+                    for _ in 0..dots {
+                        ppu.tick(self.cart.as_mut());
+                    }
+                }
+                PpuTiming::CatchUp => self.pending_ppu_dots += dots,
+            }
+        }
+    }
+
+    /// Run the PPU for the dots it is owed in [`PpuTiming::CatchUp`].
+    pub fn catch_up_ppu(&mut self) {
+        let Some(ppu) = &mut self.ppu else {
+            return;
+        };
+        let pending = std::mem::take(&mut self.pending_ppu_dots);
+        // Like `tick`, leave /NMI raised during the latest cycle for the next cycle to
+        // sample, so an edge on the last cycle of an instruction waits one more.
+        #[allow(clippy::cast_possible_truncation)] // Three or four dots.
+        let last_cycle_dots = if self.cycles == 0 {
+            0
+        } else {
+            (self.region.ppu_dots(self.cycles) - self.region.ppu_dots(self.cycles - 1)) as u32
+        };
+        for dot in 0..pending {
+            ppu.tick(self.cart.as_mut());
+            if dot + last_cycle_dots < pending {
+                self.nmi_seen |= ppu.nmi_line();
+            }
+        }
+    }
+
+    pub fn set_ppu_timing(&mut self, timing: PpuTiming) {
+        self.catch_up_ppu();
+        self.ppu_timing = timing;
+    }
+
+    #[must_use]
+    pub const fn ppu_timing(&self) -> PpuTiming {
+        self.ppu_timing
+    }
+
+    /// Whether /NMI and /IRQ are asserted right now. /IRQ is shared, so the APU or the
+    /// mapper can assert it.
+    #[must_use]
+    pub fn interrupt_lines(&self) -> (bool, bool) {
+        (
+            self.ppu.as_ref().is_some_and(Ppu::nmi_line),
+            self.apu.as_ref().is_some_and(Apu::irq_line)
+                || self.cart.as_ref().is_some_and(Cart::irq_pending),
+        )
+    }
+
+    /// The interrupt lines as the CPU polls them at the end of an instruction: /NMI if
+    /// it was asserted at the end of any cycle but the last since the previous poll, and
+    /// /IRQ at the end of the second-to-last cycle.
+    pub fn poll_interrupt_lines(&mut self) -> (bool, bool) {
+        self.catch_up_ppu();
+        let nmi = self.nmi_seen;
+        self.nmi_seen = false;
+        (nmi, self.irq_before_last_cycle)
+    }
+
+    /// Whether the CPU is on an odd cycle, counting from power-on. The DMA units take an
+    /// extra cycle to align their reads when they start on one.
+    #[must_use]
+    pub const fn odd_cycle(&self) -> bool {
+        self.cycles % 2 == 1
+    }
+
+    /// The value left on the data bus by the last access, which open bus reads return
+    /// in the default [`OpenBusMode::LastValue`] mode.
+    #[must_use]
+    pub const fn data_bus_latch(&self) -> u8 {
+        self.last_exchanged_value
+    }
+
+    /// Patch CPU reads of `address` to return `value`, only when the byte that is really
+    /// there equals `compare` if given, like a Game Genie code. Memory is left untouched.
+    pub fn add_cheat(&mut self, address: u16, value: u8, compare: Option<u8>) {
+        self.cheats.push(Cheat {
+            address,
+            value,
+            compare,
+        });
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    fn apply_cheats(&self, address: u16, data: u8) -> u8 {
+        self.cheats
+            .iter()
+            .find(|cheat| cheat.address == address && cheat.compare.is_none_or(|c| c == data))
+            .map_or(data, |cheat| cheat.value)
+    }
+
+    /// Call `hook` with the address, the value and whether it was a write on every CPU
+    /// access to the I/O registers at 0x2000-0x401F, replacing any previous hook. Side
+    /// effect free peeks do not count, and clones of the bus do not get the hook.
+    pub fn on_register_access(&mut self, hook: impl FnMut(u16, u8, bool) + Send + 'static) {
+        self.register_hook = RegisterHook(Some(Box::new(hook)));
+    }
+
+    pub fn clear_register_hook(&mut self) {
+        self.register_hook = RegisterHook(None);
+    }
+
+    /// Take over the state of `bus`, keeping the register hook, which clones lack.
+    fn restore_from(&mut self, bus: Self) {
+        let hook = std::mem::take(&mut self.register_hook);
+        *self = bus;
+        self.register_hook = hook;
+    }
+
+    fn register_accessed(&mut self, address: u16, data: u8, is_write: bool) {
+        if let (0x2000..=0x401F, Some(hook)) = (address, &mut self.register_hook.0) {
+            hook(address, data, is_write);
+        }
+    }
+
+    pub const fn set_open_bus_mode(&mut self, mode: OpenBusMode) {
+        self.open_bus_mode = mode;
+    }
+
+    #[must_use]
+    pub const fn open_bus_mode(&self) -> OpenBusMode {
+        self.open_bus_mode
+    }
+
+    pub const fn set_rom_write_mode(&mut self, mode: RomWriteMode) {
+        self.rom_write_mode = mode;
+    }
+
+    #[must_use]
+    pub const fn rom_write_mode(&self) -> RomWriteMode {
+        self.rom_write_mode
+    }
+
+    /// The last write to ROM the mapper ignored since the previous call, as `(address,
+    /// data)`, when in [`RomWriteMode::Trap`].
+    pub const fn take_rom_write(&mut self) -> Option<(u16, u8)> {
+        self.rom_write.take()
+    }
+
+    /// How many writes to ROM were warned about in [`RomWriteMode::Log`], for a
+    /// front-end to show without scraping stderr.
+    #[must_use]
+    pub const fn logged_rom_writes(&self) -> u64 {
+        self.logged_rom_writes
+    }
+
+    const fn open_bus_value(&self, address: u16) -> u8 {
+        match self.open_bus_mode {
+            OpenBusMode::LastValue => self.last_exchanged_value,
+            OpenBusMode::AddressHigh => (address >> 8) as u8,
+            OpenBusMode::Zero => 0,
+        }
+    }
+
+    /// Side-effect free read, or `None` when nothing is mapped at `address`.
+    fn peek_mapped(&mut self, address: u16) -> Option<u8> {
+        let open_bus = self.open_bus_value(address);
+        self.cart
+            .as_mut()
+            .and_then(|cart| cart.read(address))
+            .or_else(|| self.ram.as_mut()?.read(address))
+            .or_else(|| match address {
+                0x2000..=0x3FFF => self.ppu.as_ref()?.peek(address),
+                0x4000..=0x4013 | 0x4015 => self.apu.as_ref()?.peek(address),
+                0x4016 | 0x4017 => {
+                    let controller = self.controllers[usize::from(address - 0x4016)].as_ref()?;
+                    Some(open_bus & 0b1110_0000 | controller.peek())
+                }
+                _ => None,
+            })
+            .or_else(|| self.unconnected_port(address))
+    }
+
+    /// $4014 and the controller ports are always decoded, even with nothing behind them.
+    /// $4014 is write-only so reads see open bus; with no controller plugged in only the
+    /// upper bits of $4016 and $4017 come from open bus and the rest read as 0.
+    const fn unconnected_port(&self, address: u16) -> Option<u8> {
+        match address {
+            0x4014 => Some(self.open_bus_value(address)),
+            0x4016 | 0x4017 => Some(self.open_bus_value(address) & 0b1110_0000),
+            _ => None,
+        }
+    }
+
+    /// Fetch a sample byte for the DMC if it asked for one, taking `cycles` cycles, the
+    /// last of which is the fetch itself. On its own, the CPU is halted on a read cycle
+    /// and loses 4 cycles; during an OAM DMA the halt and alignment overlap with it.
+    fn dmc_dma(&mut self, cycles: u32) {
+        let Some(address) = self.apu.as_ref().and_then(Apu::dmc_dma_request) else {
+            return;
+        };
+        for _ in 0..cycles {
+            self.tick();
+        }
+        let data = self
+            .cart
+            .as_mut()
+            .and_then(|cart| cart.read(address))
+            .unwrap_or_else(|| self.open_bus_value(address));
+        self.last_exchanged_value = data;
+        if let Some(apu) = &mut self.apu {
+            apu.dmc_dma_complete(data);
+        }
+    }
+
+    /// Copy page `page` of CPU memory to OAM through $2004, returning the number of
+    /// cycles the CPU was stalled for.
+    ///
+    /// The CPU halts for one cycle, plus one more to align when the write to $4014 landed
+    /// on an odd cycle, then the DMA alternates reads and writes: 513 or 514 cycles.
+    ///
+    /// A DMC fetch wanted meanwhile takes a read cycle of the OAM DMA, which then needs
+    /// one more cycle to realign: 2 cycles rather than 4. One wanted during the last
+    /// write only has its halt cycle overlapped, and takes 3.
+    fn oam_dma(&mut self, page: u8) -> u64 {
+        let start = self.cycles;
+        let odd_cycle = self.odd_cycle();
+        self.tick();
+        if odd_cycle {
+            self.tick();
+        }
+        for low in 0..=0xFF {
+            self.dmc_dma(2);
+            let data = self.read_cycle(u16::from(page) << 8 | low);
+            // Without a PPU the writes still take their cycle, but land nowhere.
+            self.tick();
+            if let Some(ppu) = &mut self.ppu {
+                ppu.cpu_write(0x2004, data, self.cart.as_mut());
+            }
+        }
+        self.dmc_dma(3);
+        self.cycles - start
+    }
+
+    /// One read cycle, without giving the DMC the chance to halt the CPU first.
+    fn read_cycle(&mut self, address: u16) -> u8 {
+        self.tick();
+        let open_bus = self.open_bus_value(address);
+        let cart = self.cart.as_mut();
+        let data = cart
+            .and_then(|cart| cart.read(address))
+            .or_else(|| self.ram.as_mut()?.read(address))
+            .or_else(|| match address {
+                0x2000..=0x3FFF => self.ppu.as_mut()?.cpu_read(address, self.cart.as_mut()),
+                0x4000..=0x4013 | 0x4015 => self.apu.as_mut()?.cpu_read(address),
+                // Only bit 0 is driven by a standard controller. Bits 1-4 belong to the
+                // expansion port, empty here, and bits 5-7 are open bus: 0x40 after the
+                // usual `LDA $4016`, whose last fetch is the address high byte.
+                0x4016 | 0x4017 => {
+                    let controller = self.controllers[usize::from(address - 0x4016)].as_mut()?;
+                    Some(open_bus & 0b1110_0000 | controller.read())
+                }
+                _ => None,
+            })
+            .or_else(|| self.unconnected_port(address))
+            .unwrap_or_else(|| {
+                eprintln!("[WARNING] Reading byte from open bus at 0x{address:04x}");
+                self.open_bus_value(address)
+            });
+        let data = self.apply_cheats(address, data);
+        self.last_exchanged_value = data;
+        self.register_accessed(address, data, false);
+        data
+    }
+}
+
+impl Bus for CpuMemoryBus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.dmc_dma(4);
+        self.read_cycle(address)
+    }
+
+    fn peek(&mut self, address: u16) -> u8 {
+        let data = self
+            .peek_mapped(address)
+            .unwrap_or_else(|| self.open_bus_value(address));
+        self.apply_cheats(address, data)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.tick();
+        self.last_exchanged_value = data;
+        self.register_accessed(address, data, true);
+        let mut written = false;
+        if let Some(cart) = &mut self.cart {
+            written = cart.write(address, data) || written;
+        }
+        if let Some(ram) = &mut self.ram {
+            written = ram.write(address, data) || written;
+        }
+        if let (0x2000..=0x3FFF, Some(ppu)) = (address, &mut self.ppu) {
+            written = ppu.cpu_write(address, data, self.cart.as_mut()) || written;
+        }
+        if let (0x4000..=0x4013 | 0x4015 | 0x4017, Some(apu)) = (address, &mut self.apu) {
+            written = apu.cpu_write(address, data) || written;
+        }
+        match address {
+            0x4014 => {
+                self.oam_dma(data);
+                written = true;
+            }
+            0x4016 => {
+                for controller in self.controllers.iter_mut().flatten() {
+                    controller.write_strobe(data);
+                }
+                written = true;
+            }
+            // The frame counter, when there is no APU.
+            0x4017 => written = true,
+            _ => {}
+        }
+        if written {
+            return;
+        }
+        if address >= 0x8000 && self.cart.is_some() {
+            match self.rom_write_mode {
+                RomWriteMode::Ignore => {}
+                RomWriteMode::Log => {
+                    self.logged_rom_writes += 1;
+                    eprintln!("[WARNING] Ignored write to ROM at 0x{address:04x} = 0x{data:02x}");
+                }
+                RomWriteMode::Trap => self.rom_write = Some((address, data)),
+            }
+        } else {
+            eprintln!("[WARNING] Writing byte to open bus at 0x{address:04x} = 0x{data:02x}");
+        }
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn is_mapped(&mut self, address: u16) -> bool {
+        self.peek_mapped(address).is_some()
+    }
+
+    fn ppu_position(&self) -> Option<(u16, u16)> {
+        self.ppu.as_ref().map(|ppu| (ppu.scanline(), ppu.dot()))
+    }
+
+    fn nmi_asserted(&mut self) -> bool {
+        self.catch_up_ppu();
+        self.nmi_seen
+    }
+}
+
+/// The open bus mode, ROM write mode, PPU timing and cheats are settings, and are kept.
+impl Persist for CpuMemoryBus {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.last_exchanged_value);
+        w.u64(self.cycles);
+        w.u32(self.pending_ppu_dots);
+        w.bool(self.nmi_seen);
+        w.bool(self.irq_before_last_cycle);
+        self.cart.write_state(w);
+        self.ram.write_state(w);
+        self.ppu.write_state(w);
+        self.apu.write_state(w);
+        for controller in &self.controllers {
+            controller.write_state(w);
+        }
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.last_exchanged_value = r.u8()?;
+        self.cycles = r.u64()?;
+        self.pending_ppu_dots = r.u32()?;
+        self.nmi_seen = r.bool()?;
+        self.irq_before_last_cycle = r.bool()?;
+        self.cart.read_state(r)?;
+        self.ram.read_state(r)?;
+        self.ppu.read_state(r)?;
+        self.apu.read_state(r)?;
+        for controller in &mut self.controllers {
+            controller.read_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct CpuStatusFlags: u8 {
+        const CARRY = 0b0000_0001;
+        const ZERO = 0b0000_0010;
+        const INTERRUPT_DISABLE = 0b0000_0100;
+        /// A real flag on the 2A03 too: SED, CLD and the stack keep it like any other,
+        /// only ADC and SBC ignore it unless `Cpu::set_decimal_mode` is on.
+        const DECIMAL = 0b0000_1000;
+        const B_FLAG = 0b0001_0000;
+        const IGNORED = 0b0010_0000;
+        const OVERFLOW = 0b0100_0000;
+        const NEGATIVE = 0b1000_0000;
+    }
+}
+
+/// What a call to `Cpu::run_instr` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepResult {
+    pub opcode: u8,
+    /// CPU cycles taken, including the interrupt sequence if one was serviced afterwards.
+    pub cycles: u64,
+}
+
+/// Every way running an instruction can fail, for `Cpu::run_instr` and `Nes` alike.
+///
+/// None of them leave the CPU half way through an instruction, so a front-end can show
+/// the error and keep going, e.g. after fixing PC up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// The opcode at `pc` is not one the CPU can execute.
+    UnknownOpcode { pc: u16, opcode: u8 },
+    /// PC points at open bus, so the fetched opcode is whatever was last on the bus. It
+    /// is not run, whatever it is.
+    RanOffMap { pc: u16 },
+    /// The opcode at `pc` is unofficial and those are disabled.
+    IllegalOpcode { pc: u16, opcode: u8 },
+    /// A JAM (also called KIL) opcode at `pc` locked the CPU up. PC stays on it, so
+    /// stepping again jams again; only a reset gets the real CPU out of it.
+    Jammed { pc: u16 },
+    /// The instruction at `pc` wrote `data` to ROM at `address` and the mapper ignored
+    /// it, in [`RomWriteMode::Trap`]. Unlike the errors above, the instruction has run.
+    RomWrite { pc: u16, address: u16, data: u8 },
+    /// The instruction at `pc`, or the interrupt serviced after it, pushed with SP at
+    /// 0x00 while the stack check is on. It has run, and SP has wrapped to 0xFF.
+    StackOverflow { pc: u16 },
+    /// The instruction at `pc` pulled with SP at 0xFF while the stack check is on. It
+    /// has run, and SP has wrapped to 0x00.
+    StackUnderflow { pc: u16 },
+}
+
+impl CpuError {
+    /// Address of the instruction that failed. PC is left there, but after the errors
+    /// of instructions that have run.
+    #[must_use]
+    pub const fn pc(&self) -> u16 {
+        match *self {
+            Self::UnknownOpcode { pc, .. }
+            | Self::RanOffMap { pc }
+            | Self::IllegalOpcode { pc, .. }
+            | Self::Jammed { pc }
+            | Self::RomWrite { pc, .. }
+            | Self::StackOverflow { pc }
+            | Self::StackUnderflow { pc } => pc,
+        }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownOpcode { pc, opcode } => {
+                write!(f, "Unknown opcode 0x{opcode:02x} at 0x{pc:04x}")
+            }
+            Self::RanOffMap { pc } => write!(f, "PC ran into unmapped memory at 0x{pc:04x}"),
+            Self::IllegalOpcode { pc, opcode } => {
+                write!(
+                    f,
+                    "Unofficial opcode 0x{opcode:02x} at 0x{pc:04x} is disabled"
+                )
+            }
+            Self::Jammed { pc } => write!(f, "CPU jammed at 0x{pc:04x}"),
+            Self::RomWrite { pc, address, data } => write!(
+                f,
+                "Instruction at 0x{pc:04x} wrote 0x{data:02x} to ROM at 0x{address:04x}"
+            ),
+            Self::StackOverflow { pc } => {
+                write!(
+                    f,
+                    "Stack overflow: SP wrapped from 0x00 to 0xff at 0x{pc:04x}"
+                )
+            }
+            Self::StackUnderflow { pc } => {
+                write!(
+                    f,
+                    "Stack underflow: SP wrapped from 0xff to 0x00 at 0x{pc:04x}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+/// Something suspicious about the state the CPU came out of reset in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetWarning {
+    /// The reset vector points at open bus, usually a broken ROM or an unsupported
+    /// mapper setup, so the CPU is about to run garbage.
+    UnmappedVector { target: u16 },
+}
+
+impl fmt::Display for ResetWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnmappedVector { target } => {
+                write!(
+                    f,
+                    "Reset vector points to unmapped memory at 0x{target:04x}"
+                )
+            }
+        }
+    }
+}
+
+/// Why `Cpu::run_until` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The predicate held, after this many instructions.
+    Stopped { instructions: usize },
+    /// The instruction limit was hit first.
+    LimitReached,
+}
+
+/// The interrupt and reset vectors at the top of the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vectors {
+    pub nmi: u16,
+    pub reset: u16,
+    pub irq: u16,
+}
+
+/// The registers and interrupt state of a [`Cpu`], without its configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub a_reg: u8,
+    pub x_reg: u8,
+    pub y_reg: u8,
+    pub prog_counter: u16,
+    pub stack_pointer: u8,
+    pub status: u8,
+    pub nmi_line: bool,
+    pub nmi_pending: bool,
+    pub irq_line: bool,
+}
+
+impl Persist for CpuState {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.a_reg);
+        w.u8(self.x_reg);
+        w.u8(self.y_reg);
+        w.u16(self.prog_counter);
+        w.u8(self.stack_pointer);
+        w.u8(self.status);
+        w.bool(self.nmi_line);
+        w.bool(self.nmi_pending);
+        w.bool(self.irq_line);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.a_reg = r.u8()?;
+        self.x_reg = r.u8()?;
+        self.y_reg = r.u8()?;
+        self.prog_counter = r.u16()?;
+        self.stack_pointer = r.u8()?;
+        self.status = r.u8()?;
+        self.nmi_line = r.bool()?;
+        self.nmi_pending = r.bool()?;
+        self.irq_line = r.bool()?;
+        Ok(())
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug)]
+pub struct Cpu {
+    a_reg: u8,
+    x_reg: u8,
+    y_reg: u8,
+    prog_counter: u16,
+    stack_pointer: u8,
+    status_flags: CpuStatusFlags,
+    nmi_line: bool,
+    nmi_pending: bool,
+    irq_line: bool,
+    /// Whether ADC and SBC honour the D flag. The 2A03 lacks the BCD logic of the 6502.
+    decimal_mode: bool,
+    /// Whether unofficial opcodes run, rather than failing with `CpuError::IllegalOpcode`.
+    allow_illegal_opcodes: bool,
+    /// Whether to fail when SP wraps around during a push or pull.
+    stack_check: bool,
+    /// Set by the stack check when SP wraps during the current instruction: true for a
+    /// push, false for a pull.
+    stack_wrap: Option<bool>,
+    tracer: Box<dyn Tracer + Send>,
+}
+
+impl Cpu {
+    /// A CPU in its power-on state. It starts running once `reset` has loaded PC from
+    /// the reset vector of a bus.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            a_reg: 0,
+            x_reg: 0,
+            y_reg: 0,
+            prog_counter: 0,
+            stack_pointer: 0xFF,
+            status_flags: CpuStatusFlags::from_bits_retain(0x34),
+            nmi_line: false,
+            nmi_pending: false,
+            irq_line: false,
+            decimal_mode: false,
+            allow_illegal_opcodes: true,
+            stack_check: false,
+            stack_wrap: None,
+            tracer: Box::new(StderrTracer::new()),
+        }
+    }
+
+    /// Send the trace to `writer` instead of stderr.
+    #[must_use]
+    pub fn with_trace_writer(self, writer: impl std::io::Write + Send + 'static) -> Self {
+        self.with_tracer(StderrTracer::with_writer(writer))
+    }
+
+    /// Enable BCD arithmetic to behave as a stock NMOS 6502 rather than the NES CPU, e.g.
+    /// for running generic 6502 test suites.
+    pub const fn set_decimal_mode(&mut self, enabled: bool) {
+        self.decimal_mode = enabled;
+    }
+
+    /// Allowed by default, as some games rely on them. Disabling them is useful to catch
+    /// code that ran off into data.
+    pub const fn set_allow_illegal_opcodes(&mut self, allowed: bool) {
+        self.allow_illegal_opcodes = allowed;
+    }
+
+    /// Fail with [`CpuError::StackOverflow`] when a push wraps SP from 0x00 to 0xFF, or
+    /// [`CpuError::StackUnderflow`] when a pull wraps it from 0xFF to 0x00, which usually
+    /// means runaway recursion or unbalanced pulls. Off by default, as the hardware wraps
+    /// silently and some programs do it on purpose.
+    pub const fn set_stack_check(&mut self, enabled: bool) {
+        self.stack_check = enabled;
+    }
+
+    #[must_use]
+    pub fn with_tracer(mut self, tracer: impl Tracer + Send + 'static) -> Self {
+        self.set_tracer(tracer);
+        self
+    }
+
+    /// Replace the tracer of a CPU that is already running, e.g. the one inside a `Nes`.
+    pub fn set_tracer(&mut self, tracer: impl Tracer + Send + 'static) {
+        self.tracer = Box::new(tracer);
+    }
+
+    #[must_use]
+    pub const fn state(&self) -> CpuState {
+        CpuState {
+            a_reg: self.a_reg,
+            x_reg: self.x_reg,
+            y_reg: self.y_reg,
+            prog_counter: self.prog_counter,
+            stack_pointer: self.stack_pointer,
+            status: self.status_flags.bits(),
+            nmi_line: self.nmi_line,
+            nmi_pending: self.nmi_pending,
+            irq_line: self.irq_line,
+        }
+    }
+
+    pub const fn set_state(&mut self, state: &CpuState) {
+        self.a_reg = state.a_reg;
+        self.x_reg = state.x_reg;
+        self.y_reg = state.y_reg;
+        self.prog_counter = state.prog_counter;
+        self.stack_pointer = state.stack_pointer;
+        self.status_flags = CpuStatusFlags::from_bits_retain(state.status);
+        self.nmi_line = state.nmi_line;
+        self.nmi_pending = state.nmi_pending;
+        self.irq_line = state.irq_line;
+    }
+
+    #[must_use]
+    pub const fn prog_counter(&self) -> u16 {
+        self.prog_counter
+    }
+
+    /// The NMI, reset and IRQ/BRK vectors as they currently read, for debuggers. The bus
+    /// is only peeked at.
+    pub fn vectors<B: Bus>(&self, bus: &mut B) -> Vectors {
+        Vectors {
+            nmi: bus.peek_u16(0xFFFA),
+            reset: bus.peek_u16(0xFFFC),
+            irq: bus.peek_u16(0xFFFE),
+        }
+    }
+
+    /// Disassemble the instruction at PC, as `disasm::disassemble` does, leaving the CPU
+    /// and the bus untouched.
+    pub fn current_instruction<B: Bus>(&self, bus: &mut B) -> (String, u8) {
+        disasm::disassemble(bus, self.prog_counter)
+    }
+
+    pub const fn set_prog_counter(&mut self, address: u16) {
+        self.prog_counter = address;
+    }
+
+    /// Drive the /NMI input, true meaning asserted. NMI is edge triggered: only the
+    /// transition to asserted latches an interrupt.
+    pub const fn set_nmi_line(&mut self, asserted: bool) {
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = asserted;
+    }
+
+    /// Drive the /IRQ input, true meaning asserted. IRQ is level triggered.
+    pub const fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    #[must_use]
+    pub const fn nmi_pending(&self) -> bool {
+        self.nmi_pending
+    }
+
+    #[must_use]
+    pub const fn irq_pending(&self) -> bool {
+        self.irq_line
+            && !self
+                .status_flags
+                .contains(CpuStatusFlags::INTERRUPT_DISABLE)
+    }
+
+    /// Run the 7 cycle reset sequence: it is an interrupt whose three pushes are turned
+    /// into reads, so the stack pointer still moves down by 3 but memory is untouched.
+    ///
+    /// The CPU is reset either way, but a warning is returned for the caller to report
+    /// when the reset vector leads nowhere.
+    pub fn reset<B: Bus>(&mut self, bus: &mut B) -> Option<ResetWarning> {
+        bus.read(self.prog_counter);
+        bus.read(self.prog_counter);
+        for _ in 0..3 {
+            bus.read(u16::from(self.stack_pointer) | 0x0100);
+            self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        }
+        self.status_flags |= CpuStatusFlags::INTERRUPT_DISABLE;
+        let reset_vector = bus.read_u16(0xfffc);
+        self.prog_counter = reset_vector;
+        if bus.is_mapped(reset_vector) {
+            return None;
+        }
+        Some(ResetWarning::UnmappedVector {
+            target: reset_vector,
+        })
+    }
+
+    /// Run one instruction, then service a pending interrupt if there is one.
+    ///
+    /// # Errors
+    ///
+    /// Fails without running anything but the opcode fetch when the opcode is not
+    /// implemented or is a JAM, or when PC points at unmapped memory. PC is left on the
+    /// opcode. With the stack check on, also fails once an instruction that wrapped SP
+    /// has run, see `set_stack_check`.
+    pub fn run_instr<B: Bus>(&mut self, bus: &mut B) -> Result<StepResult, CpuError> {
+        self.run_instr_and_poll(bus, |_, _| {})
+    }
+
+    /// Run instructions until `predicate` holds, checking it before each one, or until
+    /// `max_instrs` have run.
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first instruction the CPU cannot run, see `run_instr`.
+    pub fn run_until<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        predicate: impl Fn(&Self) -> bool,
+        max_instrs: usize,
+    ) -> Result<RunOutcome, CpuError> {
+        for instructions in 0..max_instrs {
+            if predicate(self) {
+                return Ok(RunOutcome::Stopped { instructions });
+            }
+            self.run_instr(bus)?;
+        }
+        if predicate(self) {
+            return Ok(RunOutcome::Stopped {
+                instructions: max_instrs,
+            });
+        }
+        Ok(RunOutcome::LimitReached)
+    }
+
+    /// Like `run_instr`, but the interrupt lines are sampled through `lines`, which
+    /// returns whether NMI and IRQ are asserted, once the instruction is done. An
+    /// interrupt raised by the devices while it ran is then serviced right away instead
+    /// of after the next instruction.
+    ///
+    /// The real CPU polls during the second-to-last cycle of the instruction, so `lines`
+    /// should report the lines as they were then, like
+    /// [`CpuMemoryBus::poll_interrupt_lines`] does.
+    ///
+    /// # Errors
+    ///
+    /// See `run_instr`.
+    pub fn step_with_interrupts<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        lines: impl FnOnce(&mut B) -> (bool, bool),
+    ) -> Result<StepResult, CpuError> {
+        self.run_instr_and_poll(bus, |cpu, bus| {
+            let (nmi, irq) = lines(bus);
+            cpu.set_nmi_line(nmi);
+            cpu.set_irq_line(irq);
+        })
+    }
+
+    fn run_instr_and_poll<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        sample_lines: impl FnOnce(&mut Self, &mut B),
+    ) -> Result<StepResult, CpuError> {
+        let start = bus.cycles();
+        let pc = self.prog_counter;
+        let interrupt_disable = self
+            .status_flags
+            .contains(CpuStatusFlags::INTERRUPT_DISABLE);
+        let opcode = self.execute_instr(bus)?;
+        sample_lines(self, bus);
+        // CLI, SEI and PLP change I after the IRQ poll of their last cycle, so that poll
+        // still sees the old value. RTI is not affected.
+        let irq_masked = if matches!(opcode, 0x58 | 0x78 | 0x28) {
+            interrupt_disable
+        } else {
+            self.status_flags
+                .contains(CpuStatusFlags::INTERRUPT_DISABLE)
+        };
+        self.poll_interrupts(bus, irq_masked);
+        match self.stack_wrap.take() {
+            Some(true) => return Err(CpuError::StackOverflow { pc }),
+            Some(false) => return Err(CpuError::StackUnderflow { pc }),
+            None => {}
+        }
+        Ok(StepResult {
+            opcode,
+            cycles: bus.cycles() - start,
+        })
+    }
+
+    fn poll_interrupts<B: Bus>(&mut self, bus: &mut B, irq_masked: bool) {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            bus.read(self.prog_counter);
+            bus.read(self.prog_counter);
+            self.interrupt(bus, 0xFFFA, false);
+            trace!(self, "NMI => 0x{:04x}", self.prog_counter);
+        } else if self.irq_line && !irq_masked {
+            bus.read(self.prog_counter);
+            bus.read(self.prog_counter);
+            self.interrupt(bus, 0xFFFE, false);
+            trace!(self, "IRQ => 0x{:04x}", self.prog_counter);
+        }
+    }
+
+    /// The part of the interrupt sequence shared by BRK, IRQ and NMI: push the return
+    /// address and status, then jump through `vector`.
+    ///
+    /// The vector is only chosen once the status is pushed, and /NMI is sampled again
+    /// then, so an NMI raised by that point hijacks a BRK or IRQ: the NMI vector is
+    /// used, and the pushed B flag still says BRK. That NMI is then considered serviced.
+    fn interrupt<B: Bus>(&mut self, bus: &mut B, vector: u16, brk: bool) {
+        self.push_stack(bus, (self.prog_counter >> 8) as u8);
+        self.push_stack(bus, (self.prog_counter & 0xFF) as u8);
+        let status = self.status_for_push(brk);
+        self.push_stack(bus, status);
+        self.status_flags.insert(CpuStatusFlags::INTERRUPT_DISABLE);
+        if bus.nmi_asserted() {
+            self.set_nmi_line(true);
+        }
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            0xFFFA
+        } else {
+            vector
+        };
+        self.prog_counter = bus.read_u16(vector);
+    }
+
+    fn execute_instr<B: Bus>(&mut self, bus: &mut B) -> Result<u8, CpuError> {
+        let pc = self.prog_counter;
+        let context = self.tracer.wants_context().then(|| TraceContext {
+            pc,
+            bytes: [0, 1, 2].map(|offset| bus.peek(pc.wrapping_add(offset))),
+            a: self.a_reg,
+            x: self.x_reg,
+            y: self.y_reg,
+            p: self.status_flags.bits(),
+            sp: self.stack_pointer,
+            cycles: bus.cycles(),
+            ppu: bus.ppu_position(),
+        });
+        let opcode = self.read_instr_byte(bus);
+        self.tracer.start_instruction(pc, opcode);
+        if let Some(context) = &context {
+            self.tracer.instruction(context);
+        }
+        if !bus.is_mapped(pc) {
+            self.prog_counter = pc;
+            return Err(CpuError::RanOffMap { pc });
+        }
+        if !self.allow_illegal_opcodes && !OPCODES[usize::from(opcode)].official {
+            self.prog_counter = pc;
+            return Err(CpuError::IllegalOpcode { pc, opcode });
+        }
+        if OPCODES[usize::from(opcode)].mnemonic == "JAM" {
+            self.prog_counter = pc;
+            trace!(self, "JAM (Implied)");
+            return Err(CpuError::Jammed { pc });
+        }
+        if !self.execute_opcode(bus, opcode) {
+            self.prog_counter = pc;
+            return Err(CpuError::UnknownOpcode { pc, opcode });
+        }
+        Ok(opcode)
+    }
+
+    /// Run the rest of the instruction once `opcode` has been fetched. Returns false,
+    /// before touching anything, when the opcode is not implemented.
+    #[allow(clippy::too_many_lines)]
+    fn execute_opcode<B: Bus>(&mut self, bus: &mut B, opcode: u8) -> bool {
+        match opcode {
+            0x00 => {
+                self.read_instr_byte(bus);
+                self.interrupt(bus, 0xFFFE, true);
+                trace!(self, "BRK (Implied) => 0x{:04x}", self.prog_counter);
+            }
+            0x40 => {
+                bus.read(self.prog_counter);
+                let status = self.pull_stack(bus);
+                self.status_from_pull(status);
+                self.increment_stack_pointer();
+                let low = bus.read(u16::from(self.stack_pointer) | 0x0100);
+                self.increment_stack_pointer();
+                let high = bus.read(u16::from(self.stack_pointer) | 0x0100);
+                self.prog_counter = u16::from(high) << 8 | u16::from(low);
+                trace!(self, "RTI (Implied) => 0x{:04x}", self.prog_counter);
+            }
+            0x08 => {
+                bus.read(self.prog_counter);
+                let status = self.status_for_push(true);
+                self.push_stack(bus, status);
+                trace!(self, "PHP (Implied) => 0b{status:08b}");
+            }
+            0x8E => {
+                let address = self.read_instr_u16(bus);
+                bus.write(address, self.x_reg);
+                trace!(
+                    self,
+                    "STX (Absolute) => 0x{address:04x} = 0x{:02x}",
+                    self.x_reg
+                );
+            }
+            0x8C => {
+                let address = self.read_instr_u16(bus);
+                bus.write(address, self.y_reg);
+                trace!(
+                    self,
+                    "STY (Absolute) => 0x{address:04x} = 0x{:02x}",
+                    self.y_reg
+                );
+            }
+            0x8D => {
+                let address = self.read_instr_u16(bus);
+                bus.write(address, self.a_reg);
+                trace!(
+                    self,
+                    "STA (Absolute) => 0x{address:04x} = 0x{:02x}",
+                    self.a_reg
+                );
+            }
+            0x68 => {
+                bus.read(self.prog_counter);
+                self.a_reg = self.pull_stack(bus);
+                self.status_flags.set(CpuStatusFlags::ZERO, self.a_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.a_reg & 0b1000_0000 != 0);
+                trace!(self, "PLA (Implied) => 0x{:02x}", self.a_reg);
+            }
+            0xBA => {
+                bus.read(self.prog_counter);
+                self.x_reg = self.stack_pointer;
+                self.status_flags.set(CpuStatusFlags::ZERO, self.x_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.x_reg & 0b1000_0000 != 0);
+                trace!(self, "TSX (Implied) => 0x{:02x}", self.x_reg);
+            }
+            0x4C => {
+                let address = self.read_instr_u16(bus);
+                self.prog_counter = address;
+                trace!(self, "JMP (Absolute) => 0x{address:04x}");
+            }
+            0x78 => {
+                bus.read(self.prog_counter);
+                self.status_flags
+                    .set(CpuStatusFlags::INTERRUPT_DISABLE, true);
+                trace!(self, "SEI (Implied)");
+            }
+            0x58 => {
+                bus.read(self.prog_counter);
+                self.status_flags
+                    .set(CpuStatusFlags::INTERRUPT_DISABLE, false);
+                trace!(self, "CLI (Implied)");
+            }
+            0xD8 => {
+                bus.read(self.prog_counter);
+                self.status_flags.set(CpuStatusFlags::DECIMAL, false);
+                trace!(self, "CLD (Implied)");
+            }
+            0x9A => {
+                bus.read(self.prog_counter);
+                self.stack_pointer = self.x_reg;
+                trace!(self, "TXS (Implied)");
+            }
+            0x20 => {
+                let low_addr = self.read_instr_byte(bus);
+                bus.read(u16::from(self.stack_pointer) | 0x0100);
+                self.push_stack(bus, (self.prog_counter >> 8) as u8);
+                self.push_stack(bus, (self.prog_counter & 0xFF) as u8);
+                let address = u16::from(low_addr) | u16::from(self.read_instr_byte(bus)) << 8;
+                self.prog_counter = address;
+                trace!(self, "JSR (Absolute) => 0x{address:04x}");
+            }
+            0x84 => {
+                let address = u16::from(self.read_instr_byte(bus));
+                bus.write(address, self.y_reg);
+                trace!(
+                    self,
+                    "STY (Zero Page) => 0x{address:02x} = 0x{:02x}",
+                    self.y_reg
+                );
+            }
+            0x86 => {
+                let address = u16::from(self.read_instr_byte(bus));
+                bus.write(address, self.x_reg);
+                trace!(
+                    self,
+                    "STX (Zero Page) => 0x{address:02x} = 0x{:02x}",
+                    self.x_reg
+                );
+            }
+            0x81 | 0x91 | 0x99 | 0x9D => {
+                let mode = OPCODES[usize::from(opcode)].mode;
+                let address = self.write_operand_address(bus, mode);
+                bus.write(address, self.a_reg);
+                trace!(
+                    self,
+                    "STA ({mode}) => 0x{address:04x} = 0x{:02x}",
+                    self.a_reg
+                );
+            }
+            0xC8 => {
+                bus.read(self.prog_counter);
+                self.y_reg = self.y_reg.wrapping_add(1);
+                self.status_flags.set(CpuStatusFlags::ZERO, self.y_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.y_reg & 0b1000_0000 != 0);
+                trace!(self, "INY (Implied) => 0x{:02x}", self.y_reg);
+            }
+            0xE8 => {
+                bus.read(self.prog_counter);
+                self.x_reg = self.x_reg.wrapping_add(1);
+                self.status_flags.set(CpuStatusFlags::ZERO, self.x_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.x_reg & 0b1000_0000 != 0);
+                trace!(self, "INX (Implied) => 0x{:02x}", self.x_reg);
+            }
+            0xAA => {
+                bus.read(self.prog_counter);
+                self.x_reg = self.a_reg;
+                self.status_flags.set(CpuStatusFlags::ZERO, self.x_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.x_reg & 0b1000_0000 != 0);
+                trace!(self, "TAX (Implied) => 0x{:02x}", self.x_reg);
+            }
+            0x95 => {
+                let address = self.read_instr_byte(bus);
+                bus.read(u16::from(address));
+                bus.write(u16::from(address.wrapping_add(self.x_reg)), self.a_reg);
+                trace!(
+                    self,
+                    "STA (Zero Page,X) => 0x{address:02x} -> 0x{:02x} = 0x{:02x}",
+                    address.wrapping_add(self.x_reg),
+                    self.a_reg
+                );
+            }
+            0xCA => {
+                bus.read(self.prog_counter);
+                self.x_reg = self.x_reg.wrapping_sub(1);
+                self.status_flags.set(CpuStatusFlags::ZERO, self.x_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.x_reg & 0b1000_0000 != 0);
+                trace!(self, "DEX (Implied) => 0x{:02x}", self.x_reg);
+            }
+            0x60 => {
+                bus.read(self.prog_counter);
+                let address = self.pull_stack_address(bus);
+                self.prog_counter = address;
+                self.read_instr_byte(bus);
+                trace!(self, "RTS (Implied) => 0x{address:04x}");
+            }
+            0x2c => {
+                let address = self.read_instr_u16(bus);
+                let data = bus.read(address);
+                self.status_flags
+                    .set(CpuStatusFlags::ZERO, data & self.a_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, data & 0b1000_0000 != 0);
+                self.status_flags
+                    .set(CpuStatusFlags::OVERFLOW, data & 0b0100_0000 != 0);
+                trace!(
+                    self,
+                    "BIT (Absolute) => 0x{address:04x} -> 0x{data:02x} & 0x{:02x}",
+                    self.a_reg
+                );
+            }
+            0x88 => {
+                bus.read(self.prog_counter);
+                self.y_reg = self.y_reg.wrapping_sub(1);
+                self.status_flags.set(CpuStatusFlags::ZERO, self.y_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.y_reg & 0b1000_0000 != 0);
+                trace!(self, "DEY (Implied) => 0x{:02x}", self.y_reg);
+            }
+            0x98 => {
+                bus.read(self.prog_counter);
+                self.a_reg = self.y_reg;
+                self.status_flags.set(CpuStatusFlags::ZERO, self.a_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.a_reg & 0b1000_0000 != 0);
+                trace!(self, "TYA (Implied) => 0x{:02x}", self.a_reg);
+            }
+            0x85 => {
+                let address = u16::from(self.read_instr_byte(bus));
+                bus.write(address, self.a_reg);
+                trace!(
+                    self,
+                    "STA (Zero Page) => 0x{address:02x} = 0x{:02x}",
+                    self.a_reg
+                );
+            }
+            0x48 => {
+                bus.read(self.prog_counter);
+                self.push_stack(bus, self.a_reg);
+                trace!(self, "PHA (Implied) => 0x{:02x}", self.a_reg);
+            }
+            0xA8 => {
+                bus.read(self.prog_counter);
+                self.y_reg = self.a_reg;
+                self.status_flags.set(CpuStatusFlags::ZERO, self.y_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, self.y_reg & 0b1000_0000 != 0);
+                trace!(self, "TAY (Implied) => 0x{:02x}", self.y_reg);
+            }
+            0x28 => {
+                bus.read(self.prog_counter);
+                let status = self.pull_stack(bus);
+                self.status_from_pull(status);
+                trace!(self, "PLP (Implied) => 0b{:08b}", self.status_flags.bits());
+            }
+            0x24 => {
+                let address = self.read_instr_byte(bus);
+                let data = bus.read(u16::from(address));
+                self.status_flags
+                    .set(CpuStatusFlags::ZERO, data & self.a_reg == 0);
+                self.status_flags
+                    .set(CpuStatusFlags::NEGATIVE, data & 0b1000_0000 != 0);
+                self.status_flags
+                    .set(CpuStatusFlags::OVERFLOW, data & 0b0100_0000 != 0);
+                trace!(
+                    self,
+                    "BIT (Zero Page) => 0x{address:02x} -> 0x{data:02x} & 0x{:02x}",
+                    self.a_reg
+                );
+            }
+            0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xB0 | 0xD0 | 0xF0 => {
+                // Bits 6-7 pick the flag, bit 5 the value it must have.
+                let flag = match opcode >> 6 {
+                    0 => CpuStatusFlags::NEGATIVE,
+                    1 => CpuStatusFlags::OVERFLOW,
+                    2 => CpuStatusFlags::CARRY,
+                    _ => CpuStatusFlags::ZERO,
+                };
+                let taken = self.status_flags.contains(flag) == (opcode & 0x20 != 0);
+                self.branch(bus, opcode, taken);
+            }
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => {
+                self.a_reg = self.read_operand(bus, opcode);
+                self.set_zero_negative(self.a_reg);
+            }
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => {
+                self.x_reg = self.read_operand(bus, opcode);
+                self.set_zero_negative(self.x_reg);
+            }
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => {
+                self.y_reg = self.read_operand(bus, opcode);
+                self.set_zero_negative(self.y_reg);
+            }
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => {
+                self.a_reg |= self.read_operand(bus, opcode);
+                self.set_zero_negative(self.a_reg);
+            }
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => {
+                self.a_reg &= self.read_operand(bus, opcode);
+                self.set_zero_negative(self.a_reg);
+            }
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => {
+                self.a_reg ^= self.read_operand(bus, opcode);
+                self.set_zero_negative(self.a_reg);
+            }
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => {
+                let data = self.read_operand(bus, opcode);
+                self.compare(self.a_reg, data);
+            }
+            0xE0 | 0xE4 | 0xEC => {
+                let data = self.read_operand(bus, opcode);
+                self.compare(self.x_reg, data);
+            }
+            0xC0 | 0xC4 | 0xCC => {
+                let data = self.read_operand(bus, opcode);
+                self.compare(self.y_reg, data);
+            }
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => {
+                let data = self.read_operand(bus, opcode);
+                self.add_with_carry(data);
+            }
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 | 0xEB => {
+                let data = self.read_operand(bus, opcode);
+                self.subtract_with_borrow(data);
+            }
+            0x0A | 0x06 | 0x16 | 0x0E | 0x1E => {
+                self.read_modify_write(bus, opcode, Self::shift_left);
+            }
+            0x4A | 0x46 | 0x56 | 0x4E | 0x5E => {
+                self.read_modify_write(bus, opcode, Self::shift_right);
+            }
+            0x2A | 0x26 | 0x36 | 0x2E | 0x3E => {
+                self.read_modify_write(bus, opcode, Self::rotate_left);
+            }
+            0x6A | 0x66 | 0x76 | 0x6E | 0x7E => {
+                self.read_modify_write(bus, opcode, Self::rotate_right);
+            }
+            0xE6 | 0xF6 | 0xEE | 0xFE => {
+                self.read_modify_write(bus, opcode, |_, data| data.wrapping_add(1));
+            }
+            0xC6 | 0xD6 | 0xCE | 0xDE => {
+                self.read_modify_write(bus, opcode, |_, data| data.wrapping_sub(1));
+            }
+            // Unofficial read-modify-writes that feed the result into a second operation.
+            0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => {
+                self.a_reg |= self.read_modify_write(bus, opcode, Self::shift_left);
+                self.set_zero_negative(self.a_reg);
+            }
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => {
+                self.a_reg &= self.read_modify_write(bus, opcode, Self::rotate_left);
+                self.set_zero_negative(self.a_reg);
+            }
+            0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => {
+                self.a_reg ^= self.read_modify_write(bus, opcode, Self::shift_right);
+                self.set_zero_negative(self.a_reg);
+            }
+            0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => {
+                let data = self.read_modify_write(bus, opcode, Self::rotate_right);
+                self.add_with_carry(data);
+            }
+            0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => {
+                let data = self.read_modify_write(bus, opcode, |_, data| data.wrapping_sub(1));
+                self.compare(self.a_reg, data);
+            }
+            0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => {
+                let data = self.read_modify_write(bus, opcode, |_, data| data.wrapping_add(1));
+                self.subtract_with_borrow(data);
+            }
+            0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => {
+                self.a_reg = self.read_operand(bus, opcode);
+                self.x_reg = self.a_reg;
+                self.set_zero_negative(self.a_reg);
+            }
+            0x87 | 0x97 | 0x8F | 0x83 => {
+                let mode = OPCODES[usize::from(opcode)].mode;
+                let address = self.write_operand_address(bus, mode);
+                bus.write(address, self.a_reg & self.x_reg);
+                trace!(
+                    self,
+                    "SAX ({mode}) => 0x{address:04x} = 0x{:02x}",
+                    self.a_reg & self.x_reg
+                );
+            }
+            0xBB => {
+                let data = self.read_operand(bus, opcode) & self.stack_pointer;
+                self.a_reg = data;
+                self.x_reg = data;
+                self.stack_pointer = data;
+                self.set_zero_negative(data);
+            }
+            0x0B | 0x2B => {
+                self.a_reg &= self.read_operand(bus, opcode);
+                self.set_zero_negative(self.a_reg);
+                self.status_flags
+                    .set(CpuStatusFlags::CARRY, self.a_reg & 0x80 != 0);
+            }
+            0x4B => {
+                let data = self.a_reg & self.read_operand(bus, opcode);
+                self.a_reg = self.shift_right(data);
+                self.set_zero_negative(self.a_reg);
+            }
+            // C and V come from bits 6 and 5 of the result instead of the rotation.
+            0x6B => {
+                let data = self.a_reg & self.read_operand(bus, opcode);
+                self.a_reg = self.rotate_right(data);
+                self.set_zero_negative(self.a_reg);
+                self.status_flags
+                    .set(CpuStatusFlags::CARRY, self.a_reg & 0x40 != 0);
+                self.status_flags.set(
+                    CpuStatusFlags::OVERFLOW,
+                    (self.a_reg >> 6 ^ self.a_reg >> 5) & 0x01 != 0,
+                );
+            }
+            0xCB => {
+                let data = self.read_operand(bus, opcode);
+                let a_and_x = self.a_reg & self.x_reg;
+                self.compare(a_and_x, data);
+                self.x_reg = a_and_x.wrapping_sub(data);
+            }
+            // Unstable: A is ORed with a chip-dependent constant first. ANE uses 0xEE, the
+            // value commonly observed.
+            0x8B => {
+                let data = self.read_operand(bus, opcode);
+                self.a_reg = (self.a_reg | 0xEE) & self.x_reg & data;
+                self.set_zero_negative(self.a_reg);
+            }
+            // LXA is emulated with the constant 0xFF, which makes A no part of the
+            // result: A = X = operand.
+            0xAB => {
+                let data = self.read_operand(bus, opcode);
+                self.a_reg = data;
+                self.x_reg = data;
+                self.set_zero_negative(data);
+            }
+            0x93 | 0x9F => self.store_and_high(bus, opcode, self.a_reg & self.x_reg),
+            0x9E => self.store_and_high(bus, opcode, self.x_reg),
+            0x9C => self.store_and_high(bus, opcode, self.y_reg),
+            0x9B => {
+                self.stack_pointer = self.a_reg & self.x_reg;
+                self.store_and_high(bus, opcode, self.stack_pointer);
+            }
+            // Unofficial NOPs still fetch their operand, page-cross penalty included.
+            0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 | 0x04 | 0x44 | 0x64 | 0x14 | 0x34 | 0x54 | 0x74
+            | 0xD4 | 0xF4 | 0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
+                self.read_operand(bus, opcode);
+            }
+            0x96 => {
+                let address = self.read_instr_byte(bus);
+                bus.read(u16::from(address));
+                bus.write(u16::from(address.wrapping_add(self.y_reg)), self.x_reg);
+                trace!(
+                    self,
+                    "STX (Zero Page,Y) => 0x{address:02x} -> 0x{:02x} = 0x{:02x}",
+                    address.wrapping_add(self.y_reg),
+                    self.x_reg
+                );
+            }
+            0x94 => {
+                let address = self.read_instr_byte(bus);
+                bus.read(u16::from(address));
+                bus.write(u16::from(address.wrapping_add(self.x_reg)), self.y_reg);
+                trace!(
+                    self,
+                    "STY (Zero Page,X) => 0x{address:02x} -> 0x{:02x} = 0x{:02x}",
+                    address.wrapping_add(self.x_reg),
+                    self.y_reg
+                );
+            }
+            0x6C => {
+                let pointer = self.read_instr_u16(bus);
+                // The high byte is fetched without carrying into the pointer's high byte.
+                let address = u16::from(bus.read(pointer))
+                    | u16::from(bus.read(pointer & 0xFF00 | pointer.wrapping_add(1) & 0x00FF)) << 8;
+                self.prog_counter = address;
+                trace!(self, "JMP (Indirect) => 0x{pointer:04x} -> 0x{address:04x}");
+            }
+            0x8A => {
+                bus.read(self.prog_counter);
+                self.a_reg = self.x_reg;
+                self.set_zero_negative(self.a_reg);
+                trace!(self, "TXA (Implied) => 0x{:02x}", self.a_reg);
+            }
+            0x18 => {
+                bus.read(self.prog_counter);
+                self.status_flags.set(CpuStatusFlags::CARRY, false);
+                trace!(self, "CLC (Implied)");
+            }
+            0x38 => {
+                bus.read(self.prog_counter);
+                self.status_flags.set(CpuStatusFlags::CARRY, true);
+                trace!(self, "SEC (Implied)");
+            }
+            0xB8 => {
+                bus.read(self.prog_counter);
+                self.status_flags.set(CpuStatusFlags::OVERFLOW, false);
+                trace!(self, "CLV (Implied)");
+            }
+            0xF8 => {
+                bus.read(self.prog_counter);
+                self.status_flags.set(CpuStatusFlags::DECIMAL, true);
+                trace!(self, "SED (Implied)");
+            }
+            0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {
+                bus.read(self.prog_counter);
+                trace!(self, "NOP (Implied)");
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The status byte as pushed on the stack: bit 5 always reads as set, and bit 4 tells
+    /// a software push (PHP/BRK) from a hardware interrupt (IRQ/NMI).
+    const fn status_for_push(&self, brk: bool) -> u8 {
+        let status = self.status_flags.bits() | CpuStatusFlags::IGNORED.bits();
+        if brk {
+            status | CpuStatusFlags::B_FLAG.bits()
+        } else {
+            status & !CpuStatusFlags::B_FLAG.bits()
+        }
+    }
+
+    /// Bits 4 and 5 are not real flip-flops, so pulling the status (PLP/RTI) leaves them alone.
+    fn status_from_pull(&mut self, byte: u8) {
+        let kept = CpuStatusFlags::B_FLAG | CpuStatusFlags::IGNORED;
+        self.status_flags = (CpuStatusFlags::from_bits_retain(byte) - kept)
+            | (self.status_flags & CpuStatusFlags::B_FLAG)
+            | CpuStatusFlags::IGNORED;
+    }
+
+    fn push_stack<B: Bus>(&mut self, bus: &mut B, data: u8) {
+        bus.write(u16::from(self.stack_pointer) | 0x0100, data);
+        if self.stack_check && self.stack_pointer == 0x00 {
+            self.stack_wrap = Some(true);
+        }
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+    }
+
+    fn pull_stack<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        bus.read(u16::from(self.stack_pointer) | 0x0100);
+        self.increment_stack_pointer();
+        bus.read(u16::from(self.stack_pointer) | 0x0100)
+    }
+
+    fn pull_stack_address<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        bus.read(u16::from(self.stack_pointer) | 0x0100);
+        self.increment_stack_pointer();
+        let low = bus.read(u16::from(self.stack_pointer) | 0x0100);
+        self.increment_stack_pointer();
+        u16::from(bus.read(u16::from(self.stack_pointer) | 0x0100)) << 8 | u16::from(low)
+    }
+
+    /// Move SP up for a pull.
+    const fn increment_stack_pointer(&mut self) {
+        if self.stack_check && self.stack_pointer == 0xFF {
+            self.stack_wrap = Some(false);
+        }
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+    }
+
+    fn read_instr_byte<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let data = bus.read(self.prog_counter);
+        self.prog_counter = self.prog_counter.wrapping_add(1);
+        data
+    }
+
+    /// Fetch a little-endian word operand, such as an absolute address.
+    fn read_instr_u16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let low = self.read_instr_byte(bus);
+        u16::from(low) | u16::from(self.read_instr_byte(bus)) << 8
+    }
+
+    /// Fetch the operand of a read instruction and return the address the data is read
+    /// from. Indexed modes do their extra read when the index crosses a page.
+    fn read_operand_address<B: Bus>(&mut self, bus: &mut B, mode: AddressingMode) -> u16 {
+        use AddressingMode as M;
+        match mode {
+            M::Immediate => {
+                let address = self.prog_counter;
+                self.prog_counter = self.prog_counter.wrapping_add(1);
+                address
+            }
+            M::ZeroPage => u16::from(self.read_instr_byte(bus)),
+            M::ZeroPageX | M::ZeroPageY => {
+                let base = self.read_instr_byte(bus);
+                bus.read(u16::from(base));
+                let index = if mode == M::ZeroPageX {
+                    self.x_reg
+                } else {
+                    self.y_reg
+                };
+                u16::from(base.wrapping_add(index))
+            }
+            M::Absolute => self.read_instr_u16(bus),
+            M::AbsoluteX | M::AbsoluteY => {
+                let base = self.read_instr_u16(bus);
+                let index = if mode == M::AbsoluteX {
+                    self.x_reg
+                } else {
+                    self.y_reg
+                };
+                Self::add_index(bus, base, index)
+            }
+            // Both indirect modes fetch their pointer with `read_u16_zp`, so that a
+            // pointer at 0xFF takes its high byte from 0x00 and not 0x100.
+            M::IndirectX => {
+                let pointer = self.read_instr_byte(bus);
+                bus.read(u16::from(pointer));
+                let pointer = pointer.wrapping_add(self.x_reg);
+                bus.read_u16_zp(pointer)
+            }
+            M::IndirectY => {
+                let pointer = self.read_instr_byte(bus);
+                let base = bus.read_u16_zp(pointer);
+                Self::add_index(bus, base, self.y_reg)
+            }
+            _ => unreachable!("{mode} has no operand to read"),
+        }
+    }
+
+    /// Index `base`, reading from the address with the carry not yet applied to the high
+    /// byte when the index crosses a page, like the hardware does.
+    fn add_index<B: Bus>(bus: &mut B, base: u16, index: u8) -> u16 {
+        let address = base.wrapping_add(u16::from(index));
+        if address & 0xFF00 != base & 0xFF00 {
+            bus.read(base & 0xFF00 | address & 0x00FF);
+        }
+        address
+    }
+
+    /// Index `base` the way stores and read-modify-writes do: the read from the address
+    /// with the carry not yet applied happens even when the index stays in the page.
+    fn add_index_fixed_up<B: Bus>(bus: &mut B, base: u16, index: u8) -> u16 {
+        let address = base.wrapping_add(u16::from(index));
+        bus.read(base & 0xFF00 | address & 0x00FF);
+        address
+    }
+
+    fn set_zero_negative(&mut self, value: u8) {
+        self.status_flags.set(CpuStatusFlags::ZERO, value == 0);
+        self.status_flags
+            .set(CpuStatusFlags::NEGATIVE, value & 0b1000_0000 != 0);
+    }
+
+    /// Fetch the operand of a read instruction and read the data it points to.
+    fn read_operand<B: Bus>(&mut self, bus: &mut B, opcode: u8) -> u8 {
+        let Opcode { mnemonic, mode, .. } = OPCODES[usize::from(opcode)];
+        let address = self.read_operand_address(bus, mode);
+        let data = bus.read(address);
+        if mode == AddressingMode::Immediate {
+            trace!(self, "{mnemonic} ({mode}) => 0x{data:02x}");
+        } else {
+            trace!(
+                self,
+                "{mnemonic} ({mode}) => 0x{address:04x} = 0x{data:02x}"
+            );
+        }
+        data
+    }
+
+    /// Run a shift, rotate, increment or decrement on A or on memory, returning the
+    /// result. Memory is written twice: first the unmodified value while the ALU works,
+    /// then the result.
+    fn read_modify_write<B: Bus>(
+        &mut self,
+        bus: &mut B,
+        opcode: u8,
+        operation: impl FnOnce(&mut Self, u8) -> u8,
+    ) -> u8 {
+        let Opcode { mnemonic, mode, .. } = OPCODES[usize::from(opcode)];
+        if mode == AddressingMode::Accumulator {
+            bus.read(self.prog_counter);
+            let data = self.a_reg;
+            self.a_reg = operation(self, data);
+            self.set_zero_negative(self.a_reg);
+            trace!(
+                self,
+                "{mnemonic} ({mode}) => 0x{data:02x} -> 0x{:02x}",
+                self.a_reg
+            );
+            return self.a_reg;
+        }
+        let address = self.write_operand_address(bus, mode);
+        let data = bus.read(address);
+        bus.write(address, data);
+        let new_data = operation(self, data);
+        bus.write(address, new_data);
+        self.set_zero_negative(new_data);
+        trace!(
+            self,
+            "{mnemonic} ({mode}) => 0x{address:04x} -> 0x{data:02x} -> 0x{new_data:02x}"
+        );
+        new_data
+    }
+
+    /// The SHA/SHX/SHY/TAS store: `value` AND the high byte of the base address
+    /// plus one. When indexing crosses a page, that value also replaces the high byte
+    /// of the address written to.
+    fn store_and_high<B: Bus>(&mut self, bus: &mut B, opcode: u8, value: u8) {
+        use AddressingMode as M;
+        let Opcode { mnemonic, mode, .. } = OPCODES[usize::from(opcode)];
+        let (base, index) = if mode == M::IndirectY {
+            let pointer = self.read_instr_byte(bus);
+            (bus.read_u16_zp(pointer), self.y_reg)
+        } else {
+            let base = self.read_instr_u16(bus);
+            (
+                base,
+                if mode == M::AbsoluteX {
+                    self.x_reg
+                } else {
+                    self.y_reg
+                },
+            )
+        };
+        let mut address = Self::add_index_fixed_up(bus, base, index);
+        let [_, high] = base.to_le_bytes();
+        let data = value & high.wrapping_add(1);
+        if address & 0xFF00 != base & 0xFF00 {
+            address = u16::from(data) << 8 | address & 0x00FF;
+        }
+        bus.write(address, data);
+        trace!(
+            self,
+            "{mnemonic} ({mode}) => 0x{address:04x} = 0x{data:02x}"
+        );
+    }
+
+    fn shift_left(&mut self, data: u8) -> u8 {
+        self.status_flags
+            .set(CpuStatusFlags::CARRY, data & 0x80 != 0);
+        data << 1
+    }
+
+    fn shift_right(&mut self, data: u8) -> u8 {
+        self.status_flags
+            .set(CpuStatusFlags::CARRY, data & 0x01 != 0);
+        data >> 1
+    }
+
+    fn rotate_left(&mut self, data: u8) -> u8 {
+        let carry_in = u8::from(self.status_flags.contains(CpuStatusFlags::CARRY));
+        self.status_flags
+            .set(CpuStatusFlags::CARRY, data & 0x80 != 0);
+        data << 1 | carry_in
+    }
+
+    fn rotate_right(&mut self, data: u8) -> u8 {
+        let carry_in = u8::from(self.status_flags.contains(CpuStatusFlags::CARRY));
+        self.status_flags
+            .set(CpuStatusFlags::CARRY, data & 0x01 != 0);
+        data >> 1 | carry_in << 7
+    }
+
+    /// Resolve the operand address of a store or read-modify-write instruction. Unlike
+    /// reads, indexed modes always spend the cycle fixing up the high byte.
+    fn write_operand_address<B: Bus>(&mut self, bus: &mut B, mode: AddressingMode) -> u16 {
+        use AddressingMode as M;
+        match mode {
+            M::AbsoluteX | M::AbsoluteY => {
+                let base = self.read_instr_u16(bus);
+                let index = if mode == M::AbsoluteX {
+                    self.x_reg
+                } else {
+                    self.y_reg
+                };
+                Self::add_index_fixed_up(bus, base, index)
+            }
+            M::IndirectY => {
+                let pointer = self.read_instr_byte(bus);
+                let base = bus.read_u16_zp(pointer);
+                Self::add_index_fixed_up(bus, base, self.y_reg)
+            }
+            _ => self.read_operand_address(bus, mode),
+        }
+    }
+
+    /// Finish a branch once its offset has been fetched. Taking it costs a cycle, and
+    /// another one when the target is in a different page.
+    fn branch<B: Bus>(&mut self, bus: &mut B, opcode: u8, taken: bool) {
+        let mnemonic = OPCODES[usize::from(opcode)].mnemonic;
+        let offset = self.read_instr_byte(bus);
+        if !taken {
+            trace!(self, "{mnemonic} (Relative) => 0x{offset:02x}, not taken");
+            return;
+        }
+        bus.read(self.prog_counter);
+        let (target, page_crossed) = relative_target(self.prog_counter, offset);
+        if page_crossed {
+            bus.read(self.prog_counter & 0xFF00 | target & 0x00FF);
+        }
+        self.prog_counter = target;
+        trace!(
+            self,
+            "{mnemonic} (Relative) => 0x{offset:02x} -> 0x{target:04x}, taken"
+        );
+    }
+
+    /// CMP, CPX and CPY: the flags of `register - data`, without the borrow.
+    fn compare(&mut self, register: u8, data: u8) {
+        self.status_flags
+            .set(CpuStatusFlags::CARRY, register >= data);
+        self.set_zero_negative(register.wrapping_sub(data));
+    }
+
+    fn add_with_carry(&mut self, operand: u8) {
+        let carry = u8::from(self.status_flags.contains(CpuStatusFlags::CARRY));
+        if self.decimal_mode && self.status_flags.contains(CpuStatusFlags::DECIMAL) {
+            self.add_decimal(operand, carry);
+        } else {
+            self.add_binary(operand, carry);
+        }
+    }
+
+    /// SBC is ADC of the inverted operand in binary mode. In decimal mode the flags still
+    /// come from the binary subtraction, only A is adjusted.
+    fn subtract_with_borrow(&mut self, operand: u8) {
+        let decimal = self.decimal_mode && self.status_flags.contains(CpuStatusFlags::DECIMAL);
+        let a_reg = self.a_reg;
+        let borrow = i16::from(!self.status_flags.contains(CpuStatusFlags::CARRY));
+        self.add_binary(!operand, u8::from(borrow == 0));
+        if decimal {
+            let mut low = i16::from(a_reg & 0x0F) - i16::from(operand & 0x0F) - borrow;
+            if low < 0 {
+                low = ((low - 0x06) & 0x0F) - 0x10;
+            }
+            let mut result = i16::from(a_reg & 0xF0) - i16::from(operand & 0xF0) + low;
+            if result < 0 {
+                result -= 0x60;
+            }
+            self.a_reg = result.to_le_bytes()[0];
+        }
+    }
+
+    fn add_binary(&mut self, operand: u8, carry: u8) {
+        let sum = u16::from(self.a_reg) + u16::from(operand) + u16::from(carry);
+        let [result, carry_out] = sum.to_le_bytes();
+        self.status_flags.set(CpuStatusFlags::CARRY, carry_out != 0);
+        self.status_flags.set(
+            CpuStatusFlags::OVERFLOW,
+            (self.a_reg ^ result) & (operand ^ result) & 0x80 != 0,
+        );
+        self.a_reg = result;
+        self.set_zero_negative(self.a_reg);
+    }
+
+    /// BCD addition as done by the NMOS 6502: Z comes from the binary sum, N and V from
+    /// the sum before the high digit is adjusted.
+    fn add_decimal(&mut self, operand: u8, carry: u8) {
+        let binary = self.a_reg.wrapping_add(operand).wrapping_add(carry);
+        let mut low = u16::from(self.a_reg & 0x0F) + u16::from(operand & 0x0F) + u16::from(carry);
+        if low >= 0x0A {
+            low = ((low + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = u16::from(self.a_reg & 0xF0) + u16::from(operand & 0xF0) + low;
+        let [unadjusted, _] = sum.to_le_bytes();
+        self.status_flags.set(
+            CpuStatusFlags::OVERFLOW,
+            (self.a_reg ^ unadjusted) & (operand ^ unadjusted) & 0x80 != 0,
+        );
+        self.status_flags
+            .set(CpuStatusFlags::NEGATIVE, unadjusted & 0x80 != 0);
+        self.status_flags.set(CpuStatusFlags::ZERO, binary == 0);
+        if sum >= 0xA0 {
+            sum += 0x60;
+        }
+        self.status_flags.set(CpuStatusFlags::CARRY, sum >= 0x100);
+        self.a_reg = sum.to_le_bytes()[0];
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every opcode the CPU cannot execute yet, in ascending order. The JAM opcodes are
+/// left out: locking up the CPU is what they do.
+#[must_use]
+pub fn unimplemented_opcodes() -> Vec<u8> {
+    (0..=0xFF)
+        .filter(|&opcode| OPCODES[usize::from(opcode)].mnemonic != "JAM")
+        .filter(|&opcode| {
+            let mut bus = ProbeBus::default();
+            let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+            !cpu.execute_opcode(&mut bus, opcode)
+        })
+        .collect()
+}
+
+/// Reads as zero and ignores writes, for trying out opcodes in isolation.
+#[derive(Default)]
+struct ProbeBus {
+    cycles: u64,
+}
+
+impl Bus for ProbeBus {
+    fn read(&mut self, _address: u16) -> u8 {
+        self.cycles += 1;
+        0
+    }
+
+    fn write(&mut self, _address: u16, _data: u8) {
+        self.cycles += 1;
+    }
+
+    fn peek(&mut self, _address: u16) -> u8 {
+        0
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::testing::{exec_bytes, FlatMemoryBus};
+
+    fn state(a_reg: u8, x_reg: u8, y_reg: u8, status: u8) -> CpuState {
+        CpuState {
+            a_reg,
+            x_reg,
+            y_reg,
+            prog_counter: 0x0200,
+            stack_pointer: 0xFD,
+            status,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_line: false,
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Access {
+        Read(u16),
+        Write(u16, u8),
+    }
+
+    /// 64KB of RAM that records every access.
+    struct RecordingBus {
+        inner: FlatMemoryBus,
+        accesses: Vec<Access>,
+    }
+
+    impl RecordingBus {
+        fn new() -> Self {
+            Self {
+                inner: FlatMemoryBus::new(),
+                accesses: Vec::new(),
+            }
+        }
+    }
+
+    impl Bus for RecordingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.accesses.push(Access::Read(address));
+            self.inner.read(address)
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.accesses.push(Access::Write(address, data));
+            self.inner.write(address, data);
+        }
+
+        fn peek(&mut self, address: u16) -> u8 {
+            self.inner.peek(address)
+        }
+
+        fn cycles(&self) -> u64 {
+            self.inner.cycles()
+        }
+    }
+
+    /// Run the instruction `bytes` at 0x0200 with the given registers, returning the bus
+    /// accesses it made.
+    fn accesses_of(bytes: &[u8], setup: CpuState, memory: &[(u16, u8)]) -> Vec<Access> {
+        let mut bus = RecordingBus::new();
+        bus.inner.load(0x0200, bytes);
+        for &(address, data) in memory {
+            bus.inner.load(address, &[data]);
+        }
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&setup);
+        cpu.run_instr(&mut bus).expect("opcode runs");
+        bus.accesses
+    }
+
+    #[test]
+    fn absolute_indexed_stores_read_the_uncarried_address() {
+        use Access::{Read, Write};
+        let sta_x = accesses_of(&[0x9D, 0xF0, 0x20], state(0x42, 0x20, 0, 0x24), &[]);
+        assert_eq!(
+            sta_x,
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0202),
+                Read(0x2010),
+                Write(0x2110, 0x42)
+            ]
+        );
+        let sta_y = accesses_of(&[0x99, 0x00, 0x40], state(0x42, 0, 0x15, 0x24), &[]);
+        assert_eq!(
+            sta_y,
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0202),
+                Read(0x4015),
+                Write(0x4015, 0x42)
+            ]
+        );
+    }
+
+    #[test]
+    fn read_modify_write_writes_the_original_value_first() {
+        for (opcode, modified) in [
+            (0x06, 0x02), // ASL
+            (0x26, 0x02), // ROL
+            (0x46, 0x40), // LSR
+            (0x66, 0x40), // ROR
+            (0xE6, 0x82), // INC
+            (0xC6, 0x80), // DEC
+        ] {
+            let writes: Vec<_> =
+                accesses_of(&[opcode, 0x10], state(0, 0, 0, 0x24), &[(0x10, 0x81)])
+                    .into_iter()
+                    .filter(|access| matches!(access, Access::Write(..)))
+                    .collect();
+            assert_eq!(
+                writes,
+                [Access::Write(0x10, 0x81), Access::Write(0x10, modified)],
+                "opcode {opcode:02X}"
+            );
+        }
+    }
+
+    #[test]
+    fn two_cycle_implied_opcodes_dummy_read_the_next_byte() {
+        let stack_or_flow = ["BRK", "RTI", "RTS", "PHA", "PHP", "PLA", "PLP", "JAM"];
+        for opcode in 0..=u8::MAX {
+            let Opcode { mnemonic, mode, .. } = OPCODES[usize::from(opcode)];
+            if !matches!(mode, AddressingMode::Implied | AddressingMode::Accumulator)
+                || stack_or_flow.contains(&mnemonic)
+            {
+                continue;
+            }
+            let mut bus = RecordingBus::new();
+            bus.inner.load(0x0200, &[opcode]);
+            let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+            cpu.set_state(&state(0, 0, 0, 0x24));
+            cpu.run_instr(&mut bus).expect("opcode runs");
+            assert_eq!(
+                bus.accesses,
+                [Access::Read(0x0200), Access::Read(0x0201)],
+                "{mnemonic} ({opcode:02X})"
+            );
+            assert_eq!(cpu.prog_counter(), 0x0201, "{mnemonic} ({opcode:02X})");
+        }
+    }
+
+    #[test]
+    fn implied_opcode_access_patterns() {
+        use Access::{Read, Write};
+        let setup = state(0x42, 0, 0, 0x24);
+        assert_eq!(
+            accesses_of(&[0x48], setup, &[]),
+            [Read(0x0200), Read(0x0201), Write(0x01FD, 0x42)],
+            "PHA"
+        );
+        assert_eq!(
+            accesses_of(&[0x68], setup, &[]),
+            [Read(0x0200), Read(0x0201), Read(0x01FD), Read(0x01FE)],
+            "PLA"
+        );
+        assert_eq!(
+            accesses_of(&[0xAA], setup, &[]),
+            [Read(0x0200), Read(0x0201)],
+            "TAX"
+        );
+        assert_eq!(
+            accesses_of(&[0x18], setup, &[]),
+            [Read(0x0200), Read(0x0201)],
+            "CLC"
+        );
+    }
+
+    #[test]
+    fn indirect_y_reads_take_a_cycle_more_on_page_cross() {
+        let reads = ["ORA", "AND", "EOR", "ADC", "LDA", "LAX", "CMP", "SBC"];
+        let opcodes: Vec<u8> = (0..=u8::MAX)
+            .filter(|&opcode| {
+                let Opcode { mnemonic, mode, .. } = OPCODES[usize::from(opcode)];
+                mode == AddressingMode::IndirectY && reads.contains(&mnemonic)
+            })
+            .collect();
+        assert_eq!(opcodes.len(), reads.len());
+        for opcode in opcodes {
+            for (y_reg, cycles) in [(0x05, 5), (0x20, 6)] {
+                let mut bus = FlatMemoryBus::new();
+                bus.load(0x0010, &[0xF0, 0x02]);
+                let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+                cpu.set_state(&state(0, 0, y_reg, 0x24));
+                let result = exec_bytes(&mut cpu, &mut bus, &[opcode, 0x10]).expect("opcode runs");
+                assert_eq!(
+                    result.cycles, cycles,
+                    "opcode {opcode:02X}, Y = {y_reg:02X}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn transfers_set_zero_and_negative_except_txs() {
+        const Z: u8 = CpuStatusFlags::ZERO.bits();
+        const N: u8 = CpuStatusFlags::NEGATIVE.bits();
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        for (value, flags) in [(0x00, Z), (0x80, N), (0x01, 0)] {
+            for (opcode, name) in [
+                (0xAA, "TAX"),
+                (0xA8, "TAY"),
+                (0x8A, "TXA"),
+                (0x98, "TYA"),
+                (0xBA, "TSX"),
+                (0x9A, "TXS"),
+            ] {
+                // Start with the opposite flags, so that a missing update shows.
+                let mut setup = state(0x55, 0x55, 0x55, 0x24 | (!flags & (Z | N)));
+                setup.stack_pointer = 0x55;
+                match name {
+                    "TAX" | "TAY" => setup.a_reg = value,
+                    "TXA" | "TXS" => setup.x_reg = value,
+                    "TYA" => setup.y_reg = value,
+                    _ => setup.stack_pointer = value,
+                }
+                cpu.set_state(&setup);
+                exec_bytes(&mut cpu, &mut bus, &[opcode]).expect("opcode runs");
+                let after = cpu.state();
+                let destination = match name {
+                    "TAX" | "TSX" => after.x_reg,
+                    "TAY" => after.y_reg,
+                    "TXA" | "TYA" => after.a_reg,
+                    _ => after.stack_pointer,
+                };
+                assert_eq!(destination, value, "{name} {value:02X}");
+                let expected = if name == "TXS" {
+                    setup.status
+                } else {
+                    0x24 | flags
+                };
+                assert_eq!(after.status, expected, "{name} {value:02X}");
+            }
+        }
+    }
+
+    /// Binary ADC from first principles: the 9-bit sum, and V when both inputs have the
+    /// same sign and the result does not. Returns A and P, with P's other bits taken
+    /// from `status`.
+    fn reference_adc(a: u8, operand: u8, status: u8) -> (u8, u8) {
+        let carry = status & CpuStatusFlags::CARRY.bits();
+        let sum = u16::from(a) + u16::from(operand) + u16::from(carry);
+        let result = sum.to_le_bytes()[0];
+        let mut flags = CpuStatusFlags::from_bits_retain(status);
+        flags.set(CpuStatusFlags::CARRY, sum > 0xFF);
+        flags.set(CpuStatusFlags::ZERO, result == 0);
+        flags.set(CpuStatusFlags::NEGATIVE, result & 0x80 != 0);
+        flags.set(
+            CpuStatusFlags::OVERFLOW,
+            (a ^ result) & (operand ^ result) & 0x80 != 0,
+        );
+        (result, flags.bits())
+    }
+
+    /// SBC is ADC of the inverted operand.
+    fn reference_sbc(a: u8, operand: u8, status: u8) -> (u8, u8) {
+        reference_adc(a, !operand, status)
+    }
+
+    /// Run `opcode #operand` with A and P set up, returning A and P afterwards.
+    fn run_immediate(
+        cpu: &mut Cpu,
+        bus: &mut FlatMemoryBus,
+        opcode: u8,
+        a: u8,
+        operand: u8,
+        status: u8,
+    ) -> (u8, u8) {
+        cpu.set_state(&state(a, 0, 0, status));
+        exec_bytes(cpu, bus, &[opcode, operand]).expect("opcode runs");
+        let after = cpu.state();
+        (after.a_reg, after.status)
+    }
+
+    fn check_all_inputs(opcode: u8, reference: fn(u8, u8, u8) -> (u8, u8)) {
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        for a in 0..=u8::MAX {
+            for operand in 0..=u8::MAX {
+                for status in [0x24, 0x25] {
+                    assert_eq!(
+                        run_immediate(&mut cpu, &mut bus, opcode, a, operand, status),
+                        reference(a, operand, status),
+                        "A={a:02X} operand={operand:02X} P={status:02X}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn adc_matches_reference_for_all_inputs() {
+        check_all_inputs(0x69, reference_adc);
+    }
+
+    #[test]
+    fn sbc_matches_reference_for_all_inputs() {
+        check_all_inputs(0xE9, reference_sbc);
+    }
+
+    proptest! {
+        /// Every other flag is kept, and the D flag alone does not switch to BCD while
+        /// decimal mode is off, as on the 2A03.
+        #[test]
+        fn adc_sbc_keep_other_flags(a: u8, operand: u8, status: u8, subtract: bool) {
+            let mut bus = FlatMemoryBus::new();
+            let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+            let (opcode, expected) = if subtract {
+                (0xE9, reference_sbc(a, operand, status))
+            } else {
+                (0x69, reference_adc(a, operand, status))
+            };
+            prop_assert_eq!(
+                run_immediate(&mut cpu, &mut bus, opcode, a, operand, status),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn ram_starts_zeroed_and_is_mirrored() {
+        let mut ram = Ram::new();
+        assert_eq!(CpuBusMember::read(&mut ram, 0x0000), Some(0));
+        let mirror = u16::try_from(Ram::RAM_SIZE).expect("RAM fits the address space");
+        assert!(CpuBusMember::write(&mut ram, mirror, 0x42));
+        assert_eq!(CpuBusMember::read(&mut ram, 0x0000), Some(0x42));
+        assert_eq!(ram.contents()[0], 0x42);
+    }
+
+    /// Flat memory whose /NMI is asserted from cycle `nmi_from` on.
+    struct NmiBus {
+        inner: FlatMemoryBus,
+        nmi_from: u64,
+    }
+
+    impl Bus for NmiBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.inner.read(address)
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.inner.write(address, data);
+        }
+
+        fn peek(&mut self, address: u16) -> u8 {
+            self.inner.peek(address)
+        }
+
+        fn cycles(&self) -> u64 {
+            self.inner.cycles()
+        }
+
+        fn nmi_asserted(&mut self) -> bool {
+            self.inner.cycles() >= self.nmi_from
+        }
+    }
+
+    /// Run a BRK at 0x0200 with /NMI asserted from cycle `nmi_from`, returning the CPU
+    /// state and the status pushed by BRK.
+    fn brk_with_nmi_from(nmi_from: u64) -> (CpuState, u8) {
+        let mut bus = NmiBus {
+            inner: FlatMemoryBus::new(),
+            nmi_from,
+        };
+        bus.inner.load(0x0200, &[0x00, 0x00]);
+        bus.inner.load(0xFFFA, &[0x00, 0x90]);
+        bus.inner.load(0xFFFE, &[0x00, 0xA0]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, 0x20));
+        cpu.step_with_interrupts(&mut bus, |bus| (bus.nmi_asserted(), false))
+            .expect("BRK runs");
+        (cpu.state(), bus.peek(0x01FB))
+    }
+
+    #[test]
+    fn nmi_during_brk_pushes_hijacks_the_vector() {
+        // BRK pushes on cycles 3-5 and reads the vector on cycles 6-7.
+        let (after, pushed) = brk_with_nmi_from(3);
+        assert_eq!(after.prog_counter, 0x9000);
+        assert_eq!(after.stack_pointer, 0xFA);
+        assert!(!after.nmi_pending);
+        assert_ne!(pushed & CpuStatusFlags::B_FLAG.bits(), 0);
+
+        // Too late to change the vector: the NMI is taken after BRK instead.
+        let (after, pushed) = brk_with_nmi_from(6);
+        assert_eq!(after.prog_counter, 0x9000);
+        assert_eq!(after.stack_pointer, 0xF7);
+        assert_ne!(pushed & CpuStatusFlags::B_FLAG.bits(), 0);
+    }
+
+    #[test]
+    fn ane_and_lxa_use_their_constants() {
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        // ANE: A = (A | 0xEE) & X & operand.
+        cpu.set_state(&state(0x01, 0xF7, 0, 0x24));
+        exec_bytes(&mut cpu, &mut bus, &[0x8B, 0x7F]).expect("opcode runs");
+        assert_eq!(cpu.state().a_reg, 0x67);
+        // LXA: A = X = operand, whatever A was.
+        cpu.set_state(&state(0x00, 0x11, 0, 0x24));
+        exec_bytes(&mut cpu, &mut bus, &[0xAB, 0x85]).expect("opcode runs");
+        let after = cpu.state();
+        assert_eq!((after.a_reg, after.x_reg), (0x85, 0x85));
+        assert_eq!(after.status, 0x24 | CpuStatusFlags::NEGATIVE.bits());
+    }
+
+    /// The write made by a SHX or SHY of `register` at `base` indexed by `index`.
+    fn high_and_store(opcode: u8, register: u8, base: u16, index: u8) -> Access {
+        let setup = if opcode == 0x9E {
+            state(0, register, index, 0x24)
+        } else {
+            state(0, index, register, 0x24)
+        };
+        let [low, high] = base.to_le_bytes();
+        let accesses = accesses_of(&[opcode, low, high], setup, &[]);
+        *accesses.last().expect("an access")
+    }
+
+    #[test]
+    fn shx_shy_store_the_register_and_the_high_byte_plus_one() {
+        for opcode in [0x9E, 0x9C] {
+            // 0x05 & (0x02 + 1).
+            assert_eq!(
+                high_and_store(opcode, 0x05, 0x02E0, 0x10),
+                Access::Write(0x02F0, 0x01),
+                "opcode {opcode:02X}"
+            );
+            // Crossing into page 3, the value stored replaces the high byte too.
+            assert_eq!(
+                high_and_store(opcode, 0x05, 0x02F0, 0x20),
+                Access::Write(0x0110, 0x01),
+                "opcode {opcode:02X}"
+            );
+        }
+    }
+
+    #[test]
+    fn stack_check_reports_wraps_after_the_instruction() {
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut setup = state(0x42, 0, 0, 0x24);
+        setup.stack_pointer = 0x00;
+        cpu.set_state(&setup);
+        exec_bytes(&mut cpu, &mut bus, &[0x48]).expect("the check is off by default");
+
+        cpu.set_stack_check(true);
+        cpu.set_state(&setup);
+        assert_eq!(
+            exec_bytes(&mut cpu, &mut bus, &[0x48]),
+            Err(CpuError::StackOverflow { pc: 0x0200 })
+        );
+        assert_eq!(cpu.state().stack_pointer, 0xFF);
+        assert_eq!(bus.peek(0x0100), 0x42);
+        // Pulling it back wraps the other way.
+        assert_eq!(
+            exec_bytes(&mut cpu, &mut bus, &[0x68]),
+            Err(CpuError::StackUnderflow { pc: 0x0201 })
+        );
+        assert_eq!(cpu.state().stack_pointer, 0x00);
+        exec_bytes(&mut cpu, &mut bus, &[0xEA]).expect("no push nor pull");
+    }
+
+    #[test]
+    fn every_opcode_but_jam_is_implemented() {
+        assert_eq!(unimplemented_opcodes(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn injected_ppu_ignores_writes_until_warmed_up() {
+        let mut bus = CpuMemoryBusBuilder::new().with_ppu(Ppu::new()).build();
+        let ctrl = |bus: &CpuMemoryBus| bus.ppu().expect("PPU").ctrl().bits();
+        // OAMADDR is not affected by the warm-up, so writing it just passes time.
+        while bus.cycles() < 29_600 {
+            bus.write(0x2003, 0);
+        }
+        bus.write(0x2000, 0x04);
+        assert_eq!(ctrl(&bus), 0x00);
+        while bus.cycles() < 29_700 {
+            bus.write(0x2003, 0);
+        }
+        bus.write(0x2000, 0x04);
+        assert_eq!(ctrl(&bus), 0x04);
+
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        let mut bus = CpuMemoryBusBuilder::new().with_ppu(ppu).build();
+        bus.write(0x2000, 0x04);
+        assert_eq!(ctrl(&bus), 0x04);
+    }
+
+    /// An NROM cartridge whose PRG ROM is all `fill`, with CHR-RAM.
+    fn nrom_cart(fill: u8) -> Cart {
+        Cart::new(MapperEnum::Nrom(Nrom::new(
+            &vec![fill; 0x4000],
+            &[],
+            Mirroring::Horizontal,
+        )))
+    }
+
+    #[test]
+    fn rom_write_modes() {
+        for mode in [RomWriteMode::Ignore, RomWriteMode::Log, RomWriteMode::Trap] {
+            let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+            bus.set_rom_write_mode(mode);
+            bus.write(0x8000, 0x42);
+            assert_eq!(bus.read(0x8000), 0xEA, "{mode:?}");
+            let (trapped, logged) = match mode {
+                RomWriteMode::Ignore => (None, 0),
+                RomWriteMode::Log => (None, 1),
+                RomWriteMode::Trap => (Some((0x8000, 0x42)), 0),
+            };
+            assert_eq!(bus.take_rom_write(), trapped, "{mode:?}");
+            assert_eq!(bus.take_rom_write(), None, "{mode:?}");
+            assert_eq!(bus.logged_rom_writes(), logged, "{mode:?}");
+        }
+    }
+
+    /// Run NOPs from ROM with the APU frame IRQ unmasked, lining one up so that the IRQ
+    /// is raised on its `cycle` (1 or 2), and return the stack pointer after that NOP
+    /// and after the next instruction.
+    fn frame_irq_on_nop_cycle(cycle: u64) -> (u8, u8) {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        let mut probe = bus.clone();
+        while !probe.interrupt_lines().1 {
+            probe.tick();
+        }
+        let start = probe.cycles() - cycle;
+        if (start - bus.cycles()) % 2 == 1 {
+            bus.read(0x0000);
+        }
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut initial = state(0, 0, 0, 0x20);
+        initial.prog_counter = 0x8000;
+        cpu.set_state(&initial);
+        let mut step = |bus: &mut CpuMemoryBus| {
+            cpu.step_with_interrupts(bus, CpuMemoryBus::poll_interrupt_lines)
+                .expect("NOP runs");
+            cpu.state().stack_pointer
+        };
+        while bus.cycles() < start {
+            step(&mut bus);
+        }
+        (step(&mut bus), step(&mut bus))
+    }
+
+    #[test]
+    fn irq_raised_on_the_last_cycle_waits_one_instruction() {
+        // Raised before the poll on the second-to-last cycle: taken right after.
+        assert_eq!(frame_irq_on_nop_cycle(1), (0xFA, 0xFA));
+        // Raised on the last cycle: the NOP after it runs first.
+        assert_eq!(frame_irq_on_nop_cycle(2), (0xFD, 0xFA));
+    }
+
+    /// Run an OAM DMA starting on an odd or even cycle, while the DMC asks for a sample
+    /// byte on cycle `cycle` of the DMA or the one after, and return the stall.
+    fn oam_dma_with_dmc_fetch(odd_start: bool, cycle: u64) -> u64 {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        // A 17-byte sample at the slowest rate: the next read fetches its first byte,
+        // and the second is wanted once the first is played, 3000 cycles or so later.
+        bus.write(0x4013, 0x01);
+        bus.write(0x4015, 0x10);
+        bus.read(0x0000);
+        let mut probe = bus.clone();
+        while probe.apu.as_ref().and_then(Apu::dmc_dma_request).is_none() {
+            probe.tick();
+        }
+        let mut start = probe.cycles() - cycle;
+        if (start % 2 == 1) != odd_start {
+            start -= 1;
+        }
+        while bus.cycles() < start {
+            bus.tick();
+        }
+        bus.oam_dma(0x02)
+    }
+
+    #[test]
+    fn dmc_fetch_during_oam_dma_overlaps_with_it() {
+        for odd_start in [false, true] {
+            let alone = 513 + u64::from(odd_start);
+            // The halt and alignment overlap the OAM DMA: the fetch takes one of its
+            // read cycles, and one more realigns it.
+            assert_eq!(oam_dma_with_dmc_fetch(odd_start, 100), alone + 2);
+            // Wanted on the last read or write, only the halt overlaps.
+            assert_eq!(oam_dma_with_dmc_fetch(odd_start, alone - 1), alone + 3);
+        }
+    }
+
+    #[test]
+    fn dmc_fetch_halts_the_cpu_on_its_next_read() {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        bus.write(0x4015, 0x10);
+        // Writes go through, the fetch waits for a read, which it holds up for 4 cycles.
+        let start = bus.cycles();
+        bus.write(0x0000, 0x42);
+        assert_eq!(bus.cycles() - start, 1);
+        let start = bus.cycles();
+        assert_eq!(bus.read(0x0000), 0x42);
+        assert_eq!(bus.cycles() - start, 5);
+        let start = bus.cycles();
+        bus.read(0x0000);
+        assert_eq!(bus.cycles() - start, 1);
+        let apu = bus.apu.as_ref().expect("bus has an APU");
+        assert_eq!(apu.dmc_dma_request(), None);
+        assert_eq!(apu.peek(0x4015), Some(0x00));
+    }
+
+    #[test]
+    fn oam_dma_copies_a_page_in_513_or_514_cycles() {
+        for odd_write in [false, true] {
+            let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+            for low in 0..=0xFF {
+                bus.write(0x0200 | u16::from(low), !low);
+            }
+            // The write to $4014 lands on the next cycle.
+            if bus.odd_cycle() == odd_write {
+                bus.read(0x0000);
+            }
+            let start = bus.cycles();
+            bus.write(0x4014, 0x02);
+            let stall = bus.cycles() - start - 1;
+            assert_eq!(stall, if odd_write { 514 } else { 513 });
+            for low in 0..=0xFF {
+                // Bits 2-4 of the sprite attributes do not exist.
+                let mask = if low % 4 == 2 { 0xE3 } else { 0xFF };
+                bus.write(0x2003, low);
+                assert_eq!(bus.read(0x2004), !low & mask);
+            }
+        }
+    }
+
+    /// Run `program` from 0x0200 with /IRQ held asserted, starting with `status`, and
+    /// return the return address an IRQ pushed, if one was taken within `steps` steps.
+    fn irq_return_address(program: &[u8], status: u8, steps: usize) -> Option<u16> {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x0200, program);
+        bus.load(0xFFFE, &[0x00, 0x90]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, status));
+        for _ in 0..steps {
+            cpu.step_with_interrupts(&mut bus, |_| (false, true))
+                .expect("program runs");
+        }
+        (cpu.state().prog_counter == 0x9000)
+            .then(|| u16::from_le_bytes([bus.peek(0x01FC), bus.peek(0x01FD)]))
+    }
+
+    #[test]
+    fn cli_and_sei_change_the_irq_mask_one_instruction_late() {
+        // CLI; NOP: the IRQ waits for the NOP.
+        assert_eq!(irq_return_address(&[0x58, 0xEA], 0x24, 1), None);
+        assert_eq!(irq_return_address(&[0x58, 0xEA], 0x24, 2), Some(0x0202));
+        // SEI still lets the IRQ in right after it.
+        assert_eq!(irq_return_address(&[0x78, 0xEA], 0x20, 1), Some(0x0201));
+    }
+
+    #[test]
+    fn reset_reads_the_stack_and_vector_over_7_cycles() {
+        use Access::Read;
+        let mut bus = RecordingBus::new();
+        bus.inner.load(0xFFFC, &[0x34, 0x12]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, 0x20));
+        assert_eq!(cpu.reset(&mut bus), None);
+        assert_eq!(
+            bus.accesses,
+            [
+                Read(0x0200),
+                Read(0x0200),
+                Read(0x01FD),
+                Read(0x01FC),
+                Read(0x01FB),
+                Read(0xFFFC),
+                Read(0xFFFD)
+            ]
+        );
+        assert_eq!(bus.cycles(), 7);
+        let after = cpu.state();
+        assert_eq!(after.prog_counter, 0x1234);
+        assert_eq!(after.stack_pointer, 0xFA);
+        assert_eq!(after.status, 0x24);
+    }
+
+    /// Run `INC $0300` at 0x0200 with /IRQ asserted and /NMI asserted from cycle
+    /// `nmi_from`, returning the CPU state, the pushed return address and the cycles
+    /// taken.
+    fn inc_with_interrupts(nmi_from: u64) -> (CpuState, u16, u64) {
+        let mut bus = NmiBus {
+            inner: FlatMemoryBus::new(),
+            nmi_from,
+        };
+        bus.inner.load(0x0200, &[0xEE, 0x00, 0x03]);
+        bus.inner.load(0xFFFA, &[0x00, 0x90]);
+        bus.inner.load(0xFFFE, &[0x00, 0xA0]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, 0x20));
+        let result = cpu
+            .step_with_interrupts(&mut bus, |bus| (bus.nmi_asserted(), true))
+            .expect("INC runs");
+        let pushed = u16::from_le_bytes([bus.peek(0x01FC), bus.peek(0x01FD)]);
+        (cpu.state(), pushed, result.cycles)
+    }
+
+    #[test]
+    fn interrupts_raised_during_an_instruction_are_taken_after_it() {
+        // An NMI raised while INC runs wins over the IRQ, and both are taken right
+        // after it, within the same step.
+        let (after, pushed, cycles) = inc_with_interrupts(3);
+        assert_eq!(
+            (after.prog_counter, pushed, cycles),
+            (0x9000, 0x0203, 6 + 7)
+        );
+        assert!(!after.nmi_pending);
+        let (after, pushed, cycles) = inc_with_interrupts(u64::MAX);
+        assert_eq!(
+            (after.prog_counter, pushed, cycles),
+            (0xA000, 0x0203, 6 + 7)
+        );
+        assert_eq!(after.stack_pointer, 0xFA);
+    }
+
+    /// PRG ROM of `banks` banks of `bank_size` bytes, each filled with its number.
+    fn numbered_banks(banks: u8, bank_size: usize) -> Vec<u8> {
+        (0..banks)
+            .flat_map(|bank| std::iter::repeat_n(bank, bank_size))
+            .collect()
+    }
+
+    /// Load `value` into the MMC1 register at `address` through the serial port.
+    fn mmc1_write(mmc1: &mut Mmc1, address: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.write(address, value >> bit & 0x01);
+        }
+    }
+
+    #[test]
+    fn surom_picks_the_prg_half_with_chr_bank_bit_4() {
+        let prg = numbered_banks(32, Mmc1::ROM_PAGE_SIZE);
+        let mut mmc1 = Mmc1::new(&prg, &[], Mirroring::Horizontal);
+        mmc1_write(&mut mmc1, 0xE000, 0x02);
+        assert_eq!((mmc1.read(0x8000), mmc1.read(0xC000)), (Some(2), Some(15)));
+        // The fixed bank moves with the outer bank too.
+        mmc1_write(&mut mmc1, 0xA000, 0x10);
+        assert_eq!((mmc1.read(0x8000), mmc1.read(0xC000)), (Some(18), Some(31)));
+        // Boards of 256KB or less ignore the bit.
+        let prg = numbered_banks(16, Mmc1::ROM_PAGE_SIZE);
+        let mut mmc1 = Mmc1::new(&prg, &[], Mirroring::Horizontal);
+        mmc1_write(&mut mmc1, 0xE000, 0x02);
+        mmc1_write(&mut mmc1, 0xA000, 0x10);
+        assert_eq!((mmc1.read(0x8000), mmc1.read(0xC000)), (Some(2), Some(15)));
+    }
+
+    #[test]
+    fn axrom_switches_32kb_banks_and_the_single_screen() {
+        let prg = numbered_banks(8, Axrom::PRG_BANK_SIZE);
+        let mut axrom = Axrom::new(&prg, &[]);
+        assert_eq!((axrom.read(0x8000), axrom.read(0xFFFF)), (Some(7), Some(7)));
+        assert!(axrom.write(0x8000, 0x12));
+        assert_eq!((axrom.read(0x8000), axrom.read(0xFFFF)), (Some(2), Some(2)));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleB);
+        assert_eq!(axrom.current_banks().prg[0].bank, 2);
+        assert!(axrom.write(0xFFFF, 0x03));
+        assert_eq!(axrom.read(0xC000), Some(3));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleA);
+    }
+
+    #[test]
+    fn gxrom_switches_prg_and_chr_with_one_register() {
+        let prg = numbered_banks(4, Gxrom::PRG_BANK_SIZE);
+        let chr = numbered_banks(4, Gxrom::CHR_BANK_SIZE);
+        let mut gxrom = Gxrom::new(&prg, &chr, Mirroring::Vertical);
+        assert_eq!(
+            (gxrom.read(0x8000), gxrom.ppu_read(0x0000)),
+            (Some(0), Some(0))
+        );
+        assert!(gxrom.write(0x8000, 0x21));
+        assert_eq!(
+            (gxrom.read(0xFFFF), gxrom.ppu_read(0x1FFF)),
+            (Some(2), Some(1))
+        );
+        assert!(gxrom.write(0x8000, 0x13));
+        assert_eq!(
+            (gxrom.read(0x8000), gxrom.ppu_read(0x0000)),
+            (Some(1), Some(3))
+        );
+        let banks = gxrom.current_banks();
+        assert_eq!((banks.prg[0].bank, banks.chr[0].bank), (1, 3));
+        // CHR is ROM.
+        assert!(!gxrom.ppu_write(0x0000, 0x42));
+    }
+
+    #[test]
+    fn mmc1_prg_bank_bit_4_disables_the_work_ram() {
+        let prg = numbered_banks(2, Mmc1::ROM_PAGE_SIZE);
+        let mut cart = Cart::new(MapperEnum::Mmc1(Mmc1::new(
+            &prg,
+            &[],
+            Mirroring::Horizontal,
+        )));
+        let set_prg_bank = |cart: &mut Cart, value: u8| {
+            for bit in 0..5 {
+                CpuBusMember::write(cart, 0xE000, value >> bit & 0x01);
+            }
+        };
+        assert!(CpuBusMember::write(&mut cart, 0x6000, 0x42));
+        set_prg_bank(&mut cart, 0x10);
+        assert_eq!(CpuBusMember::read(&mut cart, 0x6000), None);
+        assert!(!CpuBusMember::write(&mut cart, 0x6000, 0x99));
+        set_prg_bank(&mut cart, 0x00);
+        assert_eq!(CpuBusMember::read(&mut cart, 0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn mapper_irq_reaches_the_cpu() {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        // Keep the APU frame interrupt out of the way.
+        bus.write(0x4017, 0x40);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut initial = state(0, 0, 0, 0x20);
+        initial.prog_counter = 0x8000;
+        cpu.set_state(&initial);
+        let mut step = |bus: &mut CpuMemoryBus| {
+            cpu.step_with_interrupts(bus, CpuMemoryBus::poll_interrupt_lines)
+                .expect("NOP runs");
+            cpu.state()
+        };
+        assert_eq!(step(&mut bus).prog_counter, 0x8001);
+        bus.cart.as_mut().expect("bus has a cart").forced_irq = true;
+        assert!(bus.interrupt_lines().1);
+        // /IRQ is seen from the next cycle on, in time for the NOP's poll.
+        let after = step(&mut bus);
+        assert_eq!((after.prog_counter, after.stack_pointer), (0xEAEA, 0xFA));
+        assert_eq!(bus.peek_u16(0x01FC), 0x8002);
+    }
+
+    #[test]
+    fn chr_goes_through_the_mapper() {
+        // CHR-RAM takes writes, CHR ROM does not.
+        let mut cart = Cart::new(MapperEnum::Nrom(Nrom::new(
+            &[0; 0x4000],
+            &[],
+            Mirroring::Horizontal,
+        )));
+        assert!(cart.ppu_write(0x1234, 0x42));
+        assert_eq!(
+            (cart.ppu_read(0x1234), cart.ppu_peek(0x1234)),
+            (Some(0x42), Some(0x42))
+        );
+        let chr = numbered_banks(1, 0x2000);
+        let mut cart = Cart::new(MapperEnum::Nrom(Nrom::new(
+            &[0; 0x4000],
+            &chr,
+            Mirroring::Horizontal,
+        )));
+        assert!(!cart.ppu_write(0x1234, 0x42));
+        assert_eq!(cart.ppu_read(0x1234), Some(0x00));
+        // Nametables are not the mapper's.
+        assert_eq!(cart.ppu_read(0x2000), None);
+
+        // MMC1 in 4KB mode serves each pattern table from its own bank.
+        let chr = numbered_banks(8, 0x1000);
+        let mut mmc1 = Mmc1::new(&vec![0; 0x8000], &chr, Mirroring::Horizontal);
+        mmc1_write(&mut mmc1, 0x8000, 0x1F);
+        mmc1_write(&mut mmc1, 0xA000, 0x05);
+        mmc1_write(&mut mmc1, 0xC000, 0x02);
+        let mut cart = Cart::new(MapperEnum::Mmc1(mmc1));
+        assert_eq!(
+            (cart.ppu_read(0x0000), cart.ppu_read(0x1FFF)),
+            (Some(5), Some(2))
+        );
+        assert!(!cart.ppu_write(0x0000, 0x42));
+    }
+
+    /// Read unmapped 0x5123 right after reading 0x42 from RAM, in open bus mode `mode`.
+    fn open_bus_read(mode: OpenBusMode) -> u8 {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        bus.set_open_bus_mode(mode);
+        bus.write(0x0010, 0x42);
+        bus.read(0x0010);
+        bus.read(0x5123)
+    }
+
+    #[test]
+    fn open_bus_last_value() {
+        assert_eq!(open_bus_read(OpenBusMode::LastValue), 0x42);
+    }
+
+    #[test]
+    fn open_bus_address_high() {
+        assert_eq!(open_bus_read(OpenBusMode::AddressHigh), 0x51);
+    }
+
+    #[test]
+    fn open_bus_zero() {
+        assert_eq!(open_bus_read(OpenBusMode::Zero), 0x00);
+    }
+
+    #[test]
+    fn open_bus_latch_follows_fetches_and_dummy_reads() {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, 0x24));
+        // NOP's second cycle reads the next byte and throws it away, but it is still
+        // on the bus.
+        exec_bytes(&mut cpu, &mut bus, &[0xEA, 0x77]).expect("NOP runs");
+        assert_eq!(bus.data_bus_latch(), 0x77);
+        // PHP's dummy read is followed by the push.
+        exec_bytes(&mut cpu, &mut bus, &[0x08, 0x77]).expect("PHP runs");
+        assert_eq!(bus.data_bus_latch(), 0x34);
+        // LDA $5123 reads open bus right after fetching the address high byte.
+        exec_bytes(&mut cpu, &mut bus, &[0xAD, 0x23, 0x51]).expect("LDA runs");
+        assert_eq!(cpu.state().a_reg, 0x51);
+    }
+
+    #[test]
+    fn write_only_registers_read_back_open_bus() {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        // The PPU has its own data bus, whose latch holds the last value written to any
+        // of its registers.
+        bus.write(0x2003, 0x5A);
+        bus.write(0x0010, 0x42);
+        bus.read(0x0010);
+        for address in [0x2000, 0x2001, 0x2003, 0x2005, 0x2006, 0x3FF8] {
+            assert_eq!(bus.read(address), 0x5A, "0x{address:04x}");
+        }
+        // The APU drives nothing, so the CPU's data bus latch comes back.
+        bus.read(0x0010);
+        for address in [0x4000, 0x4008, 0x4013] {
+            assert_eq!(bus.read(address), 0x42, "0x{address:04x}");
+        }
+    }
+
+    #[test]
+    fn io_ports_answer_without_their_devices() {
+        let mut bus = CpuMemoryBus::builder().with_ram(Ram::new()).build();
+        bus.write(0x0010, 0x42);
+        bus.read(0x0010);
+        assert_eq!(bus.read(0x4014), 0x42);
+        // Bits 0-4 are driven low by the empty ports.
+        assert_eq!(bus.read(0x4016), 0x40);
+        assert_eq!(bus.read(0x4017), 0x40);
+        assert!(bus.is_mapped(0x4016));
+        bus.write(0x4016, 0x01);
+        bus.write(0x4017, 0x40);
+        // OAM DMA still takes its cycles, with nowhere to copy to.
+        if bus.odd_cycle() {
+            bus.read(0x0010);
+        }
+        let start = bus.cycles();
+        bus.write(0x4014, 0x00);
+        assert_eq!(bus.cycles() - start, 1 + 514);
+    }
+
+    /// The status byte pushed by `program` at 0x0200, run from status 0xC3 (N, V, Z and
+    /// C set) with the interrupt lines as `lines` reports them.
+    fn pushed_status(program: &[u8], lines: (bool, bool)) -> u8 {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x0200, program);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, 0xC3));
+        cpu.step_with_interrupts(&mut bus, |_| lines)
+            .expect("program runs");
+        assert_eq!(
+            cpu.state().stack_pointer,
+            if program[0] == 0x08 { 0xFC } else { 0xFA }
+        );
+        bus.peek(u16::from(cpu.state().stack_pointer) + 0x0101)
+    }
+
+    #[test]
+    fn b_flag_is_only_pushed_by_php_and_brk() {
+        assert_eq!(pushed_status(&[0x08], (false, false)), 0xF3);
+        assert_eq!(pushed_status(&[0x00, 0x00], (false, false)), 0xF3);
+        assert_eq!(pushed_status(&[0xEA], (false, true)), 0xE3);
+        assert_eq!(pushed_status(&[0xEA], (true, false)), 0xE3);
+    }
+
+    #[test]
+    fn plp_leaves_bits_4_and_5_alone() {
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        for (before, pulled, after) in [(0x00, 0xFF, 0xEF), (0xFF, 0x00, 0x30)] {
+            cpu.set_state(&state(0, 0, 0, before));
+            bus.load(0x01FE, &[pulled]);
+            exec_bytes(&mut cpu, &mut bus, &[0x28]).expect("PLP runs");
+            assert_eq!(cpu.state().status, after, "{pulled:02x}");
+        }
+    }
+
+    #[test]
+    fn nmi_is_edge_triggered_and_irq_level_triggered() {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x0200, &[0xEA; 8]);
+        bus.load(0x9000, &[0x40]);
+        bus.load(0xA000, &[0x40]);
+        bus.load(0xFFFA, &[0x00, 0x90]);
+        bus.load(0xFFFE, &[0x00, 0xA0]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut step = |cpu: &mut Cpu, lines: (bool, bool)| {
+            cpu.step_with_interrupts(&mut bus, |_| lines)
+                .expect("program runs");
+            cpu.state().prog_counter
+        };
+
+        cpu.set_state(&state(0, 0, 0, 0x24));
+        assert_eq!(step(&mut cpu, (true, false)), 0x9000);
+        // /NMI held low through the RTI and after is the same NMI.
+        assert_eq!(step(&mut cpu, (true, false)), 0x0201);
+        assert_eq!(step(&mut cpu, (true, false)), 0x0202);
+        assert_eq!(step(&mut cpu, (false, false)), 0x0203);
+        assert_eq!(step(&mut cpu, (true, false)), 0x9000);
+
+        // /IRQ held low interrupts again as soon as RTI clears I.
+        cpu.set_state(&state(0, 0, 0, 0x20));
+        assert_eq!(step(&mut cpu, (false, true)), 0xA000);
+        assert_eq!(step(&mut cpu, (false, true)), 0xA000);
+        assert_eq!(step(&mut cpu, (false, false)), 0x0201);
+    }
+
+    #[test]
+    fn indirect_y_page_cross_reads_the_uncarried_address_first() {
+        use Access::{Read, Write};
+        let memory = [(0x10, 0xF0), (0x11, 0x02)];
+        assert_eq!(
+            accesses_of(&[0xB1, 0x10], state(0, 0, 0x20, 0x24), &memory),
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0010),
+                Read(0x0011),
+                Read(0x0210),
+                Read(0x0310)
+            ],
+            "LDA"
+        );
+        // Stores always take the extra read, carry or not.
+        assert_eq!(
+            accesses_of(&[0x91, 0x10], state(0x42, 0, 0x20, 0x24), &memory),
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0010),
+                Read(0x0011),
+                Read(0x0210),
+                Write(0x0310, 0x42)
+            ],
+            "STA"
+        );
+        assert_eq!(
+            accesses_of(&[0x91, 0x10], state(0x42, 0, 0x05, 0x24), &memory),
+            [
+                Read(0x0200),
+                Read(0x0201),
+                Read(0x0010),
+                Read(0x0011),
+                Read(0x02F5),
+                Write(0x02F5, 0x42)
+            ],
+            "STA"
+        );
+    }
+
+    #[test]
+    fn indirect_pointers_wrap_within_the_zero_page() {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x0000, &[0x12]);
+        bus.load(0x00FF, &[0x34, 0x99]);
+        bus.load(0x0010, &[0x78, 0x56]);
+        bus.load(0x1234, &[0x42]);
+        bus.load(0x5678, &[0x24]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        for (bytes, x_reg, expected) in [
+            // LDA ($FF),Y: the high byte comes from 0x0000, not 0x0100.
+            ([0xB1, 0xFF], 0x00, 0x42),
+            // LDA ($F0,X) with X = 0x0F points at 0xFF too.
+            ([0xA1, 0xF0], 0x0F, 0x42),
+            // LDA ($80,X) with X = 0x90 wraps to 0x10.
+            ([0xA1, 0x80], 0x90, 0x24),
+        ] {
+            cpu.set_state(&state(0, x_reg, 0, 0x24));
+            exec_bytes(&mut cpu, &mut bus, &bytes).expect("LDA runs");
+            assert_eq!(cpu.state().a_reg, expected, "{bytes:02X?}");
+        }
+    }
+
+    #[test]
+    fn branches_take_2_3_or_4_cycles() {
+        const Z: u8 = CpuStatusFlags::ZERO.bits();
+        for (pc, offset, status, cycles, target) in [
+            (0x0200, 0x10, 0x24 | Z, 2, 0x0202),
+            (0x0200, 0x10, 0x24, 3, 0x0212),
+            (0x02F0, 0x20, 0x24, 4, 0x0312),
+            (0x0200, 0xF0, 0x24, 4, 0x01F2),
+        ] {
+            let mut bus = FlatMemoryBus::new();
+            let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+            let mut setup = state(0, 0, 0, status);
+            setup.prog_counter = pc;
+            cpu.set_state(&setup);
+            // BNE
+            let result = exec_bytes(&mut cpu, &mut bus, &[0xD0, offset]).expect("BNE runs");
+            assert_eq!(
+                (result.cycles, cpu.prog_counter()),
+                (cycles, target),
+                "BNE {offset:02X} at {pc:04X}"
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_flag_leaves_adc_and_sbc_binary() {
+        const D: u8 = CpuStatusFlags::DECIMAL.bits();
+        const C: u8 = CpuStatusFlags::CARRY.bits();
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        // 09 + 01 is 0A, where a BCD adder would give 10.
+        assert_eq!(
+            run_immediate(&mut cpu, &mut bus, 0x69, 0x09, 0x01, 0x24 | D),
+            (0x0A, 0x24 | D)
+        );
+        // 10 - 01 is 0F, where a BCD subtractor would give 09.
+        assert_eq!(
+            run_immediate(&mut cpu, &mut bus, 0xE9, 0x10, 0x01, 0x24 | D | C),
+            (0x0F, 0x24 | D | C)
+        );
+    }
+
+    #[test]
+    fn jam_stays_on_its_opcode() {
+        for opcode in [
+            0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2,
+        ] {
+            let mut bus = FlatMemoryBus::new();
+            bus.load(0x0200, &[opcode, 0xEA]);
+            let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+            cpu.set_state(&state(0, 0, 0, 0x24));
+            for _ in 0..2 {
+                assert_eq!(
+                    cpu.run_instr(&mut bus),
+                    Err(CpuError::Jammed { pc: 0x0200 }),
+                    "{opcode:02X}"
+                );
+                assert_eq!(cpu.state(), state(0, 0, 0, 0x24), "{opcode:02X}");
+            }
+        }
+    }
+
+    #[test]
+    fn disabled_illegal_opcodes_are_errors() {
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_allow_illegal_opcodes(false);
+        cpu.set_state(&state(0, 0, 0, 0x24));
+        // LAX $10 is not run, and PC stays on it.
+        assert_eq!(
+            exec_bytes(&mut cpu, &mut bus, &[0xA7, 0x10]),
+            Err(CpuError::IllegalOpcode {
+                pc: 0x0200,
+                opcode: 0xA7
+            })
+        );
+        assert_eq!(cpu.state(), state(0, 0, 0, 0x24));
+        // Official ones still run.
+        exec_bytes(&mut cpu, &mut bus, &[0xA5, 0x10]).expect("LDA runs");
+        cpu.set_allow_illegal_opcodes(true);
+        exec_bytes(&mut cpu, &mut bus, &[0xA7, 0x10]).expect("LAX runs");
+    }
+
+    #[test]
+    fn running_into_unmapped_memory_is_an_error() {
+        // Without a PPU, nothing answers past the last RAM mirror.
+        let mut bus = CpuMemoryBus::builder().with_ram(Ram::new()).build();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut setup = state(0, 0, 0, 0x24);
+        setup.prog_counter = 0x1FFF;
+        cpu.set_state(&setup);
+        exec_bytes(&mut cpu, &mut bus, &[0xEA]).expect("NOP runs");
+        assert_eq!(
+            cpu.run_instr(&mut bus),
+            Err(CpuError::RanOffMap { pc: 0x2000 })
+        );
+        assert_eq!(cpu.prog_counter(), 0x2000);
+    }
+
+    /// Run `bytes` at 0x0200 on a flat bus, with the CPU set up by `setup`, returning the
+    /// error and its message.
+    fn cpu_error(bytes: &[u8], setup: impl FnOnce(&mut Cpu)) -> (CpuError, String) {
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, 0x24));
+        setup(&mut cpu);
+        let error = exec_bytes(&mut cpu, &mut bus, bytes).expect_err("instruction fails");
+        (error, error.to_string())
+    }
+
+    #[test]
+    fn cpu_error_unknown_opcode() {
+        // Every opcode is implemented now, so nothing the CPU runs gives this one.
+        let error = CpuError::UnknownOpcode {
+            pc: 0x1234,
+            opcode: 0x02,
+        };
+        assert_eq!(error.pc(), 0x1234);
+        assert_eq!(error.to_string(), "Unknown opcode 0x02 at 0x1234");
+    }
+
+    #[test]
+    fn cpu_error_ran_off_map() {
+        let mut bus = CpuMemoryBus::builder().with_ram(Ram::new()).build();
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut setup = state(0, 0, 0, 0x24);
+        setup.prog_counter = 0x5000;
+        cpu.set_state(&setup);
+        let error = cpu.run_instr(&mut bus).expect_err("nothing at 0x5000");
+        assert_eq!(error, CpuError::RanOffMap { pc: 0x5000 });
+        assert_eq!(error.to_string(), "PC ran into unmapped memory at 0x5000");
+    }
+
+    #[test]
+    fn cpu_error_illegal_opcode() {
+        let (error, message) = cpu_error(&[0x07, 0x10], |cpu| cpu.set_allow_illegal_opcodes(false));
+        assert_eq!(
+            error,
+            CpuError::IllegalOpcode {
+                pc: 0x0200,
+                opcode: 0x07
+            }
+        );
+        assert_eq!(message, "Unofficial opcode 0x07 at 0x0200 is disabled");
+    }
+
+    #[test]
+    fn cpu_error_jammed() {
+        let (error, message) = cpu_error(&[0x02], |_| {});
+        assert_eq!(error, CpuError::Jammed { pc: 0x0200 });
+        assert_eq!(message, "CPU jammed at 0x0200");
+    }
+
+    #[test]
+    fn cpu_error_rom_write() {
+        // Raised by `Nes::step` in `RomWriteMode::Trap`, see the tests there.
+        let error = CpuError::RomWrite {
+            pc: 0x8000,
+            address: 0x8123,
+            data: 0x42,
+        };
+        assert_eq!(error.pc(), 0x8000);
+        assert_eq!(
+            error.to_string(),
+            "Instruction at 0x8000 wrote 0x42 to ROM at 0x8123"
+        );
+    }
+
+    #[test]
+    fn cpu_error_stack_overflow() {
+        // PHA with SP at 0x00.
+        let (error, message) = cpu_error(&[0x48], |cpu| {
+            cpu.set_stack_check(true);
+            cpu.stack_pointer = 0x00;
+        });
+        assert_eq!(error, CpuError::StackOverflow { pc: 0x0200 });
+        assert_eq!(
+            message,
+            "Stack overflow: SP wrapped from 0x00 to 0xff at 0x0200"
+        );
+    }
+
+    #[test]
+    fn cpu_error_stack_underflow() {
+        // PLA with SP at 0xFF.
+        let (error, message) = cpu_error(&[0x68], |cpu| {
+            cpu.set_stack_check(true);
+            cpu.stack_pointer = 0xFF;
+        });
+        assert_eq!(error, CpuError::StackUnderflow { pc: 0x0200 });
+        assert_eq!(
+            message,
+            "Stack underflow: SP wrapped from 0xff to 0x00 at 0x0200"
+        );
+    }
+
+    #[test]
+    fn run_until_stops_on_the_predicate_or_the_limit() {
+        let mut bus = FlatMemoryBus::new();
+        // loop: INX; JMP loop
+        bus.load(0x0200, &[0xE8, 0x4C, 0x00, 0x02]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_state(&state(0, 0, 0, 0x24));
+        assert_eq!(
+            cpu.run_until(&mut bus, |cpu| cpu.state().x_reg == 3, 100),
+            Ok(RunOutcome::Stopped { instructions: 5 })
+        );
+        assert_eq!(cpu.prog_counter(), 0x0201);
+
+        cpu.set_state(&state(0, 0, 0, 0x24));
+        assert_eq!(
+            cpu.run_until(&mut bus, |cpu| cpu.prog_counter() == 0x0300, 10),
+            Ok(RunOutcome::LimitReached)
+        );
+        // The CPU is left where the limit caught it.
+        assert_eq!((cpu.state().x_reg, cpu.prog_counter()), (5, 0x0200));
+    }
+
+    #[test]
+    fn reset_warns_about_a_vector_into_open_bus() {
+        let mut prg = vec![0xEA; 0x4000];
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x50]);
+        let cart = Cart::new(MapperEnum::Nrom(Nrom::new(
+            &prg,
+            &[],
+            Mirroring::Horizontal,
+        )));
+        let mut bus = CpuMemoryBus::new(cart);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        assert_eq!(
+            cpu.reset(&mut bus),
+            Some(ResetWarning::UnmappedVector { target: 0x5000 })
+        );
+        assert_eq!(cpu.prog_counter(), 0x5000);
+        assert_eq!(
+            ResetWarning::UnmappedVector { target: 0x5000 }.to_string(),
+            "Reset vector points to unmapped memory at 0x5000"
+        );
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        assert_eq!(cpu.reset(&mut bus), None);
+    }
+
+    /// Cycles taken by `STA $4014` once `prefix` has run from an even cycle, both from
+    /// RAM at 0x0300, and whether the bus was on an odd cycle before the store.
+    fn oam_dma_store_after(prefix: &[u8]) -> (u64, bool) {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut setup = state(0x02, 0, 0, 0x24);
+        setup.prog_counter = 0x0300;
+        cpu.set_state(&setup);
+        for (address, &byte) in (0x0300..).zip(prefix.iter().chain(&[0x8D, 0x14, 0x40])) {
+            bus.write(address, byte);
+        }
+        if bus.odd_cycle() {
+            bus.read(0x0000);
+        }
+        cpu.run_instr(&mut bus).expect("prefix runs");
+        let odd = bus.odd_cycle();
+        let result = cpu.run_instr(&mut bus).expect("STA runs");
+        (result.cycles, odd)
+    }
+
+    #[test]
+    fn oam_dma_stall_follows_the_cycle_parity() {
+        // NOP keeps the parity, LDA $00 flips it.
+        let (after_nop, odd_after_nop) = oam_dma_store_after(&[0xEA]);
+        let (after_lda, odd_after_lda) = oam_dma_store_after(&[0xA5, 0x00]);
+        assert_ne!(odd_after_nop, odd_after_lda);
+        // STA takes an even 4 cycles, so the DMA starts on the parity the store did and
+        // needs an extra cycle to align when that is odd.
+        let expected = |odd_before: bool| if odd_before { 4 + 514 } else { 4 + 513 };
+        assert_eq!(after_nop, expected(odd_after_nop));
+        assert_eq!(after_lda, expected(odd_after_lda));
+    }
+
+    #[test]
+    fn cart_new_wraps_a_mapper_with_work_ram() {
+        let prg: Vec<u8> = (0..0x4000_u16).map(|i| (i >> 8) as u8).collect();
+        let mut cart = Cart::new(MapperEnum::Nrom(Nrom::new(&prg, &[], Mirroring::Vertical)));
+        assert_eq!(cart.read(0x8100), Some(0x01));
+        // 16KB of PRG ROM shows up twice.
+        assert_eq!(cart.read(0xC100), Some(0x01));
+        assert_eq!(cart.mirroring(), Mirroring::Vertical);
+        assert_eq!(cart.wram().len(), Cart::WRAM_SIZE);
+        assert!(cart.write(0x6001, 0x5A));
+        assert_eq!(cart.read(0x6001), Some(0x5A));
+        // CHR-RAM when there is no CHR ROM.
+        assert!(cart.ppu_write(0x0010, 0x77));
+        assert_eq!(cart.ppu_peek(0x0010), Some(0x77));
+
+        let mut cart = Cart::new(MapperEnum::Nrom(Nrom::new(&prg, &[], Mirroring::Vertical)))
+            .with_wram_size(0);
+        assert!(cart.wram().is_empty());
+        assert_eq!(cart.read(0x6001), None);
+        assert!(!cart.write(0x6001, 0x5A));
+    }
+
+    #[test]
+    fn cart_from_header_picks_the_mapper() {
+        let mut header = *b"NES\x1A\x01\x00\x70\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        let cart = Cart::from_header_and_data(
+            &RomHeader::parse(&header).expect("valid header"),
+            &[0; 0x4000],
+            &[],
+        )
+        .expect("AxROM is supported");
+        assert!(matches!(cart.mapper, MapperEnum::Axrom(_)));
+        header[6] = 0x20;
+        assert!(matches!(
+            Cart::from_header_and_data(
+                &RomHeader::parse(&header).expect("valid header"),
+                &[0; 0x4000],
+                &[],
+            ),
+            Err(RomLoadError::UnsupportedMapper(2))
+        ));
+    }
+
+    /// 4KB of RAM mirrored over the whole address space, and nothing else.
+    struct MirroredBus {
+        memory: [u8; 0x1000],
+        cycles: u64,
+    }
+
+    impl Bus for MirroredBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.cycles += 1;
+            self.peek(address)
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.cycles += 1;
+            self.memory[usize::from(address) % 0x1000] = data;
+        }
+
+        fn peek(&mut self, address: u16) -> u8 {
+            self.memory[usize::from(address) % 0x1000]
+        }
+
+        fn cycles(&self) -> u64 {
+            self.cycles
+        }
+    }
+
+    #[test]
+    fn cpu_runs_on_any_bus() {
+        let mut bus = MirroredBus {
+            memory: [0; 0x1000],
+            cycles: 0,
+        };
+        // LDX #$03; loop: TXA; STA $10,X; DEX; BNE loop; JMP *.
+        let program = [
+            0xA2, 0x03, 0x8A, 0x95, 0x10, 0xCA, 0xD0, 0xFA, 0x4C, 0x08, 0x04,
+        ];
+        bus.memory[0x400..0x400 + program.len()].copy_from_slice(&program);
+        // The reset vector at 0xFFFC lands on 0x0FFC.
+        bus.memory[0xFFC..].copy_from_slice(&[0x00, 0x04, 0x00, 0x00]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        assert_eq!(cpu.reset(&mut bus), None);
+        assert_eq!(cpu.prog_counter, 0x0400);
+        assert_eq!(
+            crate::testing::run_until_trap(&mut cpu, &mut bus, 1000),
+            Some(0x0408)
+        );
+        assert_eq!(bus.memory[0x11..0x14], [1, 2, 3]);
+        assert_eq!(cpu.state().x_reg, 0);
+        // Reset, LDX, 3 loops of 2 + 4 + 2 + 3 less the untaken branch, then the JMP.
+        assert_eq!(bus.cycles(), 7 + 2 + 3 * 11 - 1 + 3);
+    }
+
+    #[test]
+    fn cpu_drives_the_ppu_on_an_injected_bus() {
+        let mut ppu = Ppu::new();
+        // PPUADDR would drop the writes below during the warm-up.
+        ppu.skip_warm_up();
+        let mut bus = CpuMemoryBusBuilder::new()
+            .with_ram(Ram::new())
+            .with_ppu(ppu)
+            .build();
+        let program = crate::testing::assemble(
+            0x0200,
+            "
+                LDA #$3F
+                STA $2006
+                LDA #$01
+                STA $2006
+                LDA #$2A
+                STA $2007
+                LDA #$3F
+                STA $2006
+                LDA #$01
+                STA $2006
+                LDA $2007
+                STA $00
+            ",
+        )
+        .expect("assembles");
+        for (address, &byte) in (0x0200..).zip(&program) {
+            bus.write(address, byte);
+        }
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.prog_counter = 0x0200;
+        for _ in 0..12 {
+            cpu.run_instr(&mut bus).expect("runs");
+        }
+        assert_eq!(bus.ppu().expect("PPU").palette()[1], 0x2A);
+        // Palette reads skip the PPUDATA buffer.
+        assert_eq!(bus.peek(0x0000), 0x2A);
+    }
+
+    #[test]
+    fn word_reads_are_little_endian() {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x1234, &[0xCD, 0xAB]);
+        bus.load(0xFFFF, &[0x11]);
+        bus.load(0x0000, &[0x22]);
+        bus.load(0x00FF, &[0x33]);
+        assert_eq!(bus.read_u16(0x1234), 0xABCD);
+        assert_eq!(bus.peek_u16(0x1234), 0xABCD);
+        // A word at the top of memory wraps to 0x0000.
+        assert_eq!(bus.read_u16(0xFFFF), 0x2211);
+        // One in the zero page wraps within it.
+        assert_eq!(bus.read_u16_zp(0xFF), 0x2233);
+        assert_eq!(bus.cycles(), 6, "peeks are free");
+    }
+
+    #[test]
+    fn cpu_new_starts_in_the_power_on_state() {
+        let expected = CpuState {
+            a_reg: 0,
+            x_reg: 0,
+            y_reg: 0,
+            prog_counter: 0,
+            stack_pointer: 0xFF,
+            status: 0x34,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_line: false,
+        };
+        assert_eq!(Cpu::new().state(), expected);
+        assert_eq!(Cpu::default().state(), expected);
+        // No bus is needed until the first reset.
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0xFFFC, &[0x34, 0x12]);
+        cpu.reset(&mut bus);
+        assert_eq!(cpu.prog_counter(), 0x1234);
+    }
+
+    #[test]
+    fn vectors_peek_the_top_of_memory() {
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0xFFFA, &[0x00, 0x90, 0x00, 0x80, 0x50, 0xA0]);
+        let cpu = Cpu::new();
+        assert_eq!(
+            cpu.vectors(&mut bus),
+            Vectors {
+                nmi: 0x9000,
+                reset: 0x8000,
+                irq: 0xA050,
+            }
+        );
+        assert_eq!(bus.cycles(), 0);
+    }
+
+    #[test]
+    fn current_instruction_leaves_the_cpu_and_bus_alone() {
+        let mut bus = FlatMemoryBus::new();
+        // LDA $1234,X
+        bus.load(0x0300, &[0xBD, 0x34, 0x12]);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.set_prog_counter(0x0300);
+        let before = cpu.state();
+        assert_eq!(
+            cpu.current_instruction(&mut bus),
+            ("LDA $1234,X".to_string(), 3)
+        );
+        assert_eq!(cpu.current_instruction(&mut bus).0, "LDA $1234,X");
+        assert_eq!(cpu.state(), before);
+        assert_eq!(bus.cycles(), 0);
+        cpu.run_instr(&mut bus).expect("LDA runs");
+        assert_eq!(cpu.prog_counter(), 0x0303);
+    }
+
+    #[test]
+    fn cart_summary_names_the_board_and_its_sizes() {
+        let summary = |mapper| Cart::new(mapper).summary();
+        assert_eq!(
+            summary(MapperEnum::Nrom(Nrom::new(
+                &[0; 0x4000],
+                &[0; 0x2000],
+                Mirroring::Vertical
+            ))),
+            "NROM: 16KB PRG ROM, 8KB CHR ROM, Vertical mirroring"
+        );
+        assert_eq!(
+            summary(MapperEnum::Mmc1(Mmc1::new(
+                &vec![0; 0x20000],
+                &[],
+                Mirroring::Horizontal
+            ))),
+            "MMC1: 8 x 16KB PRG ROM, 8KB CHR RAM, Horizontal mirroring"
+        );
+        let mut axrom = Cart::new(MapperEnum::Axrom(Axrom::new(&vec![0; 0x20000], &[])));
+        assert!(axrom.write(0x8000, 0x12));
+        assert_eq!(
+            axrom.summary(),
+            "AxROM: 4 x 32KB PRG ROM, 8KB CHR RAM, bank 2, SingleB mirroring"
+        );
+        let mut gxrom = Cart::new(MapperEnum::Gxrom(Gxrom::new(
+            &vec![0; 0x10000],
+            &vec![0; 0x8000],
+            Mirroring::Horizontal,
+        )));
+        assert!(gxrom.write(0x8000, 0x13));
+        assert_eq!(
+            gxrom.summary(),
+            "GxROM: 2 x 32KB PRG ROM, 4 x 8KB CHR ROM, banks 1/3, Horizontal mirroring"
+        );
+    }
+
+    #[test]
+    fn applied_cheats_change_the_value_read() {
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        let (address, value, compare) =
+            crate::game_genie::decode_game_genie("SXIOPO").expect("valid code");
+        bus.add_cheat(address, value, compare);
+        assert_eq!(bus.read(0x91D9), 0xAD);
+        assert_eq!(bus.peek(0x91D9), 0xAD);
+        assert_eq!(bus.read(0x91DA), 0xEA);
+        // The CPU sees the patched byte too: LDA $91D9.
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.prog_counter = 0x0200;
+        exec_bytes(&mut cpu, &mut bus, &[0xAD, 0xD9, 0x91]).expect("LDA runs");
+        assert_eq!(cpu.state().a_reg, 0xAD);
+
+        // A compare value must match what is really there.
+        bus.add_cheat(0x8000, 0x42, Some(0x00));
+        bus.add_cheat(0x8001, 0x42, Some(0xEA));
+        assert_eq!(bus.read(0x8000), 0xEA);
+        assert_eq!(bus.read(0x8001), 0x42);
+
+        bus.clear_cheats();
+        assert_eq!(bus.read(0x91D9), 0xEA);
+        assert_eq!(bus.read(0x8001), 0xEA);
+    }
+
+    #[test]
+    fn current_banks_follow_bank_switches() {
+        let window = |start, size, bank| BankWindow { start, size, bank };
+        // 16KB of NROM shows up in both halves.
+        let nrom = Cart::new(MapperEnum::Nrom(Nrom::new(
+            &[0; 0x4000],
+            &[0; 0x2000],
+            Mirroring::Vertical,
+        )));
+        assert_eq!(
+            nrom.current_banks(),
+            BankState {
+                prg: vec![window(0x8000, 0x4000, 0), window(0xC000, 0x4000, 0)],
+                chr: vec![window(0x0000, 0x2000, 0)],
+                mirroring: Mirroring::Vertical,
+            }
+        );
+
+        let mut mmc1 = Cart::new(MapperEnum::Mmc1(Mmc1::new(
+            &numbered_banks(8, Mmc1::ROM_PAGE_SIZE),
+            &numbered_banks(8, Mmc1::CHR_BANK_SIZE),
+            Mirroring::Horizontal,
+        )));
+        let mut serial_write = |address, value: u8| {
+            for bit in 0..5 {
+                mmc1.write(address, value >> bit & 0x01);
+            }
+        };
+        // Vertical mirroring, PRG bank at 0x8000 with the last one fixed, 4KB CHR banks.
+        serial_write(0x8000, 0x1E);
+        serial_write(0xA000, 0x03);
+        serial_write(0xC000, 0x05);
+        serial_write(0xE000, 0x02);
+        let banks = mmc1.current_banks();
+        assert_eq!(
+            banks,
+            BankState {
+                prg: vec![window(0x8000, 0x4000, 2), window(0xC000, 0x4000, 7)],
+                chr: vec![window(0x0000, 0x1000, 3), window(0x1000, 0x1000, 5)],
+                mirroring: Mirroring::Vertical,
+            }
+        );
+        // The windows say what reads return.
+        assert_eq!(mmc1.read(0x8000), Some(2));
+        assert_eq!(mmc1.read(0xC000), Some(7));
+        assert_eq!(mmc1.ppu_peek(0x0000), Some(3));
+        assert_eq!(mmc1.ppu_peek(0x1000), Some(5));
+    }
+
+    #[test]
+    fn controller_reads_leave_the_expansion_bits_low_and_bits_5_to_7_open() {
+        use crate::controller::Buttons;
+
+        let mut bus = CpuMemoryBus::new(nrom_cart(0xEA));
+        bus.controller_mut(0)
+            .expect("controller in port 1")
+            .set_buttons(Buttons::A | Buttons::START);
+        let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+        cpu.prog_counter = 0x0200;
+        // Strobe with STA $4016 while A is 1 then 0.
+        for strobe in [0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40].chunks(5) {
+            exec_bytes(&mut cpu, &mut bus, &strobe[..2]).expect("LDA runs");
+            exec_bytes(&mut cpu, &mut bus, &strobe[2..]).expect("STA runs");
+        }
+        // LDA $4016: the high byte of the address is the last value on the bus.
+        let reads: Vec<u8> = (0..9)
+            .map(|_| {
+                exec_bytes(&mut cpu, &mut bus, &[0xAD, 0x16, 0x40]).expect("LDA runs");
+                cpu.state().a_reg
+            })
+            .collect();
+        assert_eq!(
+            reads,
+            [0x41, 0x40, 0x40, 0x41, 0x40, 0x40, 0x40, 0x40, 0x41]
+        );
+        // After some other value on the bus, bits 5-7 follow it while 1-4 stay low.
+        bus.write(0x0010, 0xFF);
+        bus.read(0x0010);
+        assert_eq!(bus.read(0x4016), 0xE1);
+        bus.read(0x0010);
+        assert_eq!(bus.read(0x4017), 0xE0);
+    }
+}
@@ -0,0 +1,836 @@
+use std::collections::VecDeque;
+
+use crate::rom::{self, RomHeader, RomLoadError};
+use crate::state::{self, Persist, StateError, StateReader, StateWriter};
+use crate::{
+    Bus, Cart, Cpu, CpuError, CpuMemoryBus, CpuState, Ram, Region, ResetWarning, StepResult,
+};
+
+/// The whole machine at one point in time, to go back to with [`Nes::restore`].
+#[derive(Clone)]
+pub struct SaveState {
+    cpu: CpuState,
+    bus: CpuMemoryBus,
+}
+
+impl SaveState {
+    #[must_use]
+    pub const fn cpu(&self) -> &CpuState {
+        &self.cpu
+    }
+
+    #[must_use]
+    pub const fn bus(&self) -> &CpuMemoryBus {
+        &self.bus
+    }
+
+    /// The RAM areas worth comparing, with the CPU address they start at.
+    fn memory(&self) -> [(u16, Option<&[u8]>); 2] {
+        [
+            (0x0000, self.bus.ram().map(Ram::contents)),
+            (0x6000, self.bus.cart().map(Cart::wram)),
+        ]
+    }
+}
+
+/// One difference found by [`diff_state`], with the value in the first state then the
+/// one in the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateDiff {
+    Register { name: &'static str, a: u16, b: u16 },
+    Memory { address: u16, a: u8, b: u8 },
+}
+
+/// List the CPU registers, and the bytes of internal RAM and cartridge work RAM, that
+/// differ between `a` and `b`, registers first then by address. Memory missing from
+/// one of the states is not compared.
+#[must_use]
+pub fn diff_state(a: &SaveState, b: &SaveState) -> Vec<StateDiff> {
+    let registers = |state: &CpuState| {
+        [
+            ("A", u16::from(state.a_reg)),
+            ("X", u16::from(state.x_reg)),
+            ("Y", u16::from(state.y_reg)),
+            ("PC", state.prog_counter),
+            ("SP", u16::from(state.stack_pointer)),
+            ("P", u16::from(state.status)),
+        ]
+    };
+    let mut diffs: Vec<StateDiff> = registers(&a.cpu)
+        .into_iter()
+        .zip(registers(&b.cpu))
+        .filter(|((_, a), (_, b))| a != b)
+        .map(|((name, a), (_, b))| StateDiff::Register { name, a, b })
+        .collect();
+
+    for ((start, a), (_, b)) in a.memory().into_iter().zip(b.memory()) {
+        let (Some(a), Some(b)) = (a, b) else {
+            continue;
+        };
+        diffs.extend(
+            (start..=u16::MAX)
+                .zip(a.iter().zip(b))
+                .filter(|(_, (a, b))| a != b)
+                .map(|(address, (&a, &b))| StateDiff::Memory { address, a, b }),
+        );
+    }
+    diffs
+}
+
+/// A whole console: the CPU and everything on its bus.
+pub struct Nes {
+    cpu: Cpu,
+    bus: CpuMemoryBus,
+    region: Region,
+    speed: f32,
+    /// Cycles `run_frame` still owes (or has overrun, when negative), so fractional
+    /// frame lengths and instructions straddling a frame boundary even out.
+    cycle_budget: f64,
+    /// One state per frame, oldest first, for `rewind`.
+    rewind_states: VecDeque<SaveState>,
+    rewind_capacity: usize,
+    reset_warning: Option<ResetWarning>,
+}
+
+impl Nes {
+    /// Plug `cart` in and power on, running the reset sequence. A reset vector leading
+    /// nowhere is only warned about, see [`Nes::reset_warning`].
+    #[must_use]
+    pub fn new(cart: Cart) -> Self {
+        let mut bus = CpuMemoryBus::new(cart);
+        let mut cpu = Cpu::new();
+        let reset_warning = cpu.reset(&mut bus);
+        Self {
+            cpu,
+            bus,
+            region: Region::Ntsc,
+            speed: 1.0,
+            cycle_budget: 0.0,
+            rewind_states: VecDeque::new(),
+            rewind_capacity: 0,
+            reset_warning,
+        }
+    }
+
+    /// Load a whole iNES file and power on.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the header is invalid, the file is shorter than the header says, or the
+    /// mapper is not supported.
+    pub fn from_rom_bytes(data: &[u8]) -> Result<Self, RomLoadError> {
+        let (header_bytes, mut rest) = data
+            .split_first_chunk::<{ RomHeader::SIZE }>()
+            .ok_or(RomLoadError::UnexpectedEnd)?;
+        let mut header = RomHeader::parse(header_bytes)?;
+        let mut take = |len: usize| {
+            let (chunk, tail) = rest
+                .split_at_checked(len)
+                .ok_or(RomLoadError::UnexpectedEnd)?;
+            rest = tail;
+            Ok(chunk)
+        };
+        let trainer = take(header.trainer_size())?;
+        let prg_rom_data = take(header.prg_rom_size)?;
+        let chr_rom_data = take(header.chr_rom_size)?;
+        if let Some(fix) = rom::lookup_rom(prg_rom_data, chr_rom_data) {
+            fix.apply(&mut header);
+        }
+        let mut cart = Cart::from_header_and_data(&header, prg_rom_data, chr_rom_data)?;
+        cart.load_trainer(trainer);
+        let mut nes = Self::new(cart);
+        nes.set_region(header.region);
+        Ok(nes)
+    }
+
+    /// What was suspicious about the power-on reset, if anything, see `Cpu::reset`.
+    #[must_use]
+    pub const fn reset_warning(&self) -> Option<ResetWarning> {
+        self.reset_warning
+    }
+
+    #[must_use]
+    pub const fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub const fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    #[must_use]
+    pub const fn bus(&self) -> &CpuMemoryBus {
+        &self.bus
+    }
+
+    pub const fn bus_mut(&mut self) -> &mut CpuMemoryBus {
+        &mut self.bus
+    }
+
+    /// CPU cycles elapsed since power-on. The APU is clocked once per CPU cycle, so this
+    /// is its cycle count too; see `ppu_cycles` for the PPU.
+    #[must_use]
+    pub fn master_cycles(&self) -> u64 {
+        self.bus.cycles()
+    }
+
+    /// PPU dots elapsed since power-on, three per CPU cycle on NTSC and 3.2 on PAL.
+    #[must_use]
+    pub fn ppu_cycles(&self) -> u64 {
+        self.region.ppu_dots(self.master_cycles())
+    }
+
+    #[must_use]
+    pub const fn region(&self) -> Region {
+        self.region
+    }
+
+    pub const fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.bus.set_region(region);
+    }
+
+    #[must_use]
+    pub const fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// Scale how many cycles `run_frame` runs per call, e.g. 2.0 for fast-forward. A
+    /// front-end calling `run_frame` at the display's frame rate is then sped up or
+    /// slowed down by that factor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `speed` is not a positive finite number, as `run_frame` would then
+    /// never finish a frame.
+    pub const fn set_speed(&mut self, speed: f32) {
+        assert!(
+            speed.is_finite() && speed > 0.0,
+            "speed must be positive and finite"
+        );
+        self.speed = speed;
+    }
+
+    /// Run one frame's worth of CPU cycles for the region, scaled by the speed. Returns
+    /// the number of cycles actually run.
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first instruction the CPU cannot run, see `Cpu::run_instr`.
+    #[allow(clippy::cast_precision_loss, clippy::while_float)] // Cycle counts stay far below 2^52.
+    pub fn run_frame(&mut self) -> Result<u64, CpuError> {
+        self.record_rewind_state();
+        let start = self.bus.cycles();
+        self.cycle_budget += self.region.cpu_cycles_per_frame() * f64::from(self.speed);
+        while self.cycle_budget > 0.0 {
+            let result = self.step()?;
+            self.cycle_budget -= result.cycles as f64;
+        }
+        Ok(self.bus.cycles() - start)
+    }
+
+    #[must_use]
+    pub fn snapshot(&self) -> SaveState {
+        SaveState {
+            cpu: self.cpu.state(),
+            bus: self.bus.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, state: &SaveState) {
+        self.cpu.set_state(&state.cpu);
+        self.bus.restore_from(state.bus.clone());
+    }
+
+    /// The whole machine as a versioned blob, to hand back to [`Self::load_state`] on a
+    /// `Nes` running the same ROM, possibly in another process. Unlike a [`SaveState`],
+    /// it leaves out the ROM contents and the settings of the machine.
+    #[must_use]
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        for &byte in state::MAGIC {
+            w.u8(byte);
+        }
+        w.u16(state::VERSION);
+        self.cpu.state().write_state(&mut w);
+        w.u64(self.cycle_budget.to_bits());
+        self.bus.write_state(&mut w);
+        w.into_inner()
+    }
+
+    /// Go back to a state made by [`Self::save_state`]. On error, nothing has changed.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `data` is not a save state of a supported version, or was saved by a
+    /// machine with another cartridge or other devices plugged in.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data);
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = r.u8().map_err(|_| StateError::NotASaveState)?;
+        }
+        if &magic != state::MAGIC {
+            return Err(StateError::NotASaveState);
+        }
+        let version = r.u16()?;
+        if version != state::VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut cpu = self.cpu.state();
+        cpu.read_state(&mut r)?;
+        let cycle_budget = f64::from_bits(r.u64()?);
+        let mut bus = self.bus.clone();
+        bus.read_state(&mut r)?;
+        if !r.is_empty() {
+            return Err(StateError::WrongLength);
+        }
+        self.cpu.set_state(&cpu);
+        self.cycle_budget = cycle_budget;
+        self.bus.restore_from(bus);
+        Ok(())
+    }
+
+    /// Keep a snapshot at the start of each of the last `frames` frames run with
+    /// `run_frame` or `run_until_vblank`, for `rewind`. 0 turns rewinding off.
+    pub fn set_rewind_capacity(&mut self, frames: usize) {
+        self.rewind_capacity = frames;
+        while self.rewind_states.len() > frames {
+            self.rewind_states.pop_front();
+        }
+    }
+
+    /// Go back to the start of the last recorded frame. Returns false when there is no
+    /// frame left to go back to.
+    pub fn rewind(&mut self) -> bool {
+        let Some(state) = self.rewind_states.pop_back() else {
+            return false;
+        };
+        self.restore(&state);
+        true
+    }
+
+    fn record_rewind_state(&mut self) {
+        if self.rewind_capacity == 0 {
+            return;
+        }
+        if self.rewind_states.len() == self.rewind_capacity {
+            self.rewind_states.pop_front();
+        }
+        self.rewind_states.push_back(self.snapshot());
+    }
+
+    /// Run one instruction, then service NMI or IRQ if the PPU, or the APU or mapper,
+    /// raised it by the second-to-last cycle of the instruction. /IRQ is shared, so any
+    /// of its sources asserts it.
+    ///
+    /// # Errors
+    ///
+    /// See `Cpu::run_instr`. Also fails after an instruction that wrote to ROM, when the
+    /// bus traps those.
+    pub fn step(&mut self) -> Result<StepResult, CpuError> {
+        let pc = self.cpu.prog_counter();
+        let result = self
+            .cpu
+            .step_with_interrupts(&mut self.bus, CpuMemoryBus::poll_interrupt_lines)?;
+        match self.bus.take_rom_write() {
+            Some((address, data)) => Err(CpuError::RomWrite { pc, address, data }),
+            None => Ok(result),
+        }
+    }
+
+    /// Run until the PPU enters vblank. If that raised an NMI, the CPU ends up at the
+    /// start of the NMI handler.
+    ///
+    /// # Errors
+    ///
+    /// Stops at the first instruction the CPU cannot run, see `Cpu::run_instr`.
+    pub fn run_until_vblank(&mut self) -> Result<(), CpuError> {
+        self.record_rewind_state();
+        let mut was_in_vblank = self.in_vblank();
+        loop {
+            self.step()?;
+            let in_vblank = self.in_vblank();
+            if in_vblank && !was_in_vblank {
+                break;
+            }
+            was_in_vblank = in_vblank;
+        }
+        Ok(())
+    }
+
+    /// Whether the PPU is past the dot where it sets the vblank flag (241:1), whether or
+    /// not the game has read it back already.
+    fn in_vblank(&self) -> bool {
+        let Some(ppu) = self.bus.ppu() else {
+            return false;
+        };
+        match ppu.scanline() {
+            241 => ppu.dot() > 1,
+            scanline => (242..ppu.pre_render_scanline()).contains(&scanline),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An NROM image whose code is `program` at 0x8000, the reset vector pointing there.
+    fn nrom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 16 + 0x4000 + 0x2000];
+        rom[..6].copy_from_slice(b"NES\x1A\x01\x01");
+        rom[16..16 + program.len()].copy_from_slice(program);
+        rom[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom
+    }
+
+    /// Like [`nrom`] for mapper `mapper`, with CHR-RAM when `chr_banks` is 0.
+    fn rom_image(mapper: u8, chr_banks: u8, program: &[u8]) -> Vec<u8> {
+        let mut rom = nrom(program);
+        rom.resize(16 + 0x4000 + usize::from(chr_banks) * 0x2000, 0);
+        rom[5] = chr_banks;
+        rom[6] = mapper << 4;
+        rom[7] = mapper & 0xF0;
+        rom
+    }
+
+    fn looping_nes() -> Nes {
+        let mut nes = Nes::from_rom_bytes(&nrom(&[0x4C, 0x00, 0x80])).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        nes
+    }
+
+    #[test]
+    fn speed_scales_frame_cycles() {
+        let mut nes = looping_nes();
+        nes.set_speed(2.0);
+        let cycles = nes.run_frame().expect("runs");
+        assert!((59_560..59_564).contains(&cycles), "{cycles}");
+    }
+
+    /// CPU cycles from the start of one vblank to the start of the next.
+    fn frame_length(nes: &mut Nes) -> u64 {
+        nes.run_until_vblank().expect("runs");
+        let start = nes.master_cycles();
+        nes.run_until_vblank().expect("runs");
+        nes.master_cycles() - start
+    }
+
+    #[test]
+    fn pal_header_runs_pal_frames() {
+        let mut rom = nrom(&[0x4C, 0x00, 0x80]);
+        rom[9] = 0x01;
+        let mut nes = Nes::from_rom_bytes(&rom).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        assert_eq!(nes.region(), Region::Pal);
+        // 341 dots by 312 lines, at 3.2 dots per cycle.
+        let cycles = frame_length(&mut nes);
+        assert!((33_245..33_251).contains(&cycles), "{cycles}");
+        let cycles = frame_length(&mut looping_nes());
+        assert!((29_778..29_784).contains(&cycles), "{cycles}");
+    }
+
+    #[test]
+    fn nes_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Nes>();
+    }
+
+    #[test]
+    fn register_hook_survives_restore() {
+        use std::sync::{Arc, Mutex};
+
+        // LDA #$80; STA $2000; JMP $8005
+        let rom = nrom(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80]);
+        let mut nes = Nes::from_rom_bytes(&rom).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        let state = nes.snapshot();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&seen);
+        nes.bus_mut()
+            .on_register_access(move |address, data, is_write| {
+                log.lock()
+                    .expect("not poisoned")
+                    .push((address, data, is_write));
+            });
+        nes.restore(&state);
+        nes.step().expect("runs");
+        nes.step().expect("runs");
+        assert_eq!(*seen.lock().expect("not poisoned"), [(0x2000, 0x80, true)]);
+    }
+
+    #[test]
+    #[should_panic = "speed must be positive and finite"]
+    fn nan_speed_is_rejected() {
+        looping_nes().set_speed(f32::NAN);
+    }
+
+    #[test]
+    #[should_panic = "speed must be positive and finite"]
+    fn zero_speed_is_rejected() {
+        looping_nes().set_speed(0.0);
+    }
+
+    #[test]
+    #[should_panic = "speed must be positive and finite"]
+    fn negative_speed_is_rejected() {
+        looping_nes().set_speed(-1.0);
+    }
+
+    /// A machine running a program that keeps changing RAM, work RAM, VRAM, the PPU and
+    /// APU registers and the mapper's, two frames in so that the PPU takes writes.
+    fn busy_nes(mapper: u8, chr_banks: u8) -> Nes {
+        let program = crate::testing::assemble(
+            0x8000,
+            "
+                LDA #$0F
+                STA $4015
+            loop:
+                INC $00
+                LDA $00
+                STA $2001
+                STA $2007
+                STA $6000
+                STA $4003
+                STA $8000
+                STA $4016
+                JMP loop
+            ",
+        )
+        .expect("assembles");
+        let mut nes =
+            Nes::from_rom_bytes(&rom_image(mapper, chr_banks, &program)).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        // NROM has no register at $8000.
+        nes.bus_mut()
+            .set_rom_write_mode(crate::RomWriteMode::Ignore);
+        nes.run_frame().expect("runs");
+        nes.run_frame().expect("runs");
+        nes
+    }
+
+    #[test]
+    fn save_state_round_trips_for_every_mapper() {
+        for (mapper, chr_banks) in [(0, 0), (0, 1), (1, 0), (7, 0), (66, 2)] {
+            let mut nes = busy_nes(mapper, chr_banks);
+            let saved = nes.save_state();
+            nes.run_frame().expect("runs");
+            let frame = nes.bus().ppu().expect("PPU").frame().to_vec();
+            let after = nes.save_state();
+            assert_ne!(saved, after, "mapper {mapper}");
+
+            nes.load_state(&saved).expect("loads");
+            assert_eq!(nes.save_state(), saved, "mapper {mapper}");
+            nes.run_frame().expect("runs");
+            assert_eq!(
+                nes.bus().ppu().expect("PPU").frame(),
+                frame,
+                "mapper {mapper}"
+            );
+            assert_eq!(nes.save_state(), after, "mapper {mapper}");
+
+            // Another machine with the same ROM ends up in the same state.
+            let mut other = busy_nes(mapper, chr_banks);
+            other.run_frame().expect("runs");
+            other.load_state(&saved).expect("loads");
+            assert_eq!(other.save_state(), saved, "mapper {mapper}");
+        }
+    }
+
+    #[test]
+    fn bad_save_states_are_rejected_without_changes() {
+        let mut nes = busy_nes(0, 1);
+        let saved = nes.save_state();
+        nes.run_frame().expect("runs");
+        let current = nes.save_state();
+
+        let mut wrong_version = saved.clone();
+        wrong_version[4..6].copy_from_slice(&(state::VERSION + 1).to_le_bytes());
+        assert_eq!(
+            nes.load_state(&wrong_version),
+            Err(StateError::UnsupportedVersion(state::VERSION + 1))
+        );
+        assert_eq!(
+            nes.load_state(&saved[..saved.len() - 1]),
+            Err(StateError::WrongLength)
+        );
+        assert_eq!(nes.load_state(&saved[..10]), Err(StateError::WrongLength));
+        let mut trailing = saved.clone();
+        trailing.push(0);
+        assert_eq!(nes.load_state(&trailing), Err(StateError::WrongLength));
+        assert_eq!(nes.load_state(&saved[..2]), Err(StateError::NotASaveState));
+        assert_eq!(
+            nes.load_state(&busy_nes(1, 0).save_state()),
+            Err(StateError::Mismatch)
+        );
+        assert_eq!(nes.save_state(), current);
+    }
+
+    #[test]
+    fn rewind_keeps_the_last_frames_and_restores_them() {
+        let mut nes = busy_nes(0, 1);
+        nes.set_rewind_capacity(3);
+        let mut starts = Vec::new();
+        for _ in 0..5 {
+            starts.push(nes.snapshot());
+            nes.run_frame().expect("runs");
+        }
+        // The two oldest frames were evicted, the newest comes back first.
+        for start in starts[2..].iter().rev() {
+            assert!(!diff_state(start, &nes.snapshot()).is_empty());
+            assert!(nes.rewind());
+            assert_eq!(diff_state(start, &nes.snapshot()), []);
+            assert_eq!(nes.master_cycles(), start.bus().cycles());
+        }
+        assert!(!nes.rewind());
+
+        for _ in 0..3 {
+            nes.run_frame().expect("runs");
+        }
+        nes.set_rewind_capacity(1);
+        assert!(nes.rewind());
+        assert!(!nes.rewind());
+    }
+
+    #[test]
+    fn trapped_rom_write_stops_the_step() {
+        // STA $8000
+        let mut nes = Nes::from_rom_bytes(&nrom(&[0x8D, 0x00, 0x80])).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        nes.bus_mut().set_rom_write_mode(crate::RomWriteMode::Trap);
+        assert_eq!(
+            nes.step(),
+            Err(CpuError::RomWrite {
+                pc: 0x8000,
+                address: 0x8000,
+                data: 0x00
+            })
+        );
+        assert_eq!(nes.cpu().prog_counter(), 0x8003);
+    }
+
+    /// A machine with NMIs on, counting them at $01, with the PPU run with `timing`.
+    fn nmi_counting_nes(timing: crate::PpuTiming) -> Nes {
+        let program = crate::testing::assemble(
+            0x8000,
+            "
+            loop:
+                LDA #$80
+                STA $2000
+                INC $00
+                JMP loop
+            nmi:
+                INC $01
+                RTI
+            ",
+        )
+        .expect("assembles");
+        let mut rom = nrom(&program);
+        // The handler follows the 10 bytes of the loop.
+        rom[16 + 0x3FFA..16 + 0x3FFC].copy_from_slice(&[0x0A, 0x80]);
+        let mut nes = Nes::from_rom_bytes(&rom).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        nes.bus_mut().set_ppu_timing(timing);
+        nes
+    }
+
+    #[test]
+    fn catch_up_timing_reaches_vblank_on_the_same_cycles() {
+        let vblanks = |nes: &mut Nes| {
+            (0..4)
+                .map(|_| {
+                    nes.run_until_vblank().expect("runs");
+                    nes.master_cycles()
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut per_cycle = nmi_counting_nes(crate::PpuTiming::PerCycle);
+        let mut catch_up = nmi_counting_nes(crate::PpuTiming::CatchUp);
+        assert_eq!(vblanks(&mut per_cycle), vblanks(&mut catch_up));
+        let nmis = |nes: &mut Nes| nes.bus_mut().peek(0x0001);
+        // The first vblank comes before the PPU takes $2000 writes, and the last NMI is
+        // still to be taken when run_until_vblank stops.
+        assert_eq!(nmis(&mut catch_up), 2);
+        assert_eq!(nmis(&mut per_cycle), nmis(&mut catch_up));
+
+        // A state saved with dots still owed to the PPU carries on the same way.
+        let saved = catch_up.save_state();
+        let mut restored = nmi_counting_nes(crate::PpuTiming::CatchUp);
+        restored.load_state(&saved).expect("loads");
+        assert_eq!(vblanks(&mut restored), vblanks(&mut catch_up));
+        assert_eq!(restored.save_state(), catch_up.save_state());
+    }
+
+    #[test]
+    fn trainer_goes_to_0x7000_and_pushes_prg_back() {
+        let mut rom = nrom(&[0x4C, 0x00, 0x80]);
+        rom[6] |= 0x04;
+        let trainer = [0xAA; RomHeader::TRAINER_SIZE];
+        rom.splice(16..16, trainer);
+        let mut nes = Nes::from_rom_bytes(&rom).expect("valid ROM");
+        assert_eq!(nes.cpu().state().prog_counter, 0x8000);
+        assert_eq!(nes.bus_mut().peek(0x8000), 0x4C);
+        assert_eq!(nes.bus_mut().peek(0x7000), 0xAA);
+        assert_eq!(nes.bus_mut().peek(0x71FF), 0xAA);
+        assert_eq!(nes.bus_mut().peek(0x7200), 0x00);
+        // Without the trainer's bytes, the file is short.
+        rom.truncate(rom.len() - 1);
+        assert!(matches!(
+            Nes::from_rom_bytes(&rom),
+            Err(RomLoadError::UnexpectedEnd)
+        ));
+    }
+
+    #[test]
+    fn power_on_keeps_the_reset_warning() {
+        let nes = Nes::from_rom_bytes(&nrom(&[0x4C, 0x00, 0x80])).expect("valid ROM");
+        assert_eq!(nes.reset_warning(), None);
+        let mut rom = nrom(&[]);
+        rom[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x50]);
+        let nes = Nes::from_rom_bytes(&rom).expect("valid ROM");
+        assert_eq!(
+            nes.reset_warning(),
+            Some(ResetWarning::UnmappedVector { target: 0x5000 })
+        );
+    }
+
+    #[test]
+    fn master_clock_counts_instruction_and_dma_cycles() {
+        // LDA #$02, STA $4014, NOP, JMP $8000.
+        let program = [0xA9, 0x02, 0x8D, 0x14, 0x40, 0xEA, 0x4C, 0x00, 0x80];
+        let mut nes = Nes::from_rom_bytes(&nrom(&program)).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        let start = nes.master_cycles();
+        let mut taken = Vec::new();
+        for _ in 0..4 {
+            let before = nes.master_cycles();
+            let cycles = nes.step().expect("runs").cycles;
+            assert_eq!(nes.master_cycles() - before, cycles);
+            taken.push(cycles);
+        }
+        // The store carries the 513 or 514 cycle DMA stall.
+        assert_eq!(taken[0], 2);
+        assert!(taken[1] == 4 + 513 || taken[1] == 4 + 514, "{}", taken[1]);
+        assert_eq!(taken[2..], [2, 3]);
+        let elapsed = nes.master_cycles() - start;
+        assert_eq!(elapsed, taken.iter().sum::<u64>());
+        assert_eq!(nes.ppu_cycles(), nes.master_cycles() * 3);
+    }
+
+    #[test]
+    fn run_until_vblank_stops_with_the_flag_set() {
+        let mut nes = looping_nes();
+        for _ in 0..3 {
+            nes.run_until_vblank().expect("runs");
+            let ppu = nes.bus().ppu().expect("PPU attached");
+            assert!(ppu.status().contains(crate::ppu::PpuStatusFlags::VBLANK));
+            assert_eq!(ppu.scanline(), 241);
+            // Stepping a whole instruction past 241:1 at most.
+            assert!(ppu.dot() <= 1 + 7 * 3, "{}", ppu.dot());
+        }
+    }
+
+    #[test]
+    fn from_rom_bytes_runs_an_in_memory_nrom() {
+        // LDA #$42, STA $00, JMP $8005.
+        let program = [0xA9, 0x42, 0x85, 0x00, 0x4C, 0x04, 0x80];
+        let mut nes = Nes::from_rom_bytes(&nrom(&program)).expect("valid ROM");
+        assert_eq!(nes.cpu().state().prog_counter, 0x8000);
+        nes.step().expect("runs");
+        nes.step().expect("runs");
+        assert_eq!(nes.bus_mut().peek(0x0000), 0x42);
+    }
+
+    #[test]
+    fn from_rom_bytes_rejects_bad_headers() {
+        let rom = nrom(&[]);
+        let mut bad_magic = rom.clone();
+        bad_magic[3] = 0x00;
+        assert!(matches!(
+            Nes::from_rom_bytes(&bad_magic),
+            Err(RomLoadError::InvalidMagic)
+        ));
+        assert!(matches!(
+            Nes::from_rom_bytes(&rom[..RomHeader::SIZE - 1]),
+            Err(RomLoadError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            Nes::from_rom_bytes(&[]),
+            Err(RomLoadError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            Nes::from_rom_bytes(&rom[..RomHeader::SIZE + 0x4000]),
+            Err(RomLoadError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            Nes::from_rom_bytes(&rom_image(0xFF, 1, &[])),
+            Err(RomLoadError::UnsupportedMapper(0xFF))
+        ));
+    }
+
+    #[test]
+    fn diff_state_reports_registers_then_memory() {
+        // LDA #$42, STA $10, STA $6001
+        let program = [0xA9, 0x42, 0x85, 0x10, 0x8D, 0x01, 0x60];
+        let mut nes = Nes::from_rom_bytes(&nrom(&program)).expect("valid ROM");
+        nes.cpu_mut()
+            .set_tracer(crate::trace::StderrTracer::with_writer(std::io::sink()));
+        let before = nes.snapshot();
+        for _ in 0..3 {
+            nes.step().expect("runs");
+        }
+        assert_eq!(
+            diff_state(&before, &nes.snapshot()),
+            [
+                StateDiff::Register {
+                    name: "A",
+                    a: 0x00,
+                    b: 0x42
+                },
+                StateDiff::Register {
+                    name: "PC",
+                    a: 0x8000,
+                    b: 0x8007
+                },
+                StateDiff::Memory {
+                    address: 0x0010,
+                    a: 0x00,
+                    b: 0x42
+                },
+                StateDiff::Memory {
+                    address: 0x6001,
+                    a: 0x00,
+                    b: 0x42
+                },
+            ]
+        );
+        assert_eq!(diff_state(&before, &before), []);
+    }
+
+    #[test]
+    fn fresh_machines_power_on_the_same() {
+        let a = looping_nes();
+        let b = looping_nes();
+        assert_eq!(a.save_state(), b.save_state());
+        let ppu = a.bus().ppu().expect("PPU attached");
+        assert_eq!(ppu.palette(), &crate::ppu::Ppu::POWER_ON_PALETTE);
+        assert_eq!(ppu.frame(), b.bus().ppu().expect("PPU attached").frame());
+        // And stay the same, frame after frame, whatever the game does.
+        let mut a = busy_nes(1, 0);
+        let mut b = busy_nes(1, 0);
+        for _ in 0..3 {
+            assert_eq!(a.save_state(), b.save_state());
+            a.run_frame().expect("runs");
+            b.run_frame().expect("runs");
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use std::fmt;
+
+use AddressingMode as M;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Relative,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+}
+
+impl AddressingMode {
+    /// Number of operand bytes following the opcode byte.
+    #[must_use]
+    pub const fn operand_len(self) -> u8 {
+        match self {
+            Self::Implied | Self::Accumulator => 0,
+            Self::Immediate
+            | Self::ZeroPage
+            | Self::ZeroPageX
+            | Self::ZeroPageY
+            | Self::Relative
+            | Self::IndirectX
+            | Self::IndirectY => 1,
+            Self::Absolute | Self::AbsoluteX | Self::AbsoluteY | Self::Indirect => 2,
+        }
+    }
+}
+
+/// The names used in the CPU trace, e.g. "Zero Page,X".
+impl fmt::Display for AddressingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Implied => "Implied",
+            Self::Accumulator => "Accumulator",
+            Self::Immediate => "Immediate",
+            Self::ZeroPage => "Zero Page",
+            Self::ZeroPageX => "Zero Page,X",
+            Self::ZeroPageY => "Zero Page,Y",
+            Self::Relative => "Relative",
+            Self::Absolute => "Absolute",
+            Self::AbsoluteX => "Absolute,X",
+            Self::AbsoluteY => "Absolute,Y",
+            Self::Indirect => "Indirect",
+            Self::IndirectX => "Indirect,X",
+            Self::IndirectY => "Indirect,Y",
+        })
+    }
+}
+
+/// Target of a branch, with `pc` the address of the instruction after it.
+///
+/// Also returns whether the target is in a different page than `pc`, which costs a taken
+/// branch one more cycle.
+#[must_use]
+pub fn relative_target(pc: u16, offset: u8) -> (u16, bool) {
+    let target = pc.wrapping_add_signed(i16::from(offset.cast_signed()));
+    (target, target & 0xFF00 != pc & 0xFF00)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opcode {
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    pub official: bool,
+}
+
+impl Opcode {
+    /// Length of the whole instruction in bytes, opcode included.
+    #[must_use]
+    pub const fn instruction_len(self) -> u8 {
+        1 + self.mode.operand_len()
+    }
+}
+
+const fn op(mnemonic: &'static str, mode: AddressingMode) -> Opcode {
+    Opcode {
+        mnemonic,
+        mode,
+        official: true,
+    }
+}
+
+const fn unofficial(mnemonic: &'static str, mode: AddressingMode) -> Opcode {
+    Opcode {
+        mnemonic,
+        mode,
+        official: false,
+    }
+}
+
+pub const OPCODES: [Opcode; 256] = [
+    // 0x00
+    op("BRK", M::Implied),
+    op("ORA", M::IndirectX),
+    unofficial("JAM", M::Implied),
+    unofficial("SLO", M::IndirectX),
+    unofficial("NOP", M::ZeroPage),
+    op("ORA", M::ZeroPage),
+    op("ASL", M::ZeroPage),
+    unofficial("SLO", M::ZeroPage),
+    op("PHP", M::Implied),
+    op("ORA", M::Immediate),
+    op("ASL", M::Accumulator),
+    unofficial("ANC", M::Immediate),
+    unofficial("NOP", M::Absolute),
+    op("ORA", M::Absolute),
+    op("ASL", M::Absolute),
+    unofficial("SLO", M::Absolute),
+    // 0x10
+    op("BPL", M::Relative),
+    op("ORA", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("SLO", M::IndirectY),
+    unofficial("NOP", M::ZeroPageX),
+    op("ORA", M::ZeroPageX),
+    op("ASL", M::ZeroPageX),
+    unofficial("SLO", M::ZeroPageX),
+    op("CLC", M::Implied),
+    op("ORA", M::AbsoluteY),
+    unofficial("NOP", M::Implied),
+    unofficial("SLO", M::AbsoluteY),
+    unofficial("NOP", M::AbsoluteX),
+    op("ORA", M::AbsoluteX),
+    op("ASL", M::AbsoluteX),
+    unofficial("SLO", M::AbsoluteX),
+    // 0x20
+    op("JSR", M::Absolute),
+    op("AND", M::IndirectX),
+    unofficial("JAM", M::Implied),
+    unofficial("RLA", M::IndirectX),
+    op("BIT", M::ZeroPage),
+    op("AND", M::ZeroPage),
+    op("ROL", M::ZeroPage),
+    unofficial("RLA", M::ZeroPage),
+    op("PLP", M::Implied),
+    op("AND", M::Immediate),
+    op("ROL", M::Accumulator),
+    unofficial("ANC", M::Immediate),
+    op("BIT", M::Absolute),
+    op("AND", M::Absolute),
+    op("ROL", M::Absolute),
+    unofficial("RLA", M::Absolute),
+    // 0x30
+    op("BMI", M::Relative),
+    op("AND", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("RLA", M::IndirectY),
+    unofficial("NOP", M::ZeroPageX),
+    op("AND", M::ZeroPageX),
+    op("ROL", M::ZeroPageX),
+    unofficial("RLA", M::ZeroPageX),
+    op("SEC", M::Implied),
+    op("AND", M::AbsoluteY),
+    unofficial("NOP", M::Implied),
+    unofficial("RLA", M::AbsoluteY),
+    unofficial("NOP", M::AbsoluteX),
+    op("AND", M::AbsoluteX),
+    op("ROL", M::AbsoluteX),
+    unofficial("RLA", M::AbsoluteX),
+    // 0x40
+    op("RTI", M::Implied),
+    op("EOR", M::IndirectX),
+    unofficial("JAM", M::Implied),
+    unofficial("SRE", M::IndirectX),
+    unofficial("NOP", M::ZeroPage),
+    op("EOR", M::ZeroPage),
+    op("LSR", M::ZeroPage),
+    unofficial("SRE", M::ZeroPage),
+    op("PHA", M::Implied),
+    op("EOR", M::Immediate),
+    op("LSR", M::Accumulator),
+    unofficial("ALR", M::Immediate),
+    op("JMP", M::Absolute),
+    op("EOR", M::Absolute),
+    op("LSR", M::Absolute),
+    unofficial("SRE", M::Absolute),
+    // 0x50
+    op("BVC", M::Relative),
+    op("EOR", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("SRE", M::IndirectY),
+    unofficial("NOP", M::ZeroPageX),
+    op("EOR", M::ZeroPageX),
+    op("LSR", M::ZeroPageX),
+    unofficial("SRE", M::ZeroPageX),
+    op("CLI", M::Implied),
+    op("EOR", M::AbsoluteY),
+    unofficial("NOP", M::Implied),
+    unofficial("SRE", M::AbsoluteY),
+    unofficial("NOP", M::AbsoluteX),
+    op("EOR", M::AbsoluteX),
+    op("LSR", M::AbsoluteX),
+    unofficial("SRE", M::AbsoluteX),
+    // 0x60
+    op("RTS", M::Implied),
+    op("ADC", M::IndirectX),
+    unofficial("JAM", M::Implied),
+    unofficial("RRA", M::IndirectX),
+    unofficial("NOP", M::ZeroPage),
+    op("ADC", M::ZeroPage),
+    op("ROR", M::ZeroPage),
+    unofficial("RRA", M::ZeroPage),
+    op("PLA", M::Implied),
+    op("ADC", M::Immediate),
+    op("ROR", M::Accumulator),
+    unofficial("ARR", M::Immediate),
+    op("JMP", M::Indirect),
+    op("ADC", M::Absolute),
+    op("ROR", M::Absolute),
+    unofficial("RRA", M::Absolute),
+    // 0x70
+    op("BVS", M::Relative),
+    op("ADC", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("RRA", M::IndirectY),
+    unofficial("NOP", M::ZeroPageX),
+    op("ADC", M::ZeroPageX),
+    op("ROR", M::ZeroPageX),
+    unofficial("RRA", M::ZeroPageX),
+    op("SEI", M::Implied),
+    op("ADC", M::AbsoluteY),
+    unofficial("NOP", M::Implied),
+    unofficial("RRA", M::AbsoluteY),
+    unofficial("NOP", M::AbsoluteX),
+    op("ADC", M::AbsoluteX),
+    op("ROR", M::AbsoluteX),
+    unofficial("RRA", M::AbsoluteX),
+    // 0x80
+    unofficial("NOP", M::Immediate),
+    op("STA", M::IndirectX),
+    unofficial("NOP", M::Immediate),
+    unofficial("SAX", M::IndirectX),
+    op("STY", M::ZeroPage),
+    op("STA", M::ZeroPage),
+    op("STX", M::ZeroPage),
+    unofficial("SAX", M::ZeroPage),
+    op("DEY", M::Implied),
+    unofficial("NOP", M::Immediate),
+    op("TXA", M::Implied),
+    unofficial("ANE", M::Immediate),
+    op("STY", M::Absolute),
+    op("STA", M::Absolute),
+    op("STX", M::Absolute),
+    unofficial("SAX", M::Absolute),
+    // 0x90
+    op("BCC", M::Relative),
+    op("STA", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("SHA", M::IndirectY),
+    op("STY", M::ZeroPageX),
+    op("STA", M::ZeroPageX),
+    op("STX", M::ZeroPageY),
+    unofficial("SAX", M::ZeroPageY),
+    op("TYA", M::Implied),
+    op("STA", M::AbsoluteY),
+    op("TXS", M::Implied),
+    unofficial("TAS", M::AbsoluteY),
+    unofficial("SHY", M::AbsoluteX),
+    op("STA", M::AbsoluteX),
+    unofficial("SHX", M::AbsoluteY),
+    unofficial("SHA", M::AbsoluteY),
+    // 0xA0
+    op("LDY", M::Immediate),
+    op("LDA", M::IndirectX),
+    op("LDX", M::Immediate),
+    unofficial("LAX", M::IndirectX),
+    op("LDY", M::ZeroPage),
+    op("LDA", M::ZeroPage),
+    op("LDX", M::ZeroPage),
+    unofficial("LAX", M::ZeroPage),
+    op("TAY", M::Implied),
+    op("LDA", M::Immediate),
+    op("TAX", M::Implied),
+    unofficial("LXA", M::Immediate),
+    op("LDY", M::Absolute),
+    op("LDA", M::Absolute),
+    op("LDX", M::Absolute),
+    unofficial("LAX", M::Absolute),
+    // 0xB0
+    op("BCS", M::Relative),
+    op("LDA", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("LAX", M::IndirectY),
+    op("LDY", M::ZeroPageX),
+    op("LDA", M::ZeroPageX),
+    op("LDX", M::ZeroPageY),
+    unofficial("LAX", M::ZeroPageY),
+    op("CLV", M::Implied),
+    op("LDA", M::AbsoluteY),
+    op("TSX", M::Implied),
+    unofficial("LAS", M::AbsoluteY),
+    op("LDY", M::AbsoluteX),
+    op("LDA", M::AbsoluteX),
+    op("LDX", M::AbsoluteY),
+    unofficial("LAX", M::AbsoluteY),
+    // 0xC0
+    op("CPY", M::Immediate),
+    op("CMP", M::IndirectX),
+    unofficial("NOP", M::Immediate),
+    unofficial("DCP", M::IndirectX),
+    op("CPY", M::ZeroPage),
+    op("CMP", M::ZeroPage),
+    op("DEC", M::ZeroPage),
+    unofficial("DCP", M::ZeroPage),
+    op("INY", M::Implied),
+    op("CMP", M::Immediate),
+    op("DEX", M::Implied),
+    unofficial("SBX", M::Immediate),
+    op("CPY", M::Absolute),
+    op("CMP", M::Absolute),
+    op("DEC", M::Absolute),
+    unofficial("DCP", M::Absolute),
+    // 0xD0
+    op("BNE", M::Relative),
+    op("CMP", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("DCP", M::IndirectY),
+    unofficial("NOP", M::ZeroPageX),
+    op("CMP", M::ZeroPageX),
+    op("DEC", M::ZeroPageX),
+    unofficial("DCP", M::ZeroPageX),
+    op("CLD", M::Implied),
+    op("CMP", M::AbsoluteY),
+    unofficial("NOP", M::Implied),
+    unofficial("DCP", M::AbsoluteY),
+    unofficial("NOP", M::AbsoluteX),
+    op("CMP", M::AbsoluteX),
+    op("DEC", M::AbsoluteX),
+    unofficial("DCP", M::AbsoluteX),
+    // 0xE0
+    op("CPX", M::Immediate),
+    op("SBC", M::IndirectX),
+    unofficial("NOP", M::Immediate),
+    unofficial("ISC", M::IndirectX),
+    op("CPX", M::ZeroPage),
+    op("SBC", M::ZeroPage),
+    op("INC", M::ZeroPage),
+    unofficial("ISC", M::ZeroPage),
+    op("INX", M::Implied),
+    op("SBC", M::Immediate),
+    op("NOP", M::Implied),
+    unofficial("SBC", M::Immediate),
+    op("CPX", M::Absolute),
+    op("SBC", M::Absolute),
+    op("INC", M::Absolute),
+    unofficial("ISC", M::Absolute),
+    // 0xF0
+    op("BEQ", M::Relative),
+    op("SBC", M::IndirectY),
+    unofficial("JAM", M::Implied),
+    unofficial("ISC", M::IndirectY),
+    unofficial("NOP", M::ZeroPageX),
+    op("SBC", M::ZeroPageX),
+    op("INC", M::ZeroPageX),
+    unofficial("ISC", M::ZeroPageX),
+    op("SED", M::Implied),
+    op("SBC", M::AbsoluteY),
+    unofficial("NOP", M::Implied),
+    unofficial("ISC", M::AbsoluteY),
+    unofficial("NOP", M::AbsoluteX),
+    op("SBC", M::AbsoluteX),
+    op("INC", M::AbsoluteX),
+    unofficial("ISC", M::AbsoluteX),
+];
@@ -0,0 +1,1368 @@
+#[cfg(feature = "png")]
+use std::{fs::File, io, io::BufWriter, path::Path};
+
+use bitflags::bitflags;
+
+use crate::state::{Persist, StateError, StateReader, StateWriter};
+use crate::{Cart, Mirroring, Region};
+
+/// RGB colour of each of the 64 palette indices the PPU outputs.
+pub const SYSTEM_PALETTE: [[u8; 3]; 64] = [
+    [0x80, 0x80, 0x80],
+    [0x00, 0x3D, 0xA6],
+    [0x00, 0x12, 0xB0],
+    [0x44, 0x00, 0x96],
+    [0xA1, 0x00, 0x5E],
+    [0xC7, 0x00, 0x28],
+    [0xBA, 0x06, 0x00],
+    [0x8C, 0x17, 0x00],
+    [0x5C, 0x2F, 0x00],
+    [0x10, 0x45, 0x00],
+    [0x05, 0x4A, 0x00],
+    [0x00, 0x47, 0x2E],
+    [0x00, 0x41, 0x66],
+    [0x00, 0x00, 0x00],
+    [0x05, 0x05, 0x05],
+    [0x05, 0x05, 0x05],
+    [0xC7, 0xC7, 0xC7],
+    [0x00, 0x77, 0xFF],
+    [0x21, 0x55, 0xFF],
+    [0x82, 0x37, 0xFA],
+    [0xEB, 0x2F, 0xB5],
+    [0xFF, 0x29, 0x50],
+    [0xFF, 0x22, 0x00],
+    [0xD6, 0x32, 0x00],
+    [0xC4, 0x62, 0x00],
+    [0x35, 0x80, 0x00],
+    [0x05, 0x8F, 0x00],
+    [0x00, 0x8A, 0x55],
+    [0x00, 0x99, 0xCC],
+    [0x21, 0x21, 0x21],
+    [0x09, 0x09, 0x09],
+    [0x09, 0x09, 0x09],
+    [0xFF, 0xFF, 0xFF],
+    [0x0F, 0xD7, 0xFF],
+    [0x69, 0xA2, 0xFF],
+    [0xD4, 0x80, 0xFF],
+    [0xFF, 0x45, 0xF3],
+    [0xFF, 0x61, 0x8B],
+    [0xFF, 0x88, 0x33],
+    [0xFF, 0x9C, 0x12],
+    [0xFA, 0xBC, 0x20],
+    [0x9F, 0xE3, 0x0E],
+    [0x2B, 0xF0, 0x35],
+    [0x0C, 0xF0, 0xA4],
+    [0x05, 0xFB, 0xFF],
+    [0x5E, 0x5E, 0x5E],
+    [0x0D, 0x0D, 0x0D],
+    [0x0D, 0x0D, 0x0D],
+    [0xFF, 0xFF, 0xFF],
+    [0xA6, 0xFC, 0xFF],
+    [0xB3, 0xEC, 0xFF],
+    [0xDA, 0xAB, 0xEB],
+    [0xFF, 0xA8, 0xF9],
+    [0xFF, 0xAB, 0xB3],
+    [0xFF, 0xD2, 0xB0],
+    [0xFF, 0xEF, 0xA6],
+    [0xFF, 0xF7, 0x9C],
+    [0xD7, 0xE8, 0x95],
+    [0xA6, 0xED, 0xAF],
+    [0xA2, 0xF2, 0xDA],
+    [0x99, 0xFF, 0xFC],
+    [0xDD, 0xDD, 0xDD],
+    [0x11, 0x11, 0x11],
+    [0x11, 0x11, 0x11],
+];
+
+/// RGB colour of palette index `index` with the emphasis bits of `mask` applied: each
+/// emphasized channel keeps its level while the others are darkened to 13/16.
+#[must_use]
+pub fn palette_to_rgb(index: u8, mask: PpuMaskFlags) -> [u8; 3] {
+    let emphasis = [
+        PpuMaskFlags::EMPHASIZE_RED,
+        PpuMaskFlags::EMPHASIZE_GREEN,
+        PpuMaskFlags::EMPHASIZE_BLUE,
+    ];
+    let mut rgb = SYSTEM_PALETTE[usize::from(index & 0x3F)];
+    if mask.intersects(PpuMaskFlags::EMPHASIS) {
+        for (channel, flag) in rgb.iter_mut().zip(emphasis) {
+            if !mask.contains(flag) {
+                *channel = u8::try_from(u16::from(*channel) * 13 / 16).unwrap_or(u8::MAX);
+            }
+        }
+    }
+    rgb
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct PpuCtrlFlags: u8 {
+        const NAMETABLE_X = 0b0000_0001;
+        const NAMETABLE_Y = 0b0000_0010;
+        const VRAM_INCREMENT = 0b0000_0100;
+        const SPRITE_TABLE = 0b0000_1000;
+        const BACKGROUND_TABLE = 0b0001_0000;
+        const SPRITE_SIZE = 0b0010_0000;
+        const MASTER_SLAVE = 0b0100_0000;
+        const NMI_ENABLE = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct PpuMaskFlags: u8 {
+        const GREYSCALE = 0b0000_0001;
+        const SHOW_BACKGROUND_LEFT = 0b0000_0010;
+        const SHOW_SPRITES_LEFT = 0b0000_0100;
+        const SHOW_BACKGROUND = 0b0000_1000;
+        const SHOW_SPRITES = 0b0001_0000;
+        const EMPHASIZE_RED = 0b0010_0000;
+        const EMPHASIZE_GREEN = 0b0100_0000;
+        const EMPHASIZE_BLUE = 0b1000_0000;
+        const EMPHASIS = Self::EMPHASIZE_RED.bits()
+            | Self::EMPHASIZE_GREEN.bits()
+            | Self::EMPHASIZE_BLUE.bits();
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct PpuStatusFlags: u8 {
+        const SPRITE_OVERFLOW = 0b0010_0000;
+        const SPRITE_ZERO_HIT = 0b0100_0000;
+        const VBLANK = 0b1000_0000;
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone)]
+pub struct Ppu {
+    ctrl: PpuCtrlFlags,
+    mask: PpuMaskFlags,
+    status: PpuStatusFlags,
+    oam_addr: u8,
+    oam: [u8; Self::OAM_SIZE],
+    vram: Box<[u8; Self::VRAM_SIZE]>,
+    palette: [u8; Self::PALETTE_SIZE],
+    /// Current VRAM address (loopy `v`).
+    v: u16,
+    /// Temporary VRAM address (loopy `t`).
+    t: u16,
+    /// Fine X scroll.
+    x: u8,
+    /// First/second write toggle shared by $2005 and $2006.
+    w: bool,
+    read_buffer: u8,
+    io_latch: u8,
+    /// A setting of the console rather than state, so it is not saved.
+    region: Region,
+    scanline: u16,
+    dot: u16,
+    odd_frame: bool,
+    /// Set by a $2002 read one dot before vblank starts, which keeps the flag (and the
+    /// NMI) from being raised this frame.
+    suppress_vblank: bool,
+    /// Keeps /NMI asserted until the next dot after a $2002 read clears a flag the CPU
+    /// would already have seen, so that the NMI is not lost.
+    nmi_hold: bool,
+    /// From power-on to the end of the first vblank (about 29658 CPU cycles) writes to
+    /// PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR are ignored.
+    warming_up: bool,
+    bg_next_tile: u8,
+    bg_next_attribute: u8,
+    bg_next_pattern_low: u8,
+    bg_next_pattern_high: u8,
+    bg_pattern_low: u16,
+    bg_pattern_high: u16,
+    bg_attribute_low: u16,
+    bg_attribute_high: u16,
+    sprite_count: usize,
+    sprite_pattern_low: [u8; 8],
+    sprite_pattern_high: [u8; 8],
+    sprite_attribute: [u8; 8],
+    sprite_x: [u8; 8],
+    sprite_zero_in_line: bool,
+    frame: Vec<u8>,
+    /// The emphasis bits of PPUMASK each pixel of `frame` was drawn with, as games may
+    /// change them mid-frame.
+    emphasis: Vec<u8>,
+}
+
+impl Ppu {
+    pub const WIDTH: usize = 256;
+    pub const HEIGHT: usize = 240;
+    const OAM_SIZE: usize = 256;
+    /// 2KB of CIRAM, plus room for the extra 2KB that four-screen carts provide.
+    const VRAM_SIZE: usize = 4 * 1024;
+    const PALETTE_SIZE: usize = 32;
+    /// Palette RAM contents at power on. Real chips come up with varying values; these
+    /// are the ones read back from a 2C02 by blargg's `power_up_palette` test.
+    pub const POWER_ON_PALETTE: [u8; Self::PALETTE_SIZE] = [
+        0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D, 0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04,
+        0x2C, 0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14, 0x08, 0x3A, 0x00, 0x02, 0x00, 0x20,
+        0x2C, 0x08,
+    ];
+    const VBLANK_SCANLINE: u16 = 241;
+
+    /// A PPU in a fixed power-on state, so that runs are reproducible: OAM and VRAM are
+    /// zeroed and palette RAM holds [`Self::POWER_ON_PALETTE`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            ctrl: PpuCtrlFlags::empty(),
+            mask: PpuMaskFlags::empty(),
+            status: PpuStatusFlags::empty(),
+            oam_addr: 0,
+            oam: [0; Self::OAM_SIZE],
+            vram: Box::new([0; Self::VRAM_SIZE]),
+            palette: Self::POWER_ON_PALETTE,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            region: Region::Ntsc,
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            suppress_vblank: false,
+            nmi_hold: false,
+            warming_up: true,
+            bg_next_tile: 0,
+            bg_next_attribute: 0,
+            bg_next_pattern_low: 0,
+            bg_next_pattern_high: 0,
+            bg_pattern_low: 0,
+            bg_pattern_high: 0,
+            bg_attribute_low: 0,
+            bg_attribute_high: 0,
+            sprite_count: 0,
+            sprite_pattern_low: [0; 8],
+            sprite_pattern_high: [0; 8],
+            sprite_attribute: [0; 8],
+            sprite_x: [0; 8],
+            sprite_zero_in_line: false,
+            frame: vec![0; Self::WIDTH * Self::HEIGHT],
+            emphasis: vec![0; Self::WIDTH * Self::HEIGHT],
+        }
+    }
+
+    /// The rendered picture, one palette index (0-63) per pixel.
+    #[must_use]
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// The rendered picture as packed 8-bit RGB, row by row, with the colour emphasis
+    /// PPUMASK had when each pixel was drawn.
+    #[must_use]
+    pub fn frame_rgb(&self) -> Vec<u8> {
+        self.frame
+            .iter()
+            .zip(&self.emphasis)
+            .flat_map(|(&index, &emphasis)| {
+                palette_to_rgb(index, PpuMaskFlags::from_bits_retain(emphasis))
+            })
+            .collect()
+    }
+
+    /// Draw pattern table `table` (0 for $0000, 1 for $1000) of the cartridge's CHR as a
+    /// 16x16 grid of tiles, colored with palette `palette`: 0-3 are the background
+    /// palettes and 4-7 the sprite ones. Like [`Self::frame`], one palette index per
+    /// pixel.
+    #[must_use]
+    pub fn render_pattern_table(
+        &self,
+        cart: Option<&Cart>,
+        table: u8,
+        palette: u8,
+    ) -> [u8; 128 * 128] {
+        let mut image = [0; 128 * 128];
+        let base = u16::from(table & 1) << 12;
+        for tile in 0..256 {
+            let (tile_x, tile_y) = (tile % 16 * 8, tile / 16 * 8);
+            for row in 0..8 {
+                let address = base | tile << 4 | row;
+                let chr = |address| cart.and_then(|cart| cart.ppu_peek(address)).unwrap_or(0);
+                let (low, high) = (chr(address), chr(address | 0x08));
+                for column in 0..8 {
+                    let bit = 0x80 >> column;
+                    let pixel = u8::from(low & bit != 0) | u8::from(high & bit != 0) << 1;
+                    let palette_address = if pixel == 0 {
+                        0
+                    } else {
+                        u16::from((palette & 0x07) << 2 | pixel)
+                    };
+                    let index = usize::from(tile_y + row) * 128 + usize::from(tile_x + column);
+                    image[index] = self.palette[Self::palette_index(palette_address)] & 0x3F;
+                }
+            }
+        }
+        image
+    }
+
+    /// Write the rendered picture to `path` as a PNG.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file cannot be created or written to.
+    #[cfg(feature = "png")]
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        #[allow(clippy::cast_possible_truncation)] // 256x240 fits in a u32.
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(File::create(path)?),
+            Self::WIDTH as u32,
+            Self::HEIGHT as u32,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.frame_rgb())?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Palette RAM, unmirrored: backgrounds at 0x00-0x0F, sprites at 0x10-0x1F.
+    #[must_use]
+    pub const fn palette(&self) -> &[u8; Self::PALETTE_SIZE] {
+        &self.palette
+    }
+
+    #[must_use]
+    pub const fn ctrl(&self) -> PpuCtrlFlags {
+        self.ctrl
+    }
+
+    #[must_use]
+    pub const fn mask(&self) -> PpuMaskFlags {
+        self.mask
+    }
+
+    /// The current VRAM address, `v` in the loopy model.
+    #[must_use]
+    pub const fn vram_address(&self) -> u16 {
+        self.v
+    }
+
+    /// The temporary VRAM address `t` that $2000, $2005 and $2006 write into.
+    #[must_use]
+    pub const fn temp_vram_address(&self) -> u16 {
+        self.t
+    }
+
+    #[must_use]
+    pub const fn fine_x(&self) -> u8 {
+        self.x
+    }
+
+    #[must_use]
+    pub const fn status(&self) -> PpuStatusFlags {
+        self.status
+    }
+
+    #[must_use]
+    pub const fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    /// The last scanline of the frame, 261 on NTSC and 311 on PAL.
+    #[must_use]
+    pub const fn pre_render_scanline(&self) -> u16 {
+        self.region.scanlines_per_frame() - 1
+    }
+
+    pub const fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    #[must_use]
+    pub const fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    /// State of the PPU's /NMI output, true when asserted.
+    #[must_use]
+    pub const fn nmi_line(&self) -> bool {
+        self.ctrl.contains(PpuCtrlFlags::NMI_ENABLE)
+            && (self.status.contains(PpuStatusFlags::VBLANK) || self.nmi_hold)
+    }
+
+    /// Handle a CPU read of 0x2000-0x3FFF. The latch does not decay over time here, unlike
+    /// on the hardware where it fades after a few hundred milliseconds.
+    pub fn cpu_read(&mut self, address: u16, mut cart: Option<&mut Cart>) -> Option<u8> {
+        let data = match address & 0x0007 {
+            0x0002 => {
+                let data = self.status.bits() | (self.io_latch & 0b0001_1111);
+                // `dot` is the next dot to run, so vblank is set on the coming one at
+                // 241:1 and was set zero or one dot ago at 241:2-3. Reading in that window
+                // suppresses the NMI; the CPU samples /NMI at the end of each cycle, so
+                // clearing the flag is enough for the later case. Any later read comes
+                // after the NMI has been raised, and must not cancel it.
+                if self.scanline == Self::VBLANK_SCANLINE && self.dot == 1 {
+                    self.suppress_vblank = true;
+                } else if self.status.contains(PpuStatusFlags::VBLANK)
+                    && !(self.scanline == Self::VBLANK_SCANLINE && self.dot <= 3)
+                {
+                    self.nmi_hold = true;
+                }
+                self.status.remove(PpuStatusFlags::VBLANK);
+                self.w = false;
+                data
+            }
+            0x0004 => self.oam_data(),
+            0x0007 => {
+                let address = self.v & 0x3FFF;
+                let data = if address >= 0x3F00 {
+                    self.read_buffer = self.read_vram(cart.as_deref_mut(), address - 0x1000);
+                    self.read_vram(cart.as_deref_mut(), address)
+                } else {
+                    let buffered = self.read_buffer;
+                    self.read_buffer = self.read_vram(cart, address);
+                    buffered
+                };
+                self.increment_vram_address();
+                data
+            }
+            // Write-only registers: nothing drives the PPU data bus, so the latch
+            // holding the last value written or read is what comes back.
+            _ => return Some(self.io_latch),
+        };
+        self.io_latch = data;
+        Some(data)
+    }
+
+    /// The value of $2004. Reads never move OAMADDR.
+    ///
+    /// On visible lines with rendering enabled, the real PPU returns what its sprite
+    /// evaluation is busy with instead. Only the clearing of secondary OAM on dots 1-64,
+    /// during which reads return 0xFF, is modelled; later in the line the byte at OAMADDR
+    /// is returned.
+    fn oam_data(&self) -> u8 {
+        if self.rendering_enabled() && self.scanline < 240 && (1..=64).contains(&self.dot) {
+            return 0xFF;
+        }
+        let data = self.oam[usize::from(self.oam_addr)];
+        // Bits 2-4 of the sprite attributes do not exist, and read back as 0.
+        if self.oam_addr & 0x03 == 2 {
+            data & 0xE3
+        } else {
+            data
+        }
+    }
+
+    /// Read a register without any of the side effects of `cpu_read`.
+    #[must_use]
+    pub fn peek(&self, address: u16) -> Option<u8> {
+        match address & 0x0007 {
+            0x0002 => Some(self.status.bits() | (self.io_latch & 0b0001_1111)),
+            0x0004 => Some(self.oam_data()),
+            0x0007 => Some(self.read_buffer),
+            _ => Some(self.io_latch),
+        }
+    }
+
+    /// End the power-on warm-up right away, for driving the PPU without waiting for a
+    /// frame first.
+    pub const fn skip_warm_up(&mut self) {
+        self.warming_up = false;
+    }
+
+    /// Handle a CPU write to 0x2000-0x3FFF.
+    pub fn cpu_write(&mut self, address: u16, data: u8, cart: Option<&mut Cart>) -> bool {
+        self.io_latch = data;
+        match address & 0x0007 {
+            0x0000 | 0x0001 | 0x0005 | 0x0006 if self.warming_up => {}
+            0x0000 => {
+                self.ctrl = PpuCtrlFlags::from_bits_retain(data);
+                self.t = (self.t & !0x0C00) | (u16::from(data) & 0x03) << 10;
+            }
+            0x0001 => self.mask = PpuMaskFlags::from_bits_retain(data),
+            0x0003 => self.oam_addr = data,
+            0x0004 => {
+                self.oam[usize::from(self.oam_addr)] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            0x0005 => {
+                if self.w {
+                    self.t = (self.t & !0x73E0)
+                        | (u16::from(data) & 0x07) << 12
+                        | (u16::from(data) & 0xF8) << 2;
+                } else {
+                    self.t = (self.t & !0x001F) | u16::from(data) >> 3;
+                    self.x = data & 0x07;
+                }
+                self.w = !self.w;
+            }
+            0x0006 => {
+                if self.w {
+                    self.t = (self.t & 0xFF00) | u16::from(data);
+                    self.v = self.t;
+                } else {
+                    self.t = (self.t & 0x00FF) | (u16::from(data) & 0x3F) << 8;
+                }
+                self.w = !self.w;
+            }
+            0x0007 => {
+                self.write_vram(cart, self.v & 0x3FFF, data);
+                self.increment_vram_address();
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Advance the PPU by one dot.
+    pub fn tick(&mut self, mut cart: Option<&mut Cart>) {
+        let visible_line = self.scanline < 240;
+        let pre_render_line = self.scanline == self.pre_render_scanline();
+        self.nmi_hold = false;
+
+        if pre_render_line && self.dot == 1 {
+            self.warming_up = false;
+            self.status.remove(
+                PpuStatusFlags::VBLANK
+                    | PpuStatusFlags::SPRITE_ZERO_HIT
+                    | PpuStatusFlags::SPRITE_OVERFLOW,
+            );
+        }
+        if self.scanline == Self::VBLANK_SCANLINE && self.dot == 1 {
+            if !self.suppress_vblank {
+                self.status.insert(PpuStatusFlags::VBLANK);
+            }
+            self.suppress_vblank = false;
+        }
+
+        if visible_line && (1..=256).contains(&self.dot) {
+            self.render_pixel();
+        }
+
+        if self.rendering_enabled() && (visible_line || pre_render_line) {
+            self.run_background_pipeline(cart.as_deref_mut());
+            if self.dot == 257 {
+                if visible_line {
+                    self.evaluate_sprites(cart);
+                } else {
+                    self.sprite_count = 0;
+                    self.sprite_zero_in_line = false;
+                }
+            }
+        }
+
+        self.advance_dot();
+    }
+
+    const fn rendering_enabled(&self) -> bool {
+        self.mask
+            .intersects(PpuMaskFlags::SHOW_BACKGROUND.union(PpuMaskFlags::SHOW_SPRITES))
+    }
+
+    const fn advance_dot(&mut self) {
+        // The NTSC pre-render line is one dot shorter on odd frames when rendering.
+        if matches!(self.region, Region::Ntsc)
+            && self.scanline == self.pre_render_scanline()
+            && self.dot == 339
+            && self.odd_frame
+            && self.rendering_enabled()
+        {
+            self.dot = 340;
+        }
+        self.dot += 1;
+        if self.dot > 340 {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > self.pre_render_scanline() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    fn run_background_pipeline(&mut self, mut cart: Option<&mut Cart>) {
+        let fetch_dot = (2..=257).contains(&self.dot) || (322..=337).contains(&self.dot);
+        if fetch_dot {
+            self.shift_background();
+        }
+        if (1..=256).contains(&self.dot) || (321..=336).contains(&self.dot) {
+            match (self.dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.bg_next_tile =
+                        self.read_vram(cart.as_deref_mut(), 0x2000 | (self.v & 0x0FFF));
+                }
+                2 => {
+                    let address = 0x23C0
+                        | (self.v & 0x0C00)
+                        | ((self.v >> 4) & 0x38)
+                        | ((self.v >> 2) & 0x07);
+                    let shift = ((self.v >> 4) & 0x04) | (self.v & 0x02);
+                    self.bg_next_attribute =
+                        (self.read_vram(cart.as_deref_mut(), address) >> shift) & 0x03;
+                }
+                4 => {
+                    let address = self.background_pattern_address();
+                    self.bg_next_pattern_low = self.read_vram(cart.as_deref_mut(), address);
+                }
+                6 => {
+                    let address = self.background_pattern_address() + 8;
+                    self.bg_next_pattern_high = self.read_vram(cart, address);
+                }
+                7 => self.increment_coarse_x(),
+                _ => {}
+            }
+        }
+        if self.dot == 256 {
+            self.increment_y();
+        }
+        if self.dot == 257 {
+            self.load_background_shifters();
+            self.v = (self.v & !0x041F) | (self.t & 0x041F);
+        }
+        if self.scanline == self.pre_render_scanline() && (280..=304).contains(&self.dot) {
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+        }
+    }
+
+    fn background_pattern_address(&self) -> u16 {
+        let table = if self.ctrl.contains(PpuCtrlFlags::BACKGROUND_TABLE) {
+            0x1000
+        } else {
+            0x0000
+        };
+        table | u16::from(self.bg_next_tile) << 4 | ((self.v >> 12) & 0x07)
+    }
+
+    const fn shift_background(&mut self) {
+        self.bg_pattern_low <<= 1;
+        self.bg_pattern_high <<= 1;
+        self.bg_attribute_low <<= 1;
+        self.bg_attribute_high <<= 1;
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.bg_pattern_low = (self.bg_pattern_low & 0xFF00) | u16::from(self.bg_next_pattern_low);
+        self.bg_pattern_high =
+            (self.bg_pattern_high & 0xFF00) | u16::from(self.bg_next_pattern_high);
+        let attribute_low = if self.bg_next_attribute & 0b01 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        let attribute_high = if self.bg_next_attribute & 0b10 != 0 {
+            0xFF
+        } else {
+            0x00
+        };
+        self.bg_attribute_low = (self.bg_attribute_low & 0xFF00) | attribute_low;
+        self.bg_attribute_high = (self.bg_attribute_high & 0xFF00) | attribute_high;
+    }
+
+    const fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    const fn increment_y(&mut self) {
+        if self.v & 0x7000 == 0x7000 {
+            self.v &= !0x7000;
+            let mut coarse_y = (self.v & 0x03E0) >> 5;
+            if coarse_y == 29 {
+                coarse_y = 0;
+                self.v ^= 0x0800;
+            } else if coarse_y == 31 {
+                coarse_y = 0;
+            } else {
+                coarse_y += 1;
+            }
+            self.v = (self.v & !0x03E0) | coarse_y << 5;
+        } else {
+            self.v += 0x1000;
+        }
+    }
+
+    const fn increment_vram_address(&mut self) {
+        let step = if self.ctrl.contains(PpuCtrlFlags::VRAM_INCREMENT) {
+            32
+        } else {
+            1
+        };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+
+    const fn sprite_height(&self) -> u16 {
+        if self.ctrl.contains(PpuCtrlFlags::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Pick the sprites of the next scanline and fetch their patterns.
+    ///
+    /// Past the eighth sprite in range, the hardware keeps looking for more but
+    /// increments its byte index along with the sprite index, so it compares tile,
+    /// attribute and X bytes as if they were Y coordinates. The overflow flag follows
+    /// that buggy search rather than the real number of sprites.
+    fn evaluate_sprites(&mut self, mut cart: Option<&mut Cart>) {
+        let height = self.sprite_height();
+        let in_range = |y: u8| self.scanline.wrapping_sub(u16::from(y)) < height;
+
+        let mut sprites = [0usize; 8];
+        let mut count = 0;
+        let mut n = 0;
+        while n < 64 && count < 8 {
+            if in_range(self.oam[n * 4]) {
+                sprites[count] = n;
+                count += 1;
+            }
+            n += 1;
+        }
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.status.insert(PpuStatusFlags::SPRITE_OVERFLOW);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+
+        self.sprite_count = count;
+        self.sprite_zero_in_line = count > 0 && sprites[0] == 0;
+        for (i, &sprite) in sprites[..count].iter().enumerate() {
+            let [y, tile, attribute, x] = [0, 1, 2, 3].map(|b| self.oam[sprite * 4 + b]);
+            let mut row = self.scanline.wrapping_sub(u16::from(y));
+            if attribute & 0x80 != 0 {
+                row = height - 1 - row;
+            }
+            let address = if height == 16 {
+                let table = u16::from(tile & 0x01) << 12;
+                let tile = u16::from(tile & 0xFE) + row / 8;
+                table | tile << 4 | (row & 0x07)
+            } else {
+                let table = if self.ctrl.contains(PpuCtrlFlags::SPRITE_TABLE) {
+                    0x1000
+                } else {
+                    0x0000
+                };
+                table | u16::from(tile) << 4 | row
+            };
+            let mut low = self.read_vram(cart.as_deref_mut(), address);
+            let mut high = self.read_vram(cart.as_deref_mut(), address + 8);
+            if attribute & 0x40 != 0 {
+                low = low.reverse_bits();
+                high = high.reverse_bits();
+            }
+            self.sprite_pattern_low[i] = low;
+            self.sprite_pattern_high[i] = high;
+            self.sprite_attribute[i] = attribute;
+            self.sprite_x[i] = x;
+        }
+    }
+
+    fn render_pixel(&mut self) {
+        let x = self.dot - 1;
+        // Bits 1 and 2 of PPUMASK hide each layer in the leftmost 8 pixels.
+        let left_column = x < 8;
+        let show_background = self.mask.contains(PpuMaskFlags::SHOW_BACKGROUND)
+            && (!left_column || self.mask.contains(PpuMaskFlags::SHOW_BACKGROUND_LEFT));
+        let show_sprites = self.mask.contains(PpuMaskFlags::SHOW_SPRITES)
+            && (!left_column || self.mask.contains(PpuMaskFlags::SHOW_SPRITES_LEFT));
+
+        let (bg_pixel, bg_palette) = if show_background {
+            let bit = 0x8000 >> self.x;
+            let pixel = u8::from(self.bg_pattern_low & bit != 0)
+                | u8::from(self.bg_pattern_high & bit != 0) << 1;
+            let palette = u8::from(self.bg_attribute_low & bit != 0)
+                | u8::from(self.bg_attribute_high & bit != 0) << 1;
+            (pixel, palette)
+        } else {
+            (0, 0)
+        };
+
+        let mut sprite = None;
+        if show_sprites {
+            for i in 0..self.sprite_count {
+                let offset = x.wrapping_sub(u16::from(self.sprite_x[i]));
+                if offset >= 8 {
+                    continue;
+                }
+                let bit = 0x80 >> offset;
+                let pixel = u8::from(self.sprite_pattern_low[i] & bit != 0)
+                    | u8::from(self.sprite_pattern_high[i] & bit != 0) << 1;
+                if pixel != 0 {
+                    sprite = Some((i, pixel, self.sprite_attribute[i]));
+                    break;
+                }
+            }
+        }
+
+        let palette_address = match sprite {
+            Some((i, sprite_pixel, attribute)) => {
+                if i == 0 && self.sprite_zero_in_line && bg_pixel != 0 && x != 255 {
+                    self.status.insert(PpuStatusFlags::SPRITE_ZERO_HIT);
+                }
+                if bg_pixel != 0 && attribute & 0x20 != 0 {
+                    u16::from(bg_palette << 2 | bg_pixel)
+                } else {
+                    0x10 | u16::from((attribute & 0x03) << 2 | sprite_pixel)
+                }
+            }
+            None if bg_pixel != 0 => u16::from(bg_palette << 2 | bg_pixel),
+            None => 0,
+        };
+        let pixel = usize::from(self.scanline) * Self::WIDTH + usize::from(x);
+        self.frame[pixel] = self.palette[Self::palette_index(palette_address)] & 0x3F;
+        self.emphasis[pixel] = (self.mask & PpuMaskFlags::EMPHASIS).bits();
+    }
+
+    const fn palette_index(address: u16) -> usize {
+        let index = (address & 0x1F) as usize;
+        // The backdrop entries of the sprite palettes mirror the background ones.
+        if index & 0x13 == 0x10 {
+            index & 0x0F
+        } else {
+            index
+        }
+    }
+
+    const fn nametable_index(address: u16, mirroring: Mirroring) -> usize {
+        let table = (address >> 10) & 0x03;
+        let offset = (address & 0x03FF) as usize;
+        let physical_table = match mirroring {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x01,
+            Mirroring::SingleA => 0,
+            Mirroring::SingleB => 1,
+            Mirroring::FourScreen => table,
+        };
+        physical_table as usize * 0x0400 + offset
+    }
+
+    /// Without a cartridge nothing drives CIRAM A10, so pick horizontal arbitrarily.
+    fn mirroring(cart: Option<&mut Cart>) -> Mirroring {
+        cart.map_or(Mirroring::Horizontal, |cart| cart.mirroring())
+    }
+
+    fn read_vram(&self, cart: Option<&mut Cart>, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => cart.and_then(|cart| cart.ppu_read(address)).unwrap_or(0),
+            0x2000..=0x3EFF => self.vram[Self::nametable_index(address, Self::mirroring(cart))],
+            _ => self.palette[Self::palette_index(address)],
+        }
+    }
+
+    fn write_vram(&mut self, cart: Option<&mut Cart>, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => {
+                if let Some(cart) = cart {
+                    cart.ppu_write(address, data);
+                }
+            }
+            0x2000..=0x3EFF => {
+                self.vram[Self::nametable_index(address, Self::mirroring(cart))] = data;
+            }
+            _ => self.palette[Self::palette_index(address)] = data,
+        }
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Persist for Ppu {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.ctrl.bits());
+        w.u8(self.mask.bits());
+        w.u8(self.status.bits());
+        w.u8(self.oam_addr);
+        w.bytes(&self.oam);
+        w.bytes(&*self.vram);
+        w.bytes(&self.palette);
+        w.u16(self.v);
+        w.u16(self.t);
+        w.u8(self.x);
+        w.bool(self.w);
+        w.u8(self.read_buffer);
+        w.u8(self.io_latch);
+        w.u16(self.scanline);
+        w.u16(self.dot);
+        w.bool(self.odd_frame);
+        w.bool(self.suppress_vblank);
+        w.bool(self.nmi_hold);
+        w.bool(self.warming_up);
+        w.u8(self.bg_next_tile);
+        w.u8(self.bg_next_attribute);
+        w.u8(self.bg_next_pattern_low);
+        w.u8(self.bg_next_pattern_high);
+        w.u16(self.bg_pattern_low);
+        w.u16(self.bg_pattern_high);
+        w.u16(self.bg_attribute_low);
+        w.u16(self.bg_attribute_high);
+        // At most 8 sprites are on a line.
+        w.u8(u8::try_from(self.sprite_count).unwrap_or(8));
+        w.bytes(&self.sprite_pattern_low);
+        w.bytes(&self.sprite_pattern_high);
+        w.bytes(&self.sprite_attribute);
+        w.bytes(&self.sprite_x);
+        w.bool(self.sprite_zero_in_line);
+        w.bytes(&self.frame);
+        w.bytes(&self.emphasis);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        self.ctrl = PpuCtrlFlags::from_bits_retain(r.u8()?);
+        self.mask = PpuMaskFlags::from_bits_retain(r.u8()?);
+        self.status = PpuStatusFlags::from_bits_retain(r.u8()?);
+        self.oam_addr = r.u8()?;
+        r.bytes_into(&mut self.oam)?;
+        r.bytes_into(&mut *self.vram)?;
+        r.bytes_into(&mut self.palette)?;
+        self.v = r.u16()?;
+        self.t = r.u16()?;
+        self.x = r.u8()?;
+        self.w = r.bool()?;
+        self.read_buffer = r.u8()?;
+        self.io_latch = r.u8()?;
+        self.scanline = r.u16()?;
+        self.dot = r.u16()?;
+        self.odd_frame = r.bool()?;
+        self.suppress_vblank = r.bool()?;
+        self.nmi_hold = r.bool()?;
+        self.warming_up = r.bool()?;
+        self.bg_next_tile = r.u8()?;
+        self.bg_next_attribute = r.u8()?;
+        self.bg_next_pattern_low = r.u8()?;
+        self.bg_next_pattern_high = r.u8()?;
+        self.bg_pattern_low = r.u16()?;
+        self.bg_pattern_high = r.u16()?;
+        self.bg_attribute_low = r.u16()?;
+        self.bg_attribute_high = r.u16()?;
+        self.sprite_count = usize::from(r.u8()?).min(8);
+        r.bytes_into(&mut self.sprite_pattern_low)?;
+        r.bytes_into(&mut self.sprite_pattern_high)?;
+        r.bytes_into(&mut self.sprite_attribute)?;
+        r.bytes_into(&mut self.sprite_x)?;
+        self.sprite_zero_in_line = r.bool()?;
+        r.bytes_into(&mut self.frame)?;
+        r.bytes_into(&mut self.emphasis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MapperEnum, Nrom};
+
+    /// Whether the sprite overflow flag is set once the first 30 lines of a frame are
+    /// rendered with `oam`.
+    fn sprite_overflow(oam: &[u8; 256]) -> bool {
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        ppu.cpu_write(0x2003, 0, None);
+        for &byte in oam {
+            ppu.cpu_write(0x2004, byte, None);
+        }
+        ppu.cpu_write(0x2001, 0x18, None);
+        for _ in 0..341 * 30 {
+            ppu.tick(None);
+        }
+        ppu.status().contains(PpuStatusFlags::SPRITE_OVERFLOW)
+    }
+
+    /// Eight sprites on line 10, and the rest off screen.
+    fn eight_sprites() -> [u8; 256] {
+        let mut oam = [0xF0; 256];
+        for sprite in 0..8 {
+            oam[sprite * 4] = 10;
+        }
+        oam
+    }
+
+    #[test]
+    fn sprite_overflow_needs_a_ninth_sprite() {
+        assert!(!sprite_overflow(&eight_sprites()));
+        let mut oam = eight_sprites();
+        oam[8 * 4] = 10;
+        assert!(sprite_overflow(&oam));
+    }
+
+    /// After the eighth sprite, the PPU also steps through the bytes of each entry, so
+    /// it checks the tile index of sprite 9 as a Y coordinate.
+    #[test]
+    fn sprite_overflow_diagonal_false_positive() {
+        let mut oam = eight_sprites();
+        oam[9 * 4 + 1] = 10;
+        assert!(sprite_overflow(&oam));
+    }
+
+    /// ...and misses the actual Y coordinate of sprite 9.
+    #[test]
+    fn sprite_overflow_diagonal_false_negative() {
+        let mut oam = eight_sprites();
+        oam[9 * 4] = 10;
+        assert!(!sprite_overflow(&oam));
+    }
+
+    #[test]
+    fn pattern_table_uses_chr_and_palette() {
+        let mut chr = vec![0; 0x2000];
+        // Row 0 of tile 1 in the first table: colors 3 3 1 1 2 2 0 0.
+        chr[0x10] = 0xF0;
+        chr[0x18] = 0xCC;
+        // Tile 0 of the second table is solid color 1.
+        chr[0x1000..0x1008].fill(0xFF);
+        let cart = Cart::new(MapperEnum::Nrom(Nrom::new(
+            &vec![0; 0x8000],
+            &chr,
+            Mirroring::Horizontal,
+        )));
+
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        ppu.cpu_write(0x2006, 0x3F, None);
+        ppu.cpu_write(0x2006, 0x00, None);
+        for color in [0x0F, 0, 0, 0, 0, 0x16, 0x27, 0x18] {
+            ppu.cpu_write(0x2007, color, None);
+        }
+
+        let image = ppu.render_pattern_table(Some(&cart), 0, 1);
+        assert_eq!(
+            image[8..16],
+            [0x18, 0x18, 0x16, 0x16, 0x27, 0x27, 0x0F, 0x0F]
+        );
+        assert_eq!(image[128 + 8], 0x0F);
+
+        let image = ppu.render_pattern_table(Some(&cart), 1, 1);
+        assert_eq!(image[..8], [0x16; 8]);
+        assert_eq!(image[8], 0x0F);
+    }
+
+    /// Run `ppu` until `dot` of `scanline` is the next one to be drawn.
+    fn run_to(ppu: &mut Ppu, cart: &mut Cart, scanline: u16, dot: u16) {
+        while (ppu.scanline(), ppu.dot()) != (scanline, dot) {
+            ppu.tick(Some(cart));
+        }
+    }
+
+    #[test]
+    fn ppuctrl_nametable_switch_takes_effect_on_the_next_line() {
+        let mut chr = vec![0; 0x2000];
+        // Tile 1 is solid color 1, tile 0 is blank.
+        chr[0x10..0x18].fill(0xFF);
+        let mut cart = Cart::new(MapperEnum::Nrom(Nrom::new(
+            &vec![0; 0x8000],
+            &chr,
+            Mirroring::Vertical,
+        )));
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        // The second nametable is all tile 1.
+        ppu.cpu_write(0x2006, 0x24, Some(&mut cart));
+        ppu.cpu_write(0x2006, 0x00, Some(&mut cart));
+        for _ in 0..0x3C0 {
+            ppu.cpu_write(0x2007, 0x01, Some(&mut cart));
+        }
+        ppu.cpu_write(0x2006, 0x3F, Some(&mut cart));
+        ppu.cpu_write(0x2006, 0x00, Some(&mut cart));
+        ppu.cpu_write(0x2007, 0x0F, Some(&mut cart));
+        ppu.cpu_write(0x2007, 0x16, Some(&mut cart));
+        ppu.cpu_write(0x2006, 0x00, Some(&mut cart));
+        ppu.cpu_write(0x2006, 0x00, Some(&mut cart));
+        ppu.cpu_write(0x2001, 0x0A, Some(&mut cart));
+
+        run_to(&mut ppu, &mut cart, 100, 100);
+        ppu.cpu_write(0x2000, 0x01, Some(&mut cart));
+        assert_eq!(ppu.temp_vram_address() & 0x0C00, 0x0400);
+        assert_eq!(ppu.vram_address() & 0x0C00, 0x0000);
+
+        // Coarse X wraps into the other nametable late in the line, so look before that.
+        run_to(&mut ppu, &mut cart, 100, 200);
+        assert_eq!(ppu.vram_address() & 0x0C00, 0x0000);
+        // Dot 257 copies the horizontal bits of t, nametable X included.
+        run_to(&mut ppu, &mut cart, 100, 258);
+        assert_eq!(ppu.vram_address() & 0x041F, 0x0400);
+
+        run_to(&mut ppu, &mut cart, 103, 0);
+        let row = |line: usize| &ppu.frame()[line * Ppu::WIDTH..(line + 1) * Ppu::WIDTH];
+        assert_eq!(row(99), [0x0F; Ppu::WIDTH]);
+        assert_eq!(row(101), [0x16; Ppu::WIDTH]);
+        assert_eq!(row(102), [0x16; Ppu::WIDTH]);
+    }
+
+    /// A cart whose tile 1 is solid color 1, the other tiles being blank.
+    fn solid_tile_cart(mirroring: Mirroring) -> Cart {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].fill(0xFF);
+        Cart::new(MapperEnum::Nrom(Nrom::new(
+            &vec![0; 0x8000],
+            &chr,
+            mirroring,
+        )))
+    }
+
+    /// Write `bytes` to VRAM from `address` on, then point v back at 0x0000.
+    fn write_vram(ppu: &mut Ppu, cart: &mut Cart, address: u16, bytes: &[u8]) {
+        let [high, low] = address.to_be_bytes();
+        ppu.cpu_write(0x2006, high, Some(cart));
+        ppu.cpu_write(0x2006, low, Some(cart));
+        for &byte in bytes {
+            ppu.cpu_write(0x2007, byte, Some(cart));
+        }
+        ppu.cpu_write(0x2006, 0x00, Some(cart));
+        ppu.cpu_write(0x2006, 0x00, Some(cart));
+    }
+
+    #[test]
+    fn sprite_zero_hit_sets_on_its_first_opaque_pixel_until_pre_render() {
+        let mut cart = solid_tile_cart(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        write_vram(&mut ppu, &mut cart, 0x2000, &[0x01; 0x3C0]);
+        // Sprite 0 is tile 1 at (40, 30), and shows from line 31 on.
+        ppu.cpu_write(0x2003, 0x00, None);
+        for byte in [30, 0x01, 0x00, 40] {
+            ppu.cpu_write(0x2004, byte, None);
+        }
+        ppu.cpu_write(0x2001, 0x1E, Some(&mut cart));
+        let hit = |ppu: &Ppu| ppu.status().contains(PpuStatusFlags::SPRITE_ZERO_HIT);
+
+        run_to(&mut ppu, &mut cart, 31, 41);
+        assert!(!hit(&ppu));
+        // Dot 41 draws pixel 40.
+        ppu.tick(Some(&mut cart));
+        assert!(hit(&ppu));
+
+        run_to(&mut ppu, &mut cart, 261, 1);
+        assert!(hit(&ppu));
+        ppu.tick(Some(&mut cart));
+        assert!(!hit(&ppu));
+    }
+
+    #[test]
+    fn ppuscroll_writes_x_then_y_into_t() {
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        ppu.cpu_write(0x2000, 0x03, None);
+        // X = 125: coarse X 15, fine X 5.
+        ppu.cpu_write(0x2005, 0x7D, None);
+        assert_eq!(ppu.temp_vram_address(), 0x0C0F);
+        assert_eq!(ppu.fine_x(), 5);
+        assert!(ppu.w);
+        // Y = 94: coarse Y 11, fine Y 6, with the nametable bits left alone.
+        ppu.cpu_write(0x2005, 0x5E, None);
+        assert_eq!(ppu.temp_vram_address(), 0x6D6F);
+        assert_eq!(ppu.fine_x(), 5);
+        assert!(!ppu.w);
+        assert_eq!(ppu.vram_address(), 0x0000);
+    }
+
+    #[test]
+    fn ppustatus_read_clears_vblank_and_the_write_toggle() {
+        let mut cart = solid_tile_cart(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        run_to(&mut ppu, &mut cart, 241, 10);
+        ppu.cpu_write(0x2005, 0x00, None);
+        assert!(ppu.w);
+        assert_eq!(
+            ppu.cpu_read(0x2002, None).map(|data| data & 0x80),
+            Some(0x80)
+        );
+        assert!(!ppu.status().contains(PpuStatusFlags::VBLANK));
+        assert!(!ppu.w);
+        assert_eq!(
+            ppu.cpu_read(0x2002, None).map(|data| data & 0x80),
+            Some(0x00)
+        );
+        // The next $2005 write is an X write again.
+        ppu.cpu_write(0x2005, 0x07, None);
+        assert_eq!(ppu.fine_x(), 7);
+    }
+
+    #[test]
+    fn oamdata_reads_0xff_while_secondary_oam_is_cleared() {
+        let mut cart = solid_tile_cart(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        ppu.cpu_write(0x2003, 0x00, None);
+        ppu.cpu_write(0x2004, 0x42, None);
+        ppu.cpu_write(0x2003, 0x00, None);
+        // Rendering off, the byte is there whatever the dot.
+        run_to(&mut ppu, &mut cart, 10, 30);
+        assert_eq!(ppu.cpu_read(0x2004, None), Some(0x42));
+
+        ppu.cpu_write(0x2001, 0x18, Some(&mut cart));
+        run_to(&mut ppu, &mut cart, 20, 1);
+        assert_eq!(ppu.cpu_read(0x2004, None), Some(0xFF));
+        run_to(&mut ppu, &mut cart, 20, 64);
+        assert_eq!(ppu.cpu_read(0x2004, None), Some(0xFF));
+        run_to(&mut ppu, &mut cart, 20, 65);
+        assert_eq!(ppu.peek(0x2004), Some(0x42));
+        // Nor during vblank.
+        run_to(&mut ppu, &mut cart, 250, 30);
+        assert_eq!(ppu.cpu_read(0x2004, None), Some(0x42));
+    }
+
+    #[test]
+    fn nametables_map_to_vram_by_mirroring() {
+        // The physical table each of the four logical ones lands in.
+        let tables = |mirroring| {
+            [0x2000, 0x2400, 0x2800, 0x2C00]
+                .map(|address| Ppu::nametable_index(address + 0x123, mirroring) / 0x0400)
+        };
+        assert_eq!(tables(Mirroring::Horizontal), [0, 0, 1, 1]);
+        assert_eq!(tables(Mirroring::Vertical), [0, 1, 0, 1]);
+        assert_eq!(tables(Mirroring::SingleA), [0, 0, 0, 0]);
+        assert_eq!(tables(Mirroring::SingleB), [1, 1, 1, 1]);
+        assert_eq!(tables(Mirroring::FourScreen), [0, 1, 2, 3]);
+        // The offset is kept, and 0x3000-0x3EFF mirrors 0x2000-0x2EFF.
+        assert_eq!(Ppu::nametable_index(0x2C05, Mirroring::FourScreen), 0x0C05);
+        assert_eq!(Ppu::nametable_index(0x3405, Mirroring::SingleB), 0x0405);
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn screenshot_decodes_to_the_frame() {
+        let mut ppu = Ppu::new();
+        ppu.frame[0] = 0x30;
+        ppu.frame[Ppu::WIDTH + 1] = 0x16;
+        ppu.frame[Ppu::WIDTH * Ppu::HEIGHT - 1] = 0x2A;
+        let path = std::env::temp_dir().join(format!("nes-emu-shot-{}.png", std::process::id()));
+        ppu.save_screenshot(&path).expect("screenshot written");
+        let decoder = png::Decoder::new(File::open(&path).expect("screenshot exists"));
+        let mut reader = decoder.read_info().expect("valid PNG");
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).expect("one frame");
+        std::fs::remove_file(&path).expect("screenshot removed");
+        assert_eq!((info.width, info.height), (256, 240));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        let pixel = |x: usize, y: usize| {
+            let start = (y * Ppu::WIDTH + x) * 3;
+            [pixels[start], pixels[start + 1], pixels[start + 2]]
+        };
+        let rgb = |index| palette_to_rgb(index, PpuMaskFlags::empty());
+        assert_eq!(pixel(0, 0), rgb(0x30));
+        assert_eq!(pixel(1, 1), rgb(0x16));
+        assert_eq!(pixel(255, 239), rgb(0x2A));
+        assert_eq!(pixel(1, 0), rgb(0x00));
+        assert_eq!(&pixels[..info.buffer_size()], ppu.frame_rgb());
+    }
+
+    /// Lines 8-59 of a frame drawn with PPUMASK set to `mask`, over a background of
+    /// tile 1 in color 0x16 with an 8x8 sprite of color 0x2A at (96, 50). The first lines
+    /// are left out, as a fresh PPU starts drawing without the tiles that the pre-render
+    /// line fetches.
+    fn frame_with_mask(mask: u8) -> Vec<u8> {
+        let mut cart = solid_tile_cart(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        write_vram(&mut ppu, &mut cart, 0x2000, &[0x01; 0x3C0]);
+        write_vram(&mut ppu, &mut cart, 0x3F00, &[0x0F, 0x16]);
+        write_vram(&mut ppu, &mut cart, 0x3F11, &[0x2A]);
+        ppu.cpu_write(0x2003, 0x00, None);
+        for byte in [49, 0x01, 0x00, 96] {
+            ppu.cpu_write(0x2004, byte, None);
+        }
+        ppu.cpu_write(0x2001, mask, Some(&mut cart));
+        run_to(&mut ppu, &mut cart, 60, 0);
+        ppu.frame()[8 * Ppu::WIDTH..60 * Ppu::WIDTH].to_vec()
+    }
+
+    #[test]
+    fn disabled_layers_are_blank() {
+        let pixel = |frame: &[u8], x: usize, y: usize| frame[(y - 8) * Ppu::WIDTH + x];
+        let both = frame_with_mask(0x1E);
+        assert_eq!(pixel(&both, 100, 52), 0x2A);
+        assert_eq!(pixel(&both, 10, 52), 0x16);
+        assert_eq!(pixel(&both, 3, 52), 0x16);
+
+        let background = frame_with_mask(0x0A);
+        assert_eq!(pixel(&background, 100, 52), 0x16);
+        assert!(background.iter().all(|&color| color == 0x16));
+
+        let sprites = frame_with_mask(0x14);
+        assert_eq!(pixel(&sprites, 100, 52), 0x2A);
+        assert_eq!(pixel(&sprites, 10, 52), 0x0F);
+        for (index, &color) in sprites.iter().enumerate() {
+            let (x, y) = (index % Ppu::WIDTH, index / Ppu::WIDTH + 8);
+            let in_sprite = (96..104).contains(&x) && (50..58).contains(&y);
+            assert_eq!(color, if in_sprite { 0x2A } else { 0x0F }, "({x}, {y})");
+        }
+
+        let neither = frame_with_mask(0x00);
+        assert!(neither.iter().all(|&color| color == 0x0F));
+
+        // Without bits 1 and 2 the leftmost 8 pixels show the backdrop.
+        let clipped = frame_with_mask(0x18);
+        assert_eq!(pixel(&clipped, 3, 52), 0x0F);
+        assert_eq!(pixel(&clipped, 8, 52), 0x16);
+    }
+
+    #[test]
+    fn each_emphasis_bit_keeps_its_channel_and_darkens_the_others() {
+        let white = palette_to_rgb(0x30, PpuMaskFlags::empty());
+        assert_eq!(white, [0xFF, 0xFF, 0xFF]);
+        // 13/16 of 0xFF.
+        let dark = 0xCF;
+        let cases = [
+            (PpuMaskFlags::EMPHASIZE_RED, [0xFF, dark, dark]),
+            (PpuMaskFlags::EMPHASIZE_GREEN, [dark, 0xFF, dark]),
+            (PpuMaskFlags::EMPHASIZE_BLUE, [dark, dark, 0xFF]),
+            (PpuMaskFlags::EMPHASIS, [0xFF, 0xFF, 0xFF]),
+        ];
+        for (mask, rgb) in cases {
+            assert_eq!(palette_to_rgb(0x30, mask), rgb, "{mask:?}");
+        }
+        // The other PPUMASK bits have no effect on colours.
+        assert_eq!(
+            palette_to_rgb(
+                0x16,
+                PpuMaskFlags::SHOW_BACKGROUND | PpuMaskFlags::SHOW_SPRITES
+            ),
+            palette_to_rgb(0x16, PpuMaskFlags::empty())
+        );
+    }
+
+    #[test]
+    fn emphasis_applies_from_the_pixel_it_is_set_on() {
+        let mut cart = solid_tile_cart(Mirroring::Horizontal);
+        let mut ppu = Ppu::new();
+        ppu.skip_warm_up();
+        write_vram(&mut ppu, &mut cart, 0x2000, &[0x01; 0x3C0]);
+        write_vram(&mut ppu, &mut cart, 0x3F00, &[0x0F, 0x30]);
+        ppu.cpu_write(0x2001, 0x0A, Some(&mut cart));
+        run_to(&mut ppu, &mut cart, 100, 0);
+        ppu.cpu_write(0x2001, 0x2A, Some(&mut cart));
+        run_to(&mut ppu, &mut cart, 200, 0);
+        ppu.cpu_write(0x2001, 0xCA, Some(&mut cart));
+        run_to(&mut ppu, &mut cart, 240, 0);
+        // Back to no emphasis, which must not recolour what is already drawn.
+        ppu.cpu_write(0x2001, 0x0A, Some(&mut cart));
+
+        let rgb = ppu.frame_rgb();
+        let pixel = |x: usize, y: usize| {
+            let start = (y * Ppu::WIDTH + x) * 3;
+            [rgb[start], rgb[start + 1], rgb[start + 2]]
+        };
+        assert_eq!(pixel(50, 50), palette_to_rgb(0x30, PpuMaskFlags::empty()));
+        assert_eq!(
+            pixel(50, 150),
+            palette_to_rgb(0x30, PpuMaskFlags::EMPHASIZE_RED)
+        );
+        assert_eq!(
+            pixel(50, 220),
+            palette_to_rgb(
+                0x30,
+                PpuMaskFlags::EMPHASIZE_GREEN | PpuMaskFlags::EMPHASIZE_BLUE
+            )
+        );
+    }
+}
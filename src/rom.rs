@@ -0,0 +1,435 @@
+use std::fmt;
+
+use crate::{Mirroring, Region};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomLoadError {
+    InvalidMagic,
+    UnsupportedMapper(u8),
+    /// The file is shorter than its header says.
+    UnexpectedEnd,
+}
+
+impl fmt::Display for RomLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidMagic => f.write_str("File is not a iNES ROM"),
+            Self::UnsupportedMapper(number) => {
+                write!(f, "Mapper number {number} is not yet supported")
+            }
+            Self::UnexpectedEnd => f.write_str("ROM file is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for RomLoadError {}
+
+/// The timing a ROM was made for, from byte 12 of a NES 2.0 header or the PAL bit of
+/// byte 9 of a plain iNES one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TvSystem {
+    Ntsc,
+    Pal,
+    /// Runs on both, so NTSC is picked.
+    Multi,
+    /// The Dendy famiclone, which is run as a PAL console.
+    Dendy,
+}
+
+impl TvSystem {
+    /// The region to emulate for a ROM made for this TV system.
+    #[must_use]
+    pub const fn region(self) -> Region {
+        match self {
+            Self::Ntsc | Self::Multi => Region::Ntsc,
+            Self::Pal | Self::Dendy => Region::Pal,
+        }
+    }
+}
+
+/// The machine a ROM was made for, from the low bits of byte 7. Only the plain NES is
+/// emulated, the others are parsed so that the header can be reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    /// Another console, given in byte 13 of a NES 2.0 header.
+    Extended,
+}
+
+/// The 16-byte header at the start of an iNES file.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Clone, Copy)]
+pub struct RomHeader {
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mirroring: Mirroring,
+    pub has_persistent_memory: bool,
+    pub has_trainer: bool,
+    pub provides_four_screen_vram: bool,
+    pub mapper_number: u8,
+    pub console_type: ConsoleType,
+    pub tv_system: TvSystem,
+    /// The region to emulate, picked from the TV system.
+    pub region: Region,
+    /// Whether this is a NES 2.0 header, which spells out the RAM sizes below. For plain
+    /// iNES they are assumed: 8KB of PRG-RAM, battery-backed if there is a battery, and
+    /// 8KB of CHR-RAM when there is no CHR ROM.
+    pub is_nes2: bool,
+    pub prg_ram_size: usize,
+    /// Battery-backed part of the PRG-RAM.
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    /// Battery-backed part of the CHR-RAM.
+    pub chr_nvram_size: usize,
+}
+
+impl fmt::Display for RomHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Mapper: {}", self.mapper_number)?;
+        writeln!(f, "PRG ROM: {} KB", self.prg_rom_size / 1024)?;
+        writeln!(f, "CHR ROM: {} KB", self.chr_rom_size / 1024)?;
+        writeln!(f, "Mirroring: {:?}", self.mirroring)?;
+        writeln!(f, "Four-screen VRAM: {}", self.provides_four_screen_vram)?;
+        writeln!(f, "Battery: {}", self.has_persistent_memory)?;
+        writeln!(f, "Trainer: {}", self.has_trainer)?;
+        writeln!(
+            f,
+            "PRG RAM: {} KB ({} KB battery-backed)",
+            (self.prg_ram_size + self.prg_nvram_size) / 1024,
+            self.prg_nvram_size / 1024
+        )?;
+        writeln!(
+            f,
+            "CHR RAM: {} KB ({} KB battery-backed)",
+            (self.chr_ram_size + self.chr_nvram_size) / 1024,
+            self.chr_nvram_size / 1024
+        )?;
+        writeln!(f, "Console: {:?}", self.console_type)?;
+        writeln!(f, "TV system: {:?}", self.tv_system)?;
+        write!(f, "Region: {:?}", self.region)
+    }
+}
+
+/// What `--info` prints about a whole iNES file: every field of its header, one per
+/// line.
+///
+/// # Errors
+///
+/// Fails if the file is shorter than a header or does not start with the iNES magic
+/// number.
+pub fn header_report(data: &[u8]) -> Result<String, RomLoadError> {
+    let header_bytes = data
+        .first_chunk::<{ RomHeader::SIZE }>()
+        .ok_or(RomLoadError::UnexpectedEnd)?;
+    Ok(RomHeader::parse(header_bytes)?.to_string())
+}
+
+impl RomHeader {
+    pub const SIZE: usize = 16;
+    pub const TRAINER_SIZE: usize = 512;
+
+    /// # Errors
+    ///
+    /// Fails if the bytes don't start with the iNES magic number.
+    pub const fn parse(header_bytes: &[u8; Self::SIZE]) -> Result<Self, RomLoadError> {
+        if !(header_bytes[0] == b'N'
+            && header_bytes[1] == b'E'
+            && header_bytes[2] == b'S'
+            && header_bytes[3] == 0x1A)
+        {
+            return Err(RomLoadError::InvalidMagic);
+        }
+        let has_persistent_memory = header_bytes[6] & 0b0000_0010 != 0;
+        let is_nes2 = header_bytes[7] & 0b0000_1100 == 0b0000_1000;
+        let (prg_ram_size, prg_nvram_size, chr_ram_size, chr_nvram_size) = if is_nes2 {
+            (
+                Self::ram_size(header_bytes[10] & 0x0F),
+                Self::ram_size(header_bytes[10] >> 4),
+                Self::ram_size(header_bytes[11] & 0x0F),
+                Self::ram_size(header_bytes[11] >> 4),
+            )
+        } else {
+            let chr_ram = if header_bytes[5] == 0 { 8 * 1024 } else { 0 };
+            if has_persistent_memory {
+                (0, 8 * 1024, chr_ram, 0)
+            } else {
+                (8 * 1024, 0, chr_ram, 0)
+            }
+        };
+        // Plain iNES has the Vs. System and PlayChoice-10 as two flags.
+        let console_type = match header_bytes[7] & 0b0000_0011 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ if is_nes2 => ConsoleType::Extended,
+            _ => ConsoleType::VsSystem,
+        };
+        let tv_system = if is_nes2 {
+            match header_bytes[12] & 0b0000_0011 {
+                0 => TvSystem::Ntsc,
+                1 => TvSystem::Pal,
+                2 => TvSystem::Multi,
+                _ => TvSystem::Dendy,
+            }
+        } else if header_bytes[9] & 0b0000_0001 != 0 {
+            TvSystem::Pal
+        } else {
+            TvSystem::Ntsc
+        };
+        Ok(Self {
+            prg_rom_size: header_bytes[4] as usize * (16 * 1024),
+            chr_rom_size: header_bytes[5] as usize * (8 * 1024),
+            mirroring: if header_bytes[6] & 0b0000_1000 != 0 {
+                Mirroring::FourScreen
+            } else if header_bytes[6] & 0b0000_0001 != 0 {
+                Mirroring::Vertical
+            } else {
+                Mirroring::Horizontal
+            },
+            has_persistent_memory,
+            has_trainer: header_bytes[6] & 0b0000_0100 != 0,
+            provides_four_screen_vram: header_bytes[6] & 0b0000_1000 != 0,
+            mapper_number: (header_bytes[6] >> 4) | (header_bytes[7] & 0xf0),
+            console_type,
+            tv_system,
+            region: tv_system.region(),
+            is_nes2,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+        })
+    }
+
+    /// Decode a NES 2.0 RAM size nibble: 64 bytes shifted left by its value, 0 meaning
+    /// no RAM at all.
+    const fn ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+
+    /// Size of the trainer that sits between the header and the PRG data.
+    #[must_use]
+    pub const fn trainer_size(&self) -> usize {
+        if self.has_trainer {
+            Self::TRAINER_SIZE
+        } else {
+            0
+        }
+    }
+}
+
+/// Corrections for a ROM whose header is known to be wrong. Fields left at `None` keep
+/// the header's value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomOverride {
+    pub mapper_number: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub region: Option<Region>,
+}
+
+impl RomOverride {
+    /// Replace the fields of `header` this override corrects.
+    pub fn apply(&self, header: &mut RomHeader) {
+        if let Some(mapper_number) = self.mapper_number {
+            header.mapper_number = mapper_number;
+        }
+        if let Some(mirroring) = self.mirroring {
+            header.mirroring = mirroring;
+            header.provides_four_screen_vram = mirroring == Mirroring::FourScreen;
+        }
+        if let Some(region) = self.region {
+            header.region = region;
+        }
+    }
+}
+
+/// Known bad headers, keyed by the CRC32 of the PRG ROM followed by the CHR ROM (the
+/// file without its header and trainer), kept sorted by CRC. Entries go in as bad dumps
+/// turn up.
+const ROM_DATABASE: &[(u32, RomOverride)] = &[
+    // Super Mario Bros.: NROM, vertical mirroring for its horizontal scrolling.
+    (
+        0x3337_EC46,
+        RomOverride {
+            mapper_number: Some(0),
+            mirroring: Some(Mirroring::Vertical),
+            region: None,
+        },
+    ),
+];
+
+/// Look a ROM up in the database of known bad headers.
+#[must_use]
+pub fn lookup_rom(prg: &[u8], chr: &[u8]) -> Option<RomOverride> {
+    lookup_crc(crc32(&[prg, chr]))
+}
+
+fn lookup_crc(crc: u32) -> Option<RomOverride> {
+    ROM_DATABASE
+        .binary_search_by_key(&crc, |&(crc, _)| crc)
+        .ok()
+        .map(|index| ROM_DATABASE[index].1)
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i: u32 = 0;
+    while i < 256 {
+        let mut crc = i;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i as usize] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC32 (as in zip and PNG) of the concatenation of `parts`.
+#[must_use]
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+    !parts
+        .iter()
+        .flat_map(|part| part.iter())
+        .fold(!0, |crc, &byte| {
+            CRC_TABLE[usize::from(crc.to_le_bytes()[0] ^ byte)] ^ crc >> 8
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_is_sorted() {
+        assert!(ROM_DATABASE.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn known_crc_overrides_mirroring() {
+        let mut header =
+            RomHeader::parse(b"NES\x1A\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00")
+                .expect("valid header");
+        assert_eq!(header.mirroring, Mirroring::Horizontal);
+        lookup_crc(0x3337_EC46)
+            .expect("Super Mario Bros. is in the database")
+            .apply(&mut header);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert_eq!(header.mapper_number, 0);
+    }
+
+    #[test]
+    fn pal_header_picks_pal_region() {
+        let header = RomHeader::parse(b"NES\x1A\x02\x01\x00\x08\x00\x00\x00\x00\x01\x00\x00\x00")
+            .expect("valid header");
+        assert_eq!(header.tv_system, TvSystem::Pal);
+        assert_eq!(header.region, Region::Pal);
+    }
+
+    #[test]
+    fn unknown_rom_has_no_override() {
+        assert_eq!(lookup_rom(&[0; 16], &[]), None);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn nes2_ram_sizes_are_shift_counts() {
+        // PRG-RAM 0 and 8KB battery-backed, CHR-RAM 32KB and none battery-backed.
+        let header = RomHeader::parse(b"NES\x1A\x02\x00\x02\x08\x00\x00\x70\x09\x00\x00\x00\x00")
+            .expect("valid header");
+        assert!(header.is_nes2);
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (0, 8 * 1024));
+        assert_eq!((header.chr_ram_size, header.chr_nvram_size), (32 * 1024, 0));
+        let header = RomHeader::parse(b"NES\x1A\x02\x00\x00\x08\x00\x00\x01\x0F\x00\x00\x00\x00")
+            .expect("valid header");
+        assert_eq!(
+            (header.prg_ram_size, header.chr_ram_size),
+            (128, 2 * 1024 * 1024)
+        );
+
+        // Plain iNES assumes 8KB of each when CHR ROM is missing.
+        let header = RomHeader::parse(b"NES\x1A\x02\x00\x02\x00\x00\x00\x70\x09\x00\x00\x00\x00")
+            .expect("valid header");
+        assert!(!header.is_nes2);
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (0, 8 * 1024));
+        assert_eq!((header.chr_ram_size, header.chr_nvram_size), (8 * 1024, 0));
+    }
+
+    /// A 16-byte header with `flags6` and `flags7`.
+    fn header_with_flags(flags6: u8, flags7: u8) -> [u8; RomHeader::SIZE] {
+        let mut header = *b"NES\x1A\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        header[6] = flags6;
+        header[7] = flags7;
+        header
+    }
+
+    #[test]
+    fn mapper_number_takes_a_nibble_from_each_flag_byte() {
+        for mapper in [0_u8, 1, 2, 4, 66, 0xA7] {
+            let header = RomHeader::parse(&header_with_flags(mapper << 4 | 0x01, mapper & 0xF0))
+                .expect("valid header");
+            assert_eq!(header.mapper_number, mapper);
+            assert_eq!(header.mirroring, Mirroring::Vertical);
+        }
+    }
+
+    #[test]
+    fn malformed_headers_are_errors() {
+        let mut header = header_with_flags(0, 0);
+        header[3] = 0x00;
+        assert_eq!(
+            RomHeader::parse(&header).err(),
+            Some(RomLoadError::InvalidMagic)
+        );
+        let header = RomHeader::parse(&header_with_flags(0x20, 0x00)).expect("valid header");
+        assert!(matches!(
+            crate::Cart::from_header_and_data(&header, &[], &[]),
+            Err(RomLoadError::UnsupportedMapper(2))
+        ));
+    }
+
+    #[test]
+    fn header_report_lists_every_field() {
+        let mut rom = header_with_flags(0x13, 0x10).to_vec();
+        rom[8] = 0x01;
+        rom.resize(RomHeader::SIZE + 0x8000, 0);
+        assert_eq!(
+            header_report(&rom).expect("valid header"),
+            "Mapper: 17\n\
+             PRG ROM: 32 KB\n\
+             CHR ROM: 8 KB\n\
+             Mirroring: Vertical\n\
+             Four-screen VRAM: false\n\
+             Battery: true\n\
+             Trainer: false\n\
+             PRG RAM: 8 KB (8 KB battery-backed)\n\
+             CHR RAM: 0 KB (0 KB battery-backed)\n\
+             Console: Nes\n\
+             TV system: Ntsc\n\
+             Region: Ntsc"
+        );
+        // Only the header is needed.
+        assert!(header_report(&rom[..RomHeader::SIZE]).is_ok());
+        assert_eq!(
+            header_report(&rom[..RomHeader::SIZE - 1]),
+            Err(RomLoadError::UnexpectedEnd)
+        );
+        assert_eq!(header_report(&[0; 16]), Err(RomLoadError::InvalidMagic));
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+/// First bytes of every blob made by `Nes::save_state`.
+pub const MAGIC: &[u8; 4] = b"NESS";
+/// Bumped whenever the layout of the blob changes.
+pub const VERSION: u16 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    /// The data does not start like a save state.
+    NotASaveState,
+    UnsupportedVersion(u16),
+    /// The data ends early, or goes on after the state.
+    WrongLength,
+    /// The state was saved with another cartridge or set of devices.
+    Mismatch,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotASaveState => write!(f, "Not a save state"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Save state version {version} is not supported")
+            }
+            Self::WrongLength => write!(f, "Save state has the wrong length"),
+            Self::Mismatch => write!(f, "Save state is for another cartridge or machine"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Something that can be written to a save state and read back in place. Only what
+/// changes while running is saved: ROM contents and settings come from the machine the
+/// state is loaded into.
+pub trait Persist {
+    fn write_state(&self, w: &mut StateWriter);
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError>;
+}
+
+/// Absent devices are saved as a 0 byte, and must be absent when loading too.
+impl<T: Persist> Persist for Option<T> {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.is_some());
+        if let Some(inner) = self {
+            inner.write_state(w);
+        }
+    }
+
+    fn read_state(&mut self, r: &mut StateReader<'_>) -> Result<(), StateError> {
+        if r.bool()? != self.is_some() {
+            return Err(StateError::Mismatch);
+        }
+        self.as_mut().map_or(Ok(()), |inner| inner.read_state(r))
+    }
+}
+
+/// Little endian fields, one after the other.
+#[derive(Default)]
+pub struct StateWriter(Vec<u8>);
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.0.push(u8::from(value));
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A length-prefixed byte array, which must have the same length when loaded.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(u32::try_from(bytes.len()).unwrap_or(u32::MAX));
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self(data)
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let (bytes, rest) = self.0.split_first_chunk().ok_or(StateError::WrongLength)?;
+        self.0 = rest;
+        Ok(*bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    /// Fill `bytes` from an array written by `StateWriter::bytes`.
+    pub fn bytes_into(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        if usize::try_from(self.u32()?).ok() != Some(bytes.len()) {
+            return Err(StateError::Mismatch);
+        }
+        if self.0.len() < bytes.len() {
+            return Err(StateError::WrongLength);
+        }
+        let (data, rest) = self.0.split_at(bytes.len());
+        bytes.copy_from_slice(data);
+        self.0 = rest;
+        Ok(())
+    }
+}
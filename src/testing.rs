@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::opcode::{AddressingMode, OPCODES};
+use crate::{Bus, Cpu, CpuError, StepResult};
+
+/// Write `bytes` at the current PC and run exactly one instruction.
+///
+/// PC must point to writable memory (RAM on a `CpuMemoryBus`), as writes to ROM are ignored.
+///
+/// # Errors
+///
+/// See `Cpu::run_instr`.
+pub fn exec_bytes<B: Bus>(
+    cpu: &mut Cpu,
+    bus: &mut B,
+    bytes: &[u8],
+) -> Result<StepResult, CpuError> {
+    let mut address = cpu.prog_counter;
+    for &byte in bytes {
+        bus.write(address, byte);
+        address = address.wrapping_add(1);
+    }
+    cpu.run_instr(bus)
+}
+
+/// Run until an instruction leaves PC where it was, returning that address.
+///
+/// A `JMP *` or a branch to itself is how 6502 test programs report their result. Gives
+/// up and returns `None` once `max_cycles` have elapsed, or when the CPU cannot run an
+/// instruction.
+pub fn run_until_trap<B: Bus>(cpu: &mut Cpu, bus: &mut B, max_cycles: u64) -> Option<u16> {
+    while bus.cycles() < max_cycles {
+        let address = cpu.prog_counter;
+        cpu.run_instr(bus).ok()?;
+        if cpu.prog_counter == address {
+            return Some(address);
+        }
+    }
+    None
+}
+
+/// Copy a raw program image (not an iNES file) to `load_addr` and point the reset vector
+/// at `reset_vector`, so that `Cpu::reset` starts it.
+pub fn load_flat(bus: &mut FlatMemoryBus, load_addr: u16, bytes: &[u8], reset_vector: u16) {
+    bus.load(load_addr, bytes);
+    bus.load(0xFFFC, &reset_vector.to_le_bytes());
+}
+
+/// 64KB of plain RAM with no mapped devices, for running the CPU in isolation.
+pub struct FlatMemoryBus {
+    memory: Vec<u8>,
+    cycles: u64,
+}
+
+impl FlatMemoryBus {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+        }
+    }
+
+    /// Copy `data` into memory at `address` without spending cycles.
+    pub fn load(&mut self, address: u16, data: &[u8]) {
+        let start = usize::from(address);
+        self.memory[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Default for FlatMemoryBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatMemoryBus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.cycles += 1;
+        self.memory[usize::from(address)]
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.cycles += 1;
+        self.memory[usize::from(address)] = data;
+    }
+
+    fn peek(&mut self, address: u16) -> u8 {
+        self.memory[usize::from(address)]
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+}
+
+/// A writer that can be handed to [`Cpu::with_trace_writer`] and read back afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A panic while writing leaves nothing half done, so a poisoned lock is still fine
+    /// to use.
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Everything written so far, lossily decoded as UTF-8.
+    #[must_use]
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.lock()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Why [`assemble`] rejected its source, with `line` counted from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// An operand value, resolved once every label is known.
+enum Value {
+    Number(u16),
+    Label(String),
+}
+
+/// Assemble a small program to be loaded at `origin`, so that tests need not compute
+/// branch offsets by hand.
+///
+/// Each line holds an optional `label:`, then an instruction with its operand in the
+/// usual syntax (`#$01`, `$10,X`, `($20),Y`, `loop`, ...), and `;` starts a comment.
+/// Numbers are `$` hexadecimal or decimal, and use zero page addressing when they fit
+/// in a byte and the instruction allows it. Official opcodes are preferred over the
+/// unofficial ones sharing a mnemonic.
+///
+/// # Errors
+///
+/// Fails on an unknown mnemonic or addressing mode, an undefined or duplicate label, or
+/// a branch too far from its target.
+pub fn assemble(origin: u16, source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut instructions = Vec::new();
+    let mut address = origin;
+    for (line, text) in (1..).zip(source.lines()) {
+        let error = |message: String| AsmError { line, message };
+        let mut text = text.split(';').next().unwrap_or_default().trim();
+        if let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if labels.insert(label, address).is_some() {
+                return Err(error(format!("label {label} is defined twice")));
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operand) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        let operand: String = operand.split_whitespace().collect();
+        let (opcode, mode, value) = parse_instruction(&mnemonic, &operand)
+            .ok_or_else(|| error(format!("cannot assemble {text}")))?;
+        instructions.push((line, address, opcode, mode, value));
+        address = address.wrapping_add(u16::from(1 + mode.operand_len()));
+    }
+
+    let mut bytes = Vec::new();
+    for (line, address, opcode, mode, value) in instructions {
+        let error = |message: String| AsmError { line, message };
+        bytes.push(opcode);
+        let value = match value {
+            None => continue,
+            Some(Value::Number(value)) => value,
+            Some(Value::Label(label)) => *labels
+                .get(label.as_str())
+                .ok_or_else(|| error(format!("label {label} is not defined")))?,
+        };
+        if mode == AddressingMode::Relative {
+            let offset = i16::try_from(i32::from(value) - i32::from(address) - 2)
+                .ok()
+                .and_then(|offset| i8::try_from(offset).ok())
+                .ok_or_else(|| error(format!("branch target ${value:04X} is out of range")))?;
+            bytes.push(offset.cast_unsigned());
+        } else if mode.operand_len() == 1 {
+            let byte = u8::try_from(value)
+                .map_err(|_| error(format!("operand ${value:04X} does not fit in a byte")))?;
+            bytes.push(byte);
+        } else {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    Ok(bytes)
+}
+
+/// The opcode, addressing mode and operand of one instruction, with `operand` stripped
+/// of whitespace.
+fn parse_instruction(mnemonic: &str, operand: &str) -> Option<(u8, AddressingMode, Option<Value>)> {
+    use AddressingMode as M;
+
+    let find = |mode| {
+        let mut matching = (0..=u8::MAX)
+            .filter(|&opcode| {
+                let op = OPCODES[usize::from(opcode)];
+                op.mnemonic == mnemonic && op.mode == mode
+            })
+            .peekable();
+        let first = *matching.peek()?;
+        Some(
+            matching
+                .find(|&opcode| OPCODES[usize::from(opcode)].official)
+                .unwrap_or(first),
+        )
+    };
+    let with = |mode, value| find(mode).map(|opcode| (opcode, mode, value));
+
+    if operand.is_empty() {
+        return with(M::Implied, None).or_else(|| with(M::Accumulator, None));
+    }
+    if operand.eq_ignore_ascii_case("A") {
+        return with(M::Accumulator, None);
+    }
+    if let Some(value) = operand.strip_prefix('#') {
+        return with(M::Immediate, Some(parse_value(value)?));
+    }
+    if let Some(inner) = operand.strip_prefix('(') {
+        let upper = inner.to_ascii_uppercase();
+        return if upper.ends_with(",X)") {
+            with(M::IndirectX, Some(parse_value(&inner[..inner.len() - 3])?))
+        } else if upper.ends_with("),Y") {
+            with(M::IndirectY, Some(parse_value(&inner[..inner.len() - 3])?))
+        } else {
+            with(M::Indirect, Some(parse_value(inner.strip_suffix(')')?)?))
+        };
+    }
+
+    let upper = operand.to_ascii_uppercase();
+    let (value, zero_page, absolute) = if upper.ends_with(",X") {
+        (&operand[..operand.len() - 2], M::ZeroPageX, M::AbsoluteX)
+    } else if upper.ends_with(",Y") {
+        (&operand[..operand.len() - 2], M::ZeroPageY, M::AbsoluteY)
+    } else {
+        (operand, M::ZeroPage, M::Absolute)
+    };
+    let value = parse_value(value)?;
+    if absolute == M::Absolute {
+        if let Some(branch) = with(M::Relative, None) {
+            return Some((branch.0, M::Relative, Some(value)));
+        }
+    }
+    match value {
+        Value::Number(number) if number <= 0xFF => {
+            with(zero_page, Some(Value::Number(number))).or_else(|| with(absolute, Some(value)))
+        }
+        _ => with(absolute, Some(value)),
+    }
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+    match text.strip_prefix('$') {
+        Some(hex) => u16::from_str_radix(hex, 16).ok().map(Value::Number),
+        None if text.starts_with(|c: char| c.is_ascii_digit()) => {
+            text.parse().ok().map(Value::Number)
+        }
+        None if text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => {
+            Some(Value::Label(text.to_owned()))
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quiet_cpu() -> Cpu {
+        Cpu::new().with_trace_writer(io::sink())
+    }
+
+    #[test]
+    fn exec_bytes_writes_at_pc_and_runs_one_instruction() {
+        let mut bus = FlatMemoryBus::new();
+        let mut cpu = quiet_cpu();
+        cpu.prog_counter = 0x0300;
+        // LDA #$42
+        let result = exec_bytes(&mut cpu, &mut bus, &[0xA9, 0x42]).expect("LDA runs");
+        assert_eq!(result.opcode, 0xA9);
+        assert_eq!(result.cycles, 2);
+        assert_eq!(cpu.state().a_reg, 0x42);
+        assert_eq!(cpu.prog_counter, 0x0302);
+        assert_eq!(bus.peek(0x0300), 0xA9);
+        assert_eq!(bus.peek(0x0301), 0x42);
+        // The next call carries on from there: STA $10.
+        exec_bytes(&mut cpu, &mut bus, &[0x85, 0x10]).expect("STA runs");
+        assert_eq!(bus.peek(0x0010), 0x42);
+        assert_eq!(cpu.prog_counter, 0x0304);
+    }
+
+    #[test]
+    fn load_flat_copies_the_image_and_sets_the_reset_vector() {
+        let mut bus = FlatMemoryBus::new();
+        // A zero byte, then the entry point: LDX #$07; JMP *
+        load_flat(
+            &mut bus,
+            0x05FF,
+            &[0x00, 0xA2, 0x07, 0x4C, 0x02, 0x06],
+            0x0600,
+        );
+        assert_eq!(bus.peek(0x0600), 0xA2);
+        assert_eq!(bus.peek(0x0604), 0x06);
+        assert_eq!(bus.cycles(), 0);
+        assert_eq!(bus.read_u16(0xFFFC), 0x0600);
+        let mut cpu = quiet_cpu();
+        cpu.reset(&mut bus);
+        assert_eq!(cpu.prog_counter, 0x0600);
+        assert_eq!(run_until_trap(&mut cpu, &mut bus, 100), Some(0x0602));
+        assert_eq!(cpu.state().x_reg, 0x07);
+    }
+
+    #[test]
+    fn assemble_resolves_labels_and_branch_offsets() {
+        let bytes = assemble(
+            0x8000,
+            "
+            start:
+                LDX #$02      ; 0x8000
+            back:
+                DEX           ; 0x8002
+                BNE back      ; 0x8003, back 3 from 0x8005
+                BEQ ahead     ; 0x8005, forward 3 from 0x8007
+                JMP start     ; 0x8007
+            ahead:
+                STA $10,X     ; 0x800A
+                JMP ahead
+            ",
+        )
+        .expect("assembles");
+        assert_eq!(
+            bytes,
+            [
+                0xA2, 0x02, 0xCA, 0xD0, 0xFD, 0xF0, 0x03, 0x4C, 0x00, 0x80, 0x95, 0x10, 0x4C, 0x0A,
+                0x80,
+            ]
+        );
+    }
+
+    #[test]
+    fn assemble_reports_the_failing_line() {
+        let error = |source| assemble(0x8000, source).expect_err("does not assemble");
+        let undefined = error("NOP\nJMP nowhere");
+        assert_eq!(undefined.line, 2);
+        assert_eq!(undefined.message, "label nowhere is not defined");
+        assert_eq!(error("a: NOP\na: NOP").line, 2);
+        assert_eq!(error("FOO #$01").message, "cannot assemble FOO #$01");
+        let far = format!("BNE far\n{}far: NOP", "NOP\n".repeat(128));
+        assert_eq!(error(&far).message, "branch target $8082 is out of range");
+        // 127 bytes ahead is still in reach.
+        let near = format!("BNE near\n{}near: NOP", "NOP\n".repeat(127));
+        assert_eq!(assemble(0x8000, &near).expect("assembles")[1], 0x7F);
+    }
+
+    #[test]
+    fn trace_goes_to_the_configured_writer() {
+        let buffer = SharedBuffer::new();
+        let mut cpu = Cpu::new().with_trace_writer(buffer.clone());
+        let mut bus = FlatMemoryBus::new();
+        // JMP $1234
+        exec_bytes(&mut cpu, &mut bus, &[0x4C, 0x34, 0x12]).expect("JMP runs");
+        assert_eq!(buffer.contents(), "JMP (Absolute) => 0x1234\n");
+
+        let mut tracer = crate::trace::StderrTracer::with_writer(Vec::new());
+        crate::trace::Tracer::trace(&mut tracer, format_args!("SEI (Implied)"));
+        assert_eq!(tracer.into_inner(), b"SEI (Implied)\n");
+    }
+}
@@ -0,0 +1,373 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::disasm::disassemble_bytes;
+
+/// Receives one line per executed instruction or serviced interrupt.
+pub trait Tracer {
+    fn trace(&mut self, line: fmt::Arguments<'_>);
+
+    /// Called once the opcode at `pc` is fetched, before any of the lines it produces.
+    fn start_instruction(&mut self, _pc: u16, _opcode: u8) {}
+
+    /// Whether `instruction` should be called. Gathering its context takes a few bus
+    /// peeks per instruction, so it is only done for tracers that use it.
+    fn wants_context(&self) -> bool {
+        false
+    }
+
+    /// Called right after `start_instruction` with the state the instruction started
+    /// from, when `wants_context` is true.
+    fn instruction(&mut self, _context: &TraceContext) {}
+}
+
+/// The machine state before an instruction runs, which is what the traces of other
+/// emulators show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub pc: u16,
+    /// The opcode and the two bytes after it, whether or not they are operands.
+    pub bytes: [u8; 3],
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    /// CPU cycles elapsed before the opcode fetch.
+    pub cycles: u64,
+    /// Scanline and dot, when there is a PPU on the bus.
+    pub ppu: Option<(u16, u16)>,
+}
+
+impl TraceContext {
+    /// The instruction as text, and its length in bytes.
+    #[must_use]
+    pub fn disassembly(&self) -> (String, u8) {
+        disassemble_bytes(self.pc, self.bytes)
+    }
+
+    /// The bytes of the instruction in hex, e.g. `4C F5 C5`.
+    #[must_use]
+    pub fn hex_bytes(&self) -> String {
+        let (_, len) = self.disassembly();
+        self.bytes[..usize::from(len)]
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Turns the state before each instruction into a trace line, to diff against the log of
+/// another emulator.
+pub trait TraceFormatter {
+    fn format(&self, context: &TraceContext) -> String;
+}
+
+/// The layout of `nestest.log`:
+/// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`.
+///
+/// The values nestest appends to memory operands (`= 00`) are left out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NestestFormatter;
+
+impl TraceFormatter for NestestFormatter {
+    fn format(&self, c: &TraceContext) -> String {
+        let (text, _) = c.disassembly();
+        let ppu = c
+            .ppu
+            .map(|(scanline, dot)| format!(" PPU:{scanline:>3},{dot:>3}"))
+            .unwrap_or_default();
+        format!(
+            "{:04X}  {:<8}  {text:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}{ppu} CYC:{}",
+            c.pc,
+            c.hex_bytes(),
+            c.a,
+            c.x,
+            c.y,
+            c.p,
+            c.sp,
+            c.cycles
+        )
+    }
+}
+
+/// The layout of Mesen's trace logger, with the flags spelled out as letters, uppercase
+/// when set:
+/// `C000  JMP $C5F5                A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Cycle:7`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MesenFormatter;
+
+impl TraceFormatter for MesenFormatter {
+    fn format(&self, c: &TraceContext) -> String {
+        let (text, _) = c.disassembly();
+        let flags: String = "nvubdizc"
+            .chars()
+            .zip((0..8).rev())
+            .map(|(letter, bit)| {
+                if c.p & 1 << bit == 0 {
+                    letter
+                } else {
+                    letter.to_ascii_uppercase()
+                }
+            })
+            .collect();
+        let ppu = c
+            .ppu
+            .map(|(scanline, dot)| format!(" V:{scanline:<3} H:{dot:<3}"))
+            .unwrap_or_default();
+        format!(
+            "{:04X}  {text:<25}A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{flags}{ppu} Cycle:{}",
+            c.pc, c.a, c.x, c.y, c.sp, c.cycles
+        )
+    }
+}
+
+/// Writes one line per instruction, laid out by a [`TraceFormatter`], instead of the
+/// lines of the default trace.
+pub struct FormattedTracer<F: TraceFormatter, W: Write = io::Stderr> {
+    formatter: F,
+    writer: W,
+}
+
+impl<F: TraceFormatter, W: Write> FormattedTracer<F, W> {
+    pub const fn new(formatter: F, writer: W) -> Self {
+        Self { formatter, writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<F: TraceFormatter, W: Write> Tracer for FormattedTracer<F, W> {
+    fn trace(&mut self, _line: fmt::Arguments<'_>) {}
+
+    fn wants_context(&self) -> bool {
+        true
+    }
+
+    fn instruction(&mut self, context: &TraceContext) {
+        // Tracing must never stop the emulation, so write errors are dropped.
+        let _ = writeln!(self.writer, "{}", self.formatter.format(context));
+    }
+}
+
+impl fmt::Debug for dyn Tracer + Send {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Tracer")
+    }
+}
+
+/// The default tracer: writes each line to stderr, or to any other writer.
+pub struct StderrTracer<W: Write = io::Stderr> {
+    writer: W,
+}
+
+impl StderrTracer {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_writer(io::stderr())
+    }
+}
+
+impl Default for StderrTracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> StderrTracer<W> {
+    pub const fn with_writer(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Tracer for StderrTracer<W> {
+    fn trace(&mut self, line: fmt::Arguments<'_>) {
+        // Tracing must never stop the emulation, so write errors are dropped.
+        let _ = writeln!(self.writer, "{line}");
+    }
+}
+
+/// Passes on only the lines of the instructions that match every condition set, along
+/// with the interrupts serviced right after them.
+pub struct TraceFilter<T: Tracer> {
+    inner: T,
+    pc_range: Option<RangeInclusive<u16>>,
+    opcodes: Option<Vec<u8>>,
+    enabled: bool,
+}
+
+impl<T: Tracer> TraceFilter<T> {
+    /// A filter letting everything through until conditions are added.
+    pub const fn new(inner: T) -> Self {
+        Self {
+            inner,
+            pc_range: None,
+            opcodes: None,
+            enabled: true,
+        }
+    }
+
+    /// Only trace instructions whose address is between `start` and `end` inclusive.
+    #[must_use]
+    pub const fn only_pc_range(mut self, start: u16, end: u16) -> Self {
+        self.pc_range = Some(start..=end);
+        self
+    }
+
+    /// Only trace instructions with one of these opcodes.
+    #[must_use]
+    pub fn only_opcodes(mut self, opcodes: &[u8]) -> Self {
+        self.opcodes = Some(opcodes.to_vec());
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Tracer> Tracer for TraceFilter<T> {
+    fn trace(&mut self, line: fmt::Arguments<'_>) {
+        if self.enabled {
+            self.inner.trace(line);
+        }
+    }
+
+    fn start_instruction(&mut self, pc: u16, opcode: u8) {
+        self.enabled = self
+            .pc_range
+            .as_ref()
+            .is_none_or(|range| range.contains(&pc))
+            && self
+                .opcodes
+                .as_ref()
+                .is_none_or(|opcodes| opcodes.contains(&opcode));
+        if self.enabled {
+            self.inner.start_instruction(pc, opcode);
+        }
+    }
+
+    fn wants_context(&self) -> bool {
+        self.inner.wants_context()
+    }
+
+    fn instruction(&mut self, context: &TraceContext) {
+        if self.enabled {
+            self.inner.instruction(context);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FlatMemoryBus, SharedBuffer};
+    use crate::Cpu;
+
+    type BufferFilter = TraceFilter<StderrTracer<SharedBuffer>>;
+
+    /// The lines `filter` lets through over two rounds of `SEI; CLD; CLI; JMP $0300`.
+    fn filtered(filter: impl FnOnce(BufferFilter) -> BufferFilter) -> String {
+        let buffer = SharedBuffer::new();
+        let tracer = filter(TraceFilter::new(StderrTracer::with_writer(buffer.clone())));
+        let mut cpu = Cpu::new().with_tracer(tracer);
+        let mut bus = FlatMemoryBus::new();
+        bus.load(0x0300, &[0x78, 0xD8, 0x58, 0x4C, 0x00, 0x03]);
+        cpu.set_prog_counter(0x0300);
+        for _ in 0..8 {
+            cpu.run_instr(&mut bus).expect("runs");
+        }
+        buffer.contents()
+    }
+
+    #[test]
+    fn trace_filter_selects_by_opcode_and_pc_range() {
+        let round = "SEI (Implied)\nCLD (Implied)\nCLI (Implied)\nJMP (Absolute) => 0x0300\n";
+        assert_eq!(filtered(|filter| filter), round.repeat(2));
+        assert_eq!(
+            filtered(|filter| filter.only_opcodes(&[0x78, 0x4C])),
+            "SEI (Implied)\nJMP (Absolute) => 0x0300\n".repeat(2)
+        );
+        assert_eq!(
+            filtered(|filter| filter.only_pc_range(0x0301, 0x0302)),
+            "CLD (Implied)\nCLI (Implied)\n".repeat(2)
+        );
+        // Both conditions must hold.
+        assert_eq!(
+            filtered(|filter| filter
+                .only_pc_range(0x0301, 0x0303)
+                .only_opcodes(&[0x78, 0x58])),
+            "CLI (Implied)\n".repeat(2)
+        );
+    }
+
+    /// The first line of `nestest.log`.
+    const NESTEST_START: TraceContext = TraceContext {
+        pc: 0xC000,
+        bytes: [0x4C, 0xF5, 0xC5],
+        a: 0x00,
+        x: 0x00,
+        y: 0x00,
+        p: 0x24,
+        sp: 0xFD,
+        cycles: 7,
+        ppu: Some((0, 21)),
+    };
+
+    #[test]
+    fn formatters_match_the_logs_of_other_emulators() {
+        assert_eq!(
+            NestestFormatter.format(&NESTEST_START),
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+        assert_eq!(
+            MesenFormatter.format(&NESTEST_START),
+            "C000  JMP $C5F5                A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Cycle:7"
+        );
+        let context = TraceContext {
+            pc: 0x0300,
+            bytes: [0xE8, 0xFF, 0xFF],
+            a: 0x12,
+            x: 0xFF,
+            y: 0x80,
+            p: 0xE7,
+            sp: 0x01,
+            cycles: 123_456,
+            ppu: None,
+        };
+        assert_eq!(
+            NestestFormatter.format(&context),
+            "0300  E8        INX                             A:12 X:FF Y:80 P:E7 SP:01 CYC:123456"
+        );
+        assert_eq!(
+            MesenFormatter.format(&context),
+            "0300  INX                      A:12 X:FF Y:80 S:01 P:NVUbdIZC Cycle:123456"
+        );
+    }
+
+    #[test]
+    fn formatted_tracer_writes_a_line_before_each_instruction() {
+        let buffer = SharedBuffer::new();
+        let mut cpu =
+            Cpu::new().with_tracer(FormattedTracer::new(NestestFormatter, buffer.clone()));
+        let mut bus = FlatMemoryBus::new();
+        // LDX #$05, INX
+        bus.load(0x0300, &[0xA2, 0x05, 0xE8]);
+        cpu.set_prog_counter(0x0300);
+        cpu.run_instr(&mut bus).expect("LDX runs");
+        cpu.run_instr(&mut bus).expect("INX runs");
+        assert_eq!(
+            buffer.contents(),
+            "0300  A2 05     LDX #$05                        A:00 X:00 Y:00 P:34 SP:FF CYC:0\n\
+             0302  E8        INX                             A:00 X:05 Y:00 P:34 SP:FF CYC:2\n"
+        );
+    }
+}
@@ -0,0 +1,42 @@
+//! Klaus Dormann's 6502 functional test, run on a flat 64KB bus.
+//!
+//! The binary is not bundled. Fetch the 64KB image assembled with the default options
+//! (decimal tests enabled, code at 0x0400) from `bin_files/6502_functional_test.bin` in
+//! <https://github.com/Klaus2m5/6502_65C02_functional_tests>, put it in `tests/roms/`
+//! (or point `FUNCTIONAL_TEST_BIN` at it), then run:
+//!
+//! `cargo test --release --features testing --test functional_test -- --ignored`
+use nes_emu::testing::{run_until_trap, FlatMemoryBus};
+use nes_emu::{Bus, Cpu};
+
+const START_ADDRESS: u16 = 0x0400;
+/// Where the default build traps once every test has passed.
+const SUCCESS_ADDRESS: u16 = 0x3469;
+const MAX_CYCLES: u64 = 200_000_000;
+
+#[test]
+#[ignore = "needs tests/roms/6502_functional_test.bin, see the module docs"]
+fn functional_test_reaches_success_trap() {
+    let path = std::env::var("FUNCTIONAL_TEST_BIN").unwrap_or_else(|_| {
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/roms/6502_functional_test.bin"
+        )
+        .to_owned()
+    });
+    let image = std::fs::read(&path).unwrap_or_else(|err| panic!("Unable to read {path}: {err}"));
+    let mut bus = FlatMemoryBus::new();
+    bus.load(0x0000, &image);
+    let mut cpu = Cpu::new().with_trace_writer(std::io::sink());
+    cpu.set_decimal_mode(true);
+    cpu.set_prog_counter(START_ADDRESS);
+
+    let trap = run_until_trap(&mut cpu, &mut bus, MAX_CYCLES);
+    assert_eq!(
+        trap,
+        Some(SUCCESS_ADDRESS),
+        "stuck with PC = 0x{:04x} after {} cycles",
+        cpu.prog_counter(),
+        bus.cycles()
+    );
+}